@@ -7,7 +7,7 @@ of its input arguments from environment variables.
 
 use buildsys::manifest::SupportedArch;
 use buildsys::BuildType;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 use url::Url;
 
@@ -15,9 +15,10 @@ use url::Url;
 /// variable changes. The build type is represented with bit flags so that we can easily list
 /// multiple build types for a single variable. See `[BuildType]` and `[rerun_for_envs]` below to
 /// see how this list is used.
-const REBUILD_VARS: [(&str, u8); 14] = [
+const REBUILD_VARS: [(&str, u8); 15] = [
     ("BUILDSYS_ARCH", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_CACERTS_BUNDLE_OVERRIDE", VARIANT),
+    ("BUILDSYS_DRY_RUN", PACKAGE | KIT | VARIANT | REPACK),
     ("BUILDSYS_KITS_DIR", KIT),
     ("BUILDSYS_EXTERNAL_KITS_DIR", PACKAGE | KIT | VARIANT),
     ("BUILDSYS_NAME", VARIANT),
@@ -45,15 +46,24 @@ pub(crate) enum Command {
     BuildKit(Box<BuildKitArgs>),
     BuildVariant(Box<BuildVariantArgs>),
     RepackVariant(Box<RepackVariantArgs>),
+    PopulateLookasideCache(Box<PopulateLookasideCacheArgs>),
+    UpdateExternalFiles(Box<UpdateExternalFilesArgs>),
+    LintPackage(Box<LintPackageArgs>),
+    DependencyGraph(Box<DependencyGraphArgs>),
 }
 
 impl Command {
-    pub(crate) fn build_type(&self) -> BuildType {
+    /// The type of build, or `None` for commands that Cargo does not run.
+    pub(crate) fn build_type(&self) -> Option<BuildType> {
         match self {
-            Command::BuildPackage(_) => BuildType::Package,
-            Command::BuildKit(_) => BuildType::Kit,
-            Command::BuildVariant(_) => BuildType::Variant,
-            Command::RepackVariant(_) => BuildType::Repack,
+            Command::BuildPackage(_) => Some(BuildType::Package),
+            Command::BuildKit(_) => Some(BuildType::Kit),
+            Command::BuildVariant(_) => Some(BuildType::Variant),
+            Command::RepackVariant(_) => Some(BuildType::Repack),
+            Command::PopulateLookasideCache(_)
+            | Command::UpdateExternalFiles(_)
+            | Command::LintPackage(_)
+            | Command::DependencyGraph(_) => None,
         }
     }
 }
@@ -85,11 +95,23 @@ pub(crate) struct Common {
     #[arg(long, env = "TWOLITER_TOOLS_DIR")]
     pub(crate) tools_dir: PathBuf,
 
+    /// Where to append a JSON line for the start and finish of each build. Nothing is recorded
+    /// when this is unset.
+    #[arg(long, env = "BUILDSYS_EVENTS_PATH")]
+    pub(crate) events_path: Option<PathBuf>,
+
+    /// Print the `docker build` command, its inputs and its outputs instead of running it. No
+    /// files are fetched or written.
+    #[arg(long, env = "BUILDSYS_DRY_RUN")]
+    pub(crate) dry_run: bool,
+
     /// cicd_hack is used to suppress builds from running after all the cargo-related metadata is
     /// emitted. This allows cargo to create a fresh crate, and assumes that the corresponding
     /// build artifacts are already present. It is intended for use in a CI/CD scenario where some
     /// other process populates the build directory from a cache. Other uses may lead to unexpected
     /// build failures that are difficult to troubleshoot.
+    ///
+    /// Deprecated: packages can be restored from `BUILDSYS_REMOTE_CACHE` instead.
     #[arg(long, env = "BUILDSYS_CICD_HACK")]
     pub(crate) cicd_hack: bool,
 }
@@ -115,12 +137,28 @@ pub(crate) struct BuildPackageArgs {
     #[arg(long, env = "BUILDSYS_SOURCES_DIR")]
     pub(crate) sources_dir: PathBuf,
 
-    #[arg(long, env = "BUILDSYS_LOOKASIDE_CACHE")]
-    pub(crate) lookaside_cache: Url,
+    /// The lookaside caches to fetch external files from, tried in order. Mirrors are separated
+    /// by commas, and may be http(s), file or s3 URLs.
+    #[arg(
+        long,
+        env = "BUILDSYS_LOOKASIDE_CACHE",
+        value_delimiter = ',',
+        required = true
+    )]
+    pub(crate) lookaside_cache: Vec<Url>,
 
     #[arg(long, env = "BUILDSYS_UPSTREAM_SOURCE_FALLBACK")]
     pub(crate) upstream_source_fallback: String,
 
+    /// An OCI repository to restore package builds from, and push them to. Builds are tagged with
+    /// a hash of their inputs.
+    #[arg(long, env = "BUILDSYS_REMOTE_CACHE")]
+    pub(crate) remote_cache: Option<String>,
+
+    /// Whether to push package builds to the remote cache.
+    #[arg(long, env = "BUILDSYS_REMOTE_CACHE_PUSH")]
+    pub(crate) remote_cache_push: bool,
+
     #[command(flatten)]
     pub(crate) common: Common,
 }
@@ -193,6 +231,91 @@ pub(crate) struct RepackVariantArgs {
     pub(crate) common: Common,
 }
 
+/// Download the external files of every package from upstream, and add the ones that are missing
+/// to a lookaside cache.
+#[derive(Debug, Parser)]
+pub(crate) struct PopulateLookasideCacheArgs {
+    /// The project directory, whose `packages` directory holds a directory for each package.
+    #[arg(long, env = "BUILDSYS_ROOT_DIR")]
+    pub(crate) root_dir: PathBuf,
+
+    /// The lookaside cache to add files to, as a file or s3 URL.
+    #[arg(long, env = "BUILDSYS_LOOKASIDE_CACHE_TARGET")]
+    pub(crate) lookaside_cache: Url,
+
+    /// Only list the files that are missing from the lookaside cache.
+    #[arg(long, env = "BUILDSYS_LOOKASIDE_CACHE_DRY_RUN")]
+    pub(crate) dry_run: bool,
+}
+
+/// Download the signed external files of a package from upstream, check their signatures, and write
+/// their SHA-512 hashes to the package's manifest.
+#[derive(Debug, Parser)]
+pub(crate) struct UpdateExternalFilesArgs {
+    /// The package directory, which holds its `Cargo.toml`.
+    #[arg(long)]
+    pub(crate) package_dir: PathBuf,
+}
+
+/// Check package specs against the project's conventions, applying their patches in the SDK
+/// container.
+#[derive(Debug, Parser)]
+pub(crate) struct LintPackageArgs {
+    /// The project directory, whose `packages` directory holds a directory for each package.
+    #[arg(long, env = "BUILDSYS_ROOT_DIR")]
+    pub(crate) root_dir: PathBuf,
+
+    /// The package to check. Every package is checked when this is not given.
+    #[arg(long)]
+    pub(crate) package: Option<String>,
+
+    #[arg(long, env = "TLPRIVATE_SDK_IMAGE")]
+    pub(crate) sdk_image: String,
+
+    /// The lookaside caches to fetch external files from, tried in order.
+    #[arg(long, env = "BUILDSYS_LOOKASIDE_CACHE", value_delimiter = ',')]
+    pub(crate) lookaside_cache: Vec<Url>,
+
+    #[arg(
+        long,
+        env = "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
+        default_value = "false"
+    )]
+    pub(crate) upstream_source_fallback: String,
+
+    /// Don't fetch external files or apply patches, which needs Docker.
+    #[arg(long, env = "BUILDSYS_LINT_SKIP_PATCHES")]
+    pub(crate) skip_patches: bool,
+}
+
+/// Print the graph of the project's packages, kits and variants, including the external kits they
+/// are built with.
+#[derive(Debug, Parser)]
+pub(crate) struct DependencyGraphArgs {
+    #[arg(long, env = "BUILDSYS_CARGO_METADATA_PATH")]
+    pub(crate) cargo_metadata_path: PathBuf,
+
+    /// The project directory, which holds the external kit metadata.
+    #[arg(long, env = "BUILDSYS_ROOT_DIR")]
+    pub(crate) root_dir: PathBuf,
+
+    #[arg(
+        long,
+        env = "BUILDSYS_GRAPH_FORMAT",
+        value_enum,
+        default_value_t = GraphFormat::Json
+    )]
+    pub(crate) format: GraphFormat,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum GraphFormat {
+    /// A list of nodes and a list of edges
+    Json,
+    /// The DOT language, for Graphviz
+    Dot,
+}
+
 /// Returns the environment variables that need to be watched for a given `[BuildType]`.
 fn sensitive_env_vars(build_type: BuildFlags) -> impl Iterator<Item = &'static str> {
     REBUILD_VARS
@@ -7,7 +7,9 @@ the repository's top-level Dockerfile.
 pub(crate) mod error;
 
 use crate::args::{BuildKitArgs, BuildPackageArgs, BuildVariantArgs, RepackVariantArgs};
+use crate::remote_cache::RemoteCache;
 use bottlerocket_variant::Variant;
+use buildsys::events::{BuildEvent, EventLog};
 use buildsys::manifest::{
     ExternalKitMetadataView, ImageFeature, ImageFormat, ImageLayout, Manifest, PartitionPlan,
     SupportedArch,
@@ -25,11 +27,13 @@ use sha2::{Digest, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashSet;
 use std::env;
-use std::fs::{self, read_dir, File};
+use std::fs::{self, read_dir, File, OpenOptions};
+use std::io::Write;
 use std::num::NonZeroU16;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Output;
+use std::time::Instant;
 use walkdir::{DirEntry, WalkDir};
 
 /*
@@ -111,6 +115,7 @@ struct CommonBuildArgs {
     token: String,
     cleanup: OutputCleanup,
     output_socket: String,
+    dry_run: bool,
 }
 
 impl CommonBuildArgs {
@@ -119,6 +124,7 @@ impl CommonBuildArgs {
         sdk: String,
         arch: SupportedArch,
         cleanup: OutputCleanup,
+        dry_run: bool,
     ) -> Self {
         let token = token(&root);
 
@@ -136,6 +142,7 @@ impl CommonBuildArgs {
             token,
             cleanup,
             output_socket,
+            dry_run,
         }
     }
 }
@@ -309,6 +316,16 @@ impl TargetBuildArgs {
             TargetBuildArgs::Repack(_) => BuildType::Repack,
         }
     }
+
+    /// The local packages and kits that this build depends on.
+    fn dependencies(&self) -> (&[String], &[String]) {
+        match self {
+            TargetBuildArgs::Package(p) => (&p.package_dependencies, &p.kit_dependencies),
+            TargetBuildArgs::Kit(k) => (&k.package_dependencies, &k.local_kits),
+            TargetBuildArgs::Variant(v) => (&v.package_dependencies, &v.kit_dependencies),
+            TargetBuildArgs::Repack(_) => (&[], &[]),
+        }
+    }
 }
 
 pub(crate) struct DockerBuild {
@@ -319,6 +336,9 @@ pub(crate) struct DockerBuild {
     root_dir: PathBuf,
     artifacts_dirs: Vec<PathBuf>,
     state_dir: PathBuf,
+    events: Option<EventLog>,
+    /// The cache to restore the build from or push it to, and the hash that identifies the build.
+    remote_cache: Option<(RemoteCache, String)>,
     artifact_name: String,
     common_build_args: CommonBuildArgs,
     target_build_args: TargetBuildArgs,
@@ -347,12 +367,15 @@ impl DockerBuild {
             root_dir: args.common.root_dir.clone(),
             artifacts_dirs: vec![per_package_dir, old_package_dir],
             state_dir: args.common.state_dir,
+            events: args.common.events_path.map(EventLog::new),
+            remote_cache: None,
             artifact_name: package.to_string(),
             common_build_args: CommonBuildArgs::new(
                 &args.common.root_dir,
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.dry_run,
             ),
             target_build_args: TargetBuildArgs::Package(PackageBuildArgs {
                 package: package.to_string(),
@@ -387,12 +410,15 @@ impl DockerBuild {
             root_dir: args.common.root_dir.clone(),
             artifacts_dirs: vec![per_kit_dir],
             state_dir: args.common.state_dir,
+            events: args.common.events_path.map(EventLog::new),
+            remote_cache: None,
             artifact_name: kit.to_string(),
             common_build_args: CommonBuildArgs::new(
                 &args.common.root_dir,
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.dry_run,
             ),
             target_build_args: TargetBuildArgs::Kit(KitBuildArgs {
                 kit: kit.to_string(),
@@ -441,12 +467,15 @@ impl DockerBuild {
                 .image_dir
                 .join(format!("{}-{}", args.common.arch, variant))],
             state_dir: args.common.state_dir,
+            events: args.common.events_path.map(EventLog::new),
+            remote_cache: None,
             artifact_name: variant.clone(),
             common_build_args: CommonBuildArgs::new(
                 &args.common.root_dir,
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::BeforeBuild,
+                args.common.dry_run,
             ),
             target_build_args: TargetBuildArgs::Variant(VariantBuildArgs {
                 package_dependencies: manifest.package_dependencies().context(error::GraphSnafu)?,
@@ -524,12 +553,15 @@ impl DockerBuild {
                 .image_dir
                 .join(format!("{}-{}", args.common.arch, variant))],
             state_dir: args.common.state_dir,
+            events: args.common.events_path.map(EventLog::new),
+            remote_cache: None,
             artifact_name: variant.clone(),
             common_build_args: CommonBuildArgs::new(
                 &args.common.root_dir,
                 args.common.sdk_image,
                 args.common.arch,
                 OutputCleanup::None,
+                args.common.dry_run,
             ),
             target_build_args: TargetBuildArgs::Repack(RepackVariantBuildArgs {
                 data_image_publish_size_gib,
@@ -557,7 +589,48 @@ impl DockerBuild {
         })
     }
 
+    /// Restore the build from `remote_cache` if it has a build with the same hash, rather than
+    /// building it, and push the build to it otherwise.
+    pub(crate) fn remote_cache(mut self, remote_cache: RemoteCache, hash: &str) -> Self {
+        self.remote_cache = Some((remote_cache, hash.to_string()));
+        self
+    }
+
+    /// Build the artifacts, recording the start and finish of the build in the event log if one
+    /// was requested. For a dry run, the build is only described.
     pub(crate) fn build(&self) -> Result<()> {
+        if self.common_build_args.dry_run {
+            print!("{}", self.describe());
+            return Ok(());
+        }
+
+        let kind = self.target_build_args.build_type();
+        let arch = self.common_build_args.arch.to_string();
+        let log_path = build_log_path(&kind, &self.artifact_name, &arch, &self.state_dir);
+
+        let (packages, kits) = self.target_build_args.dependencies();
+        let start_event =
+            BuildEvent::start(kind, &self.artifact_name, &arch).dependencies(packages, kits);
+        self.record_event(&start_event);
+        let start = Instant::now();
+
+        let result = self.build_artifacts(&log_path);
+
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.record_event(&start_event.finish(start.elapsed(), error, &log_path));
+        result
+    }
+
+    /// Failing to record an event shouldn't fail the build, so we only warn about it.
+    fn record_event(&self, event: &BuildEvent) {
+        if let Some(events) = &self.events {
+            if let Err(e) = events.append(event) {
+                println!("cargo:warning={}", e);
+            }
+        }
+    }
+
+    fn build_artifacts(&self, log_path: &Path) -> Result<()> {
         env::set_current_dir(&self.root_dir).context(error::DirectoryChangeSnafu {
             path: &self.root_dir,
         })?;
@@ -570,6 +643,9 @@ impl DockerBuild {
             &self.state_dir,
         )?;
 
+        // Start a fresh log for the output of `docker build`.
+        create_build_log(log_path)?;
+
         // Clean up any previous outputs we have tracked.
         match self.common_build_args.cleanup {
             OutputCleanup::BeforeBuild => {
@@ -578,25 +654,17 @@ impl DockerBuild {
             OutputCleanup::None => (),
         }
 
-        let mut build = format!(
-            "build {context} \
-            --target {target} \
-            --tag {tag} \
-            --network host \
-            --file {dockerfile} \
-            --no-cache-filter rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack \
-            --build-arg BYPASS_SOCKET={tag}-bypass \
-            --build-arg BUILDER_UID={uid}",
-            context = self.context.display(),
-            dockerfile = self.dockerfile.display(),
-            target = self.target,
-            tag = self.tag,
-            uid = *BUILDER_UID,
-        )
-        .split_string();
+        if let Some((remote_cache, hash)) = &self.remote_cache {
+            if remote_cache
+                .restore(hash, &marker_dir)
+                .context(error::RemoteCacheSnafu)?
+            {
+                copy_build_files(&marker_dir, &self.artifacts_dirs[0])?;
+                return Ok(());
+            }
+        }
 
-        build.extend(self.build_args());
-        build.extend(self.secrets_args.clone());
+        let build = self.docker_build_args();
 
         // Run a container with the project's root as a read-only volume mount, so that pipesys can
         // serve a read-only file descriptor that's safe to pass into builds.
@@ -623,10 +691,10 @@ impl DockerBuild {
         let rm_bypass = format!("rm --force {}-bypass", self.tag).split_string();
 
         // Clean up the previous image if it exists.
-        let _ = docker(&rm_image, Retry::No);
+        let _ = docker(&rm_image, Retry::No, None);
 
         // Clean up the stopped bypass container if it exists.
-        let _ = docker(&rm_bypass, Retry::No);
+        let _ = docker(&rm_bypass, Retry::No, None);
 
         let runtime = tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?;
 
@@ -642,7 +710,7 @@ impl DockerBuild {
         // Spawn a background task for the bypass container that will serve the project root file
         // descriptor.
         runtime.spawn(async move {
-            let _ = docker(&run_bypass, Retry::No);
+            let _ = docker(&run_bypass, Retry::No, None);
         });
 
         // Build the image, which builds the artifacts we want.
@@ -658,10 +726,11 @@ impl DockerBuild {
                     &*CREATEREPO_C_READ_HEADER_ERROR,
                 ],
             },
+            Some(log_path),
         );
 
         // Clean up our bypass container.
-        let _ = docker(&rm_bypass, Retry::No);
+        let _ = docker(&rm_bypass, Retry::No, None);
 
         // Stop the runtime and the background threads.
         runtime.shutdown_background();
@@ -670,7 +739,15 @@ impl DockerBuild {
         build_result?;
 
         // Clean up our image now that we're done.
-        docker(&rm_image, Retry::No)?;
+        docker(&rm_image, Retry::No, None)?;
+
+        // A failed push leaves the cache without this build, but the build itself is fine.
+        if let Some((remote_cache, hash)) = &self.remote_cache {
+            let goarch = self.common_build_args.arch.goarch();
+            if let Err(e) = remote_cache.store(hash, &marker_dir, goarch) {
+                println!("cargo:warning={}", e);
+            }
+        }
 
         // Copy artifacts to the expected directory and write markers to track them.
         copy_build_files(&marker_dir, &self.artifacts_dirs[0])?;
@@ -678,6 +755,83 @@ impl DockerBuild {
         Ok(())
     }
 
+    /// The arguments for `docker build`, including the build args and secrets.
+    fn docker_build_args(&self) -> Vec<String> {
+        let mut build = format!(
+            "build {context} \
+            --target {target} \
+            --tag {tag} \
+            --network host \
+            --file {dockerfile} \
+            --no-cache-filter rpmbuild,kitbuild,repobuild,imgbuild,migrationbuild,kmodkitbuild,imgrepack \
+            --build-arg BYPASS_SOCKET={tag}-bypass \
+            --build-arg BUILDER_UID={uid}",
+            context = self.context.display(),
+            dockerfile = self.dockerfile.display(),
+            target = self.target,
+            tag = self.tag,
+            uid = *BUILDER_UID,
+        )
+        .split_string();
+
+        build.extend(self.build_args());
+        build.extend(self.secrets_args.clone());
+        build
+    }
+
+    /// Describes what a build would do: the `docker build` command that would run, its inputs,
+    /// and where its outputs would go. Secrets are named by their source rather than their value.
+    fn describe(&self) -> String {
+        let kind = self.target_build_args.build_type();
+        let arch = self.common_build_args.arch.to_string();
+        let build = self.docker_build_args();
+        let (packages, kits) = self.target_build_args.dependencies();
+
+        let mut sections = vec![
+            (
+                "command",
+                vec![format!("docker {}", shell_words::join(&build))],
+            ),
+            ("build args", flag_values(&build, "--build-arg")),
+            ("secrets", flag_values(&self.secrets_args, "--secret")),
+            ("package dependencies", packages.to_vec()),
+            ("kit dependencies", kits.to_vec()),
+            (
+                "output directories",
+                self.artifacts_dirs
+                    .iter()
+                    .map(|d| d.display().to_string())
+                    .collect(),
+            ),
+            (
+                "state directory",
+                vec![
+                    marker_dir(&kind, &self.artifact_name, &arch, &self.state_dir)
+                        .display()
+                        .to_string(),
+                ],
+            ),
+        ];
+        if let Some((_, hash)) = &self.remote_cache {
+            sections.push(("remote cache hash", vec![hash.clone()]));
+        }
+
+        let mut description = format!(
+            "dry run of {} build for {}\n",
+            self.target, self.artifact_name
+        );
+        for (title, values) in sections {
+            description.push_str(&format!("{}:\n", title));
+            if values.is_empty() {
+                description.push_str("  (none)\n");
+            }
+            for value in values {
+                description.push_str(&format!("  {}\n", value));
+            }
+        }
+        description
+    }
+
     fn build_args(&self) -> Vec<String> {
         let mut args = match &self.target_build_args {
             TargetBuildArgs::Package(p) => p.build_args(),
@@ -697,8 +851,9 @@ impl DockerBuild {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-/// Run `docker` with the specified arguments.
-fn docker(args: &[String], retry: Retry) -> Result<Output> {
+/// Run `docker` with the specified arguments. The output of every attempt is appended to `log`,
+/// if given.
+fn docker(args: &[String], retry: Retry, log: Option<&Path>) -> Result<Output> {
     let mut max_attempts: u16 = 1;
     let mut retry_messages: &[&Regex] = &[];
     if let Retry::Yes { attempts, messages } = retry {
@@ -717,6 +872,13 @@ fn docker(args: &[String], retry: Retry) -> Result<Output> {
 
         let stdout = String::from_utf8_lossy(&output.stdout);
         println!("{}", &stdout);
+        if let Some(path) = log {
+            OpenOptions::new()
+                .append(true)
+                .open(path)
+                .and_then(|mut f| f.write_all(&output.stdout))
+                .context(error::FileWriteSnafu { path })?;
+        }
         if output.status.success() {
             return Ok(output);
         }
@@ -800,6 +962,28 @@ fn secrets_args() -> Result<Vec<String>> {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
+/// The directory name under the state directory for each kind of build.
+fn state_prefix(kind: &BuildType) -> &'static str {
+    match kind {
+        BuildType::Package => "packages",
+        BuildType::Kit => "kits",
+        BuildType::Variant => "variants",
+        BuildType::Repack => "variants",
+    }
+}
+
+/// The directory for build artifacts, where they are tracked before they are moved into position.
+fn marker_dir(kind: &BuildType, name: &str, arch: &str, state_dir: &Path) -> PathBuf {
+    [
+        &state_dir.display().to_string(),
+        arch,
+        state_prefix(kind),
+        name,
+    ]
+    .iter()
+    .collect()
+}
+
 /// Create a directory for build artifacts.
 fn create_marker_dir(
     kind: &BuildType,
@@ -807,22 +991,29 @@ fn create_marker_dir(
     arch: &str,
     state_dir: &Path,
 ) -> Result<PathBuf> {
-    let prefix = match kind {
-        BuildType::Package => "packages",
-        BuildType::Kit => "kits",
-        BuildType::Variant => "variants",
-        BuildType::Repack => "variants",
-    };
-
-    let path = [&state_dir.display().to_string(), arch, prefix, name]
-        .iter()
-        .collect();
-
+    let path = marker_dir(kind, name, arch, state_dir);
     fs::create_dir_all(&path).context(error::DirectoryCreateSnafu { path: &path })?;
 
     Ok(path)
 }
 
+/// The path of the file that holds the output of `docker build` for an artifact.
+fn build_log_path(kind: &BuildType, name: &str, arch: &str, state_dir: &Path) -> PathBuf {
+    state_dir
+        .join(arch)
+        .join("logs")
+        .join(state_prefix(kind))
+        .join(format!("{name}.log"))
+}
+
+/// Create an empty build log, replacing the log of any previous build.
+fn create_build_log(path: &Path) -> Result<()> {
+    let dir = path.parent().context(error::BadDirectorySnafu { path })?;
+    fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+    File::create(path).context(error::FileCreateSnafu { path })?;
+    Ok(())
+}
+
 const MARKER_EXTENSION: &str = ".buildsys_marker";
 
 /// Copy build artifacts to the output directory.
@@ -1027,6 +1218,15 @@ impl BuildSecret for Vec<String> {
     }
 }
 
+/// The values given for `flag` in a list of arguments, like the `KEY=value` of each `--build-arg`.
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.iter()
+        .zip(args.iter().skip(1))
+        .filter(|(arg, _)| *arg == flag)
+        .map(|(_, value)| value.clone())
+        .collect()
+}
+
 /// Helper trait for splitting a string on spaces into owned Strings.
 ///
 /// If you need an element with internal spaces, you should handle that separately, for example
@@ -1057,3 +1257,25 @@ fn filename(p: impl AsRef<Path>) -> String {
         .to_string_lossy()
         .to_string()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flag_values() {
+        let mut args = "--network host".split_string();
+        args.build_arg("KERNEL_PARAMETERS", "console=tty0 quiet");
+        args.build_secret("env", "aws-session-token.env", "AWS_SESSION_TOKEN");
+        args.build_arg("IMAGE_FORMAT", "raw");
+        assert_eq!(
+            flag_values(&args, "--build-arg"),
+            ["KERNEL_PARAMETERS=console=tty0 quiet", "IMAGE_FORMAT=raw"]
+        );
+        assert_eq!(
+            flag_values(&args, "--secret"),
+            ["type=env,id=aws-session-token.env,src=AWS_SESSION_TOKEN"]
+        );
+        assert!(flag_values(&args, "--tag").is_empty());
+    }
+}
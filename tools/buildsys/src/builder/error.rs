@@ -71,6 +71,12 @@ pub(crate) enum Error {
         source: std::io::Error,
     },
 
+    #[snafu(display("Failed to write file '{}': {}", path.display(), source))]
+    FileWrite {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Failed to create build arguments due to a dependency error: {source}"))]
     Graph { source: buildsys::manifest::Error },
 
@@ -80,6 +86,11 @@ pub(crate) enum Error {
         source: std::env::VarError,
     },
 
+    #[snafu(display("{source}"))]
+    RemoteCache {
+        source: crate::remote_cache::error::Error,
+    },
+
     #[snafu(display("Failed to strip prefix '{}' from path '{}': {}", prefix.display(), path.display(), source))]
     StripPathPrefix {
         path: PathBuf,
@@ -0,0 +1,279 @@
+/*!
+Bundle modules vendor the dependencies of an external file, such as its Go modules or Cargo
+crates, into a second archive that the package build can use without network access.
+
+Each kind of bundle module has a bash script that vendors the dependencies. The script runs in
+the SDK container through the docker-go tool, which mounts the package directory and the parent of
+the project, where the caches of both Go modules and Cargo crates are kept.
+
+The script is written to the package directory from a template in which these placeholders are
+replaced:
+* `__LOCAL_FILE_NAME__`: the external file.
+* `__ROOT_DIR__`: the directory within the archive to vendor, from `bundle-root-path`, or empty to
+  use the first directory in the archive.
+* `__OUTPUT__`: the archive to write, from `bundle-output-path`, which defaults to
+  `bundled-<external file>`.
+* `__PROJECT_DIR__`: the root directory of the project.
+
+ */
+
+pub(crate) mod error;
+
+use crate::{cargo_vendor, gomod};
+use buildsys::manifest::{BundleModule, ExternalFile};
+use duct::cmd;
+use error::Result;
+use filetime::{set_file_mtime, FileTime};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// How to vendor the dependencies of one kind of bundle module.
+pub(crate) struct BundleScript {
+    /// The name that the script is written to the package directory with while it runs.
+    pub(crate) name: &'static str,
+    /// The script, with the placeholders described above.
+    pub(crate) template: &'static str,
+}
+
+/// Vendors the dependencies of `external_file` with the script for `module`, in the SDK
+/// container. The bundle is given `mtime`.
+pub(crate) fn vendor(
+    module: &BundleModule,
+    root_dir: &Path,
+    package_dir: &Path,
+    external_file: &ExternalFile,
+    sdk: &str,
+    mtime: FileTime,
+) -> Result<()> {
+    let script = match module {
+        BundleModule::Go => &gomod::SCRIPT,
+        BundleModule::Cargo => &cargo_vendor::SCRIPT,
+    };
+
+    let local_file_name = local_file_name(external_file)?;
+    let full_path = package_dir.join(&local_file_name);
+    ensure!(
+        full_path.is_file(),
+        error::InputFileBadSnafu { path: full_path }
+    );
+    let output_path = output_path(external_file).context(error::InputFileSnafu)?;
+    println!("cargo:rerun-if-changed={}", output_path.display());
+
+    // Create and/or write the temporary script file to the package directory.
+    let script_contents = script_contents(script, external_file, root_dir)?;
+    let script_path = package_dir.join(script.name);
+
+    // Drop the reference after writing the file to avoid a "text busy" error
+    // when attempting to execute it.
+    {
+        let mut script_file = fs::File::create(&script_path)
+            .context(error::CreateFileSnafu { path: &script_path })?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o777))
+            .context(error::SetFilePermissionsSnafu { path: &script_path })?;
+        script_file
+            .write_all(script_contents.as_bytes())
+            .context(error::WriteFileSnafu { path: &script_path })?;
+    }
+
+    let res = docker_go_args(package_dir, sdk, root_dir, script).and_then(|args| docker_go(&args));
+    fs::remove_file(&script_path).context(error::RemoveFileSnafu { path: &script_path })?;
+
+    if res.is_ok() {
+        set_file_mtime(&output_path, mtime).context(error::SetMtimeSnafu { path: &output_path })?;
+    }
+
+    res
+}
+
+/// The archive that an external file's dependencies are bundled into, or `None` if it has no
+/// bundle modules.
+pub(crate) fn output_path(external_file: &ExternalFile) -> Option<PathBuf> {
+    match (
+        &external_file.bundle_output_path,
+        &external_file.bundle_modules,
+    ) {
+        (Some(output_path), _) => Some(output_path.clone()),
+        (None, Some(_)) => {
+            let local_file_name = local_file_name(external_file).ok()?;
+            Some(PathBuf::from(format!(
+                "bundled-{}",
+                local_file_name.to_string_lossy()
+            )))
+        }
+        (None, None) => None,
+    }
+}
+
+/// The name of the external file in the package directory.
+fn local_file_name(external_file: &ExternalFile) -> Result<PathBuf> {
+    let local_file_name = match &external_file.path {
+        Some(path) => path.clone(),
+        None => PathBuf::from(
+            external_file
+                .default_name()
+                .context(error::InputFileSnafu)?,
+        ),
+    };
+    ensure!(
+        local_file_name.components().count() == 1,
+        error::InputFileSnafu
+    );
+    Ok(local_file_name)
+}
+
+/// The script for an external file, with its placeholders replaced.
+fn script_contents(
+    script: &BundleScript,
+    external_file: &ExternalFile,
+    root_dir: &Path,
+) -> Result<String> {
+    let local_file_name = local_file_name(external_file)?;
+    let output_path = output_path(external_file).context(error::InputFileSnafu)?;
+    // If a root path was not provided, the script uses the first directory in the archive.
+    let bundle_root_path = external_file.bundle_root_path.clone().unwrap_or_default();
+    Ok(script
+        .template
+        .replace("__LOCAL_FILE_NAME__", &local_file_name.to_string_lossy())
+        .replace("__ROOT_DIR__", &bundle_root_path.to_string_lossy())
+        .replace("__OUTPUT__", &output_path.to_string_lossy())
+        .replace("__PROJECT_DIR__", &root_dir.to_string_lossy()))
+}
+
+/// The arguments to run a script in the package directory with `docker-go`.
+fn docker_go_args(
+    package_dir: &Path,
+    sdk: &str,
+    root_dir: &Path,
+    script: &BundleScript,
+) -> Result<Vec<String>> {
+    let path_arg = |path: &Path| {
+        path.to_str()
+            .map(str::to_string)
+            .context(error::InputFileSnafu)
+    };
+    Ok(vec![
+        "--module-path".to_string(),
+        path_arg(package_dir)?,
+        "--sdk-image".to_string(),
+        sdk.to_string(),
+        // docker-go mounts the parent of the project, from two levels above this.
+        "--go-mod-cache".to_string(),
+        path_arg(&root_dir.join(".gomodcache"))?,
+        "--command".to_string(),
+        format!("./{}", script.name),
+    ])
+}
+
+/// Run `docker-go` with the specified arguments.
+fn docker_go(args: &[String]) -> Result<()> {
+    let arg_string = args.join(" ");
+    let twoliter_tools_dir = env::var("TWOLITER_TOOLS_DIR").context(error::EnvironmentSnafu {
+        var: "TWOLITER_TOOLS_DIR",
+    })?;
+    let program = PathBuf::from(twoliter_tools_dir).join("docker-go");
+    let output = cmd(program, args)
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    println!("{}", &stdout);
+    ensure!(
+        output.status.success(),
+        error::DockerExecutionSnafu { args: arg_string }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn external_file(path: Option<&str>, bundle_output_path: Option<&str>) -> ExternalFile {
+        ExternalFile {
+            path: path.map(PathBuf::from),
+            sha512: "abc".to_string(),
+            url: Some("https://example.com/releases/v1.0.tar.gz".to_string()),
+            git: None,
+            rev: None,
+            force_upstream: None,
+            bundle_modules: Some(vec![BundleModule::Cargo]),
+            bundle_root_path: None,
+            bundle_output_path: bundle_output_path.map(PathBuf::from),
+            signature_url: None,
+            signing_key: None,
+        }
+    }
+
+    #[test]
+    fn test_output_path() {
+        assert_eq!(
+            output_path(&external_file(None, None)),
+            Some(PathBuf::from("bundled-v1.0.tar.gz"))
+        );
+        assert_eq!(
+            output_path(&external_file(Some("tool-1.0.tar.gz"), None)),
+            Some(PathBuf::from("bundled-tool-1.0.tar.gz"))
+        );
+        assert_eq!(
+            output_path(&external_file(
+                Some("tool-1.0.tar.gz"),
+                Some("vendor.tar.gz")
+            )),
+            Some(PathBuf::from("vendor.tar.gz"))
+        );
+
+        let mut f = external_file(None, None);
+        f.bundle_modules = None;
+        assert_eq!(output_path(&f), None);
+    }
+
+    #[test]
+    fn test_script_contents() {
+        let mut f = external_file(Some("tool-1.0.tar.gz"), None);
+        let contents = script_contents(&cargo_vendor::SCRIPT, &f, Path::new("/project")).unwrap();
+        assert!(contents.contains("tar xf tool-1.0.tar.gz\n"));
+        assert!(contents.contains("if [ -z  ] ; then"));
+        assert!(contents.contains("tar czf bundled-tool-1.0.tar.gz \"${targetdir}\"/vendor"));
+        assert!(contents.contains("export CARGO_HOME=\"/project/.cargo\""));
+        assert!(!contents.contains("__"));
+
+        f.bundle_root_path = Some(PathBuf::from("tool-1.0/cli"));
+        let contents = script_contents(&gomod::SCRIPT, &f, Path::new("/project")).unwrap();
+        assert!(contents.contains("targetdir=\"tool-1.0/cli\""));
+        assert!(!contents.contains("__"));
+
+        // An external file can't be written outside of the package directory.
+        let f = external_file(Some("../tool-1.0.tar.gz"), None);
+        assert!(script_contents(&gomod::SCRIPT, &f, Path::new("/project")).is_err());
+    }
+
+    #[test]
+    fn test_docker_go_args() {
+        let args = docker_go_args(
+            Path::new("/project/packages/tool"),
+            "sdk:latest",
+            Path::new("/project"),
+            &cargo_vendor::SCRIPT,
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "--module-path",
+                "/project/packages/tool",
+                "--sdk-image",
+                "sdk:latest",
+                "--go-mod-cache",
+                "/project/.gomodcache",
+                "--command",
+                "./docker-cargo-script.sh",
+            ]
+        );
+    }
+}
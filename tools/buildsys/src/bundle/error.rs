@@ -8,7 +8,7 @@ pub(crate) enum Error {
     #[snafu(display("Failed to start command: {}", source))]
     CommandStart { source: std::io::Error },
 
-    #[snafu(display("Failed to execute docker-go script. 'args: {}'", args))]
+    #[snafu(display("Failed to execute bundle script with docker-go. 'args: {}'", args))]
     DockerExecution { args: String },
 
     #[snafu(display("Input url is required"))]
@@ -17,12 +17,6 @@ pub(crate) enum Error {
     #[snafu(display("Input file {} must be a file", path.display()))]
     InputFileBad { path: PathBuf },
 
-    #[snafu(display("Bad file url '{}': {}", url, source))]
-    InputUrl {
-        url: String,
-        source: url::ParseError,
-    },
-
     #[snafu(display("Missing environment variable '{}'", var))]
     Environment {
         var: String,
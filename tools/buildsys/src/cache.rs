@@ -8,171 +8,523 @@ given the (name, url, hash) data that uniquely identifies each file.
 
 It implements a two-tier approach to retrieval: files are first pulled from the
 "lookaside" cache and only fetched from the upstream site if that access fails.
+The lookaside cache may have mirrors, which are tried in order. Besides HTTP(S)
+servers, a mirror may be a local directory given by a `file://` URL, or an
+S3-compatible bucket given by an `s3://` URL. Each holds a file at
+`<name>/<sha512>/<name>`.
+
+A file from a git repository has no upstream URL. Instead, its revision is
+exported to an archive that is the same each time, so it can be checked and
+cached like any other file.
+
+A file whose manifest names a signing key is also checked against its detached
+signature, fetched from upstream, whenever the file itself is fetched from upstream.
+A copy from a lookaside cache is trusted by its SHA-512 hash alone, since only
+checked files are added to the cache.
+
+Each URL is retried with a growing delay when the failure looks temporary. A
+download that is cut short leaves its partial file behind, and the next attempt
+asks the server for the rest of it rather than starting over.
 
 */
 pub(crate) mod error;
+mod git;
+mod s3;
+mod signature;
 use error::Result;
 
-use buildsys::manifest;
+use buildsys::manifest::{self, Upstream};
 use filetime::{set_file_mtime, FileTime};
-use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use reqwest::blocking::{Body, Client};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE, RANGE, USER_AGENT};
+use reqwest::{Method, StatusCode};
 use sha2::{Digest, Sha512};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::fs::{self, File};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use url::Url;
 
+/// How many times to try a URL before moving on to the next one.
+const ATTEMPTS: u32 = 4;
+
+/// How long to wait before trying a URL again. This doubles after each attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How many of a package's external files to download at once.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
 pub(crate) struct LookasideCache {
-    /// The version string to include in HTTP headers.
-    version: String,
+    client: Client,
 
-    /// The lookaside cache base URL for source tarballs.
-    lookaside_cache: Url,
+    /// The lookaside caches for source tarballs, in the order to try them.
+    mirrors: Vec<Mirror>,
 
     /// Whether we are allowed to pull sources from upstream URLs. When this is false, it can be
     /// overridden by `upstream-fallback` in the manifest.
     upstream_fallback: bool,
+
+    /// How long to wait before the first retry of a URL.
+    backoff: Duration,
 }
 
 impl LookasideCache {
     pub(crate) fn new(
         version: impl AsRef<str>,
-        lookaside_cache: Url,
+        lookaside_caches: Vec<Url>,
         upstream_fallback: bool,
-    ) -> Self {
-        Self {
-            version: version.as_ref().to_string(),
-            lookaside_cache,
+    ) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(&format!(
+                "Bottlerocket buildsys {} (https://github.com/bottlerocket-os/bottlerocket)",
+                version.as_ref()
+            ))
+            .unwrap_or(HeaderValue::from_static(
+                "Bottlerocket buildsys (https://github.com/bottlerocket-os/bottlerocket)",
+            )),
+        );
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .context(error::HttpClientSnafu)?;
+
+        let mirrors = lookaside_caches
+            .iter()
+            .map(Mirror::new)
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            client,
+            mirrors,
             upstream_fallback,
-        }
+            backoff: INITIAL_BACKOFF,
+        })
     }
 
-    /// Fetch files stored out-of-tree and ensure they match the stored hash.
+    /// Fetch files stored out-of-tree and ensure they match the stored hash. Several files are
+    /// fetched at once.
     pub(crate) fn fetch(&self, files: &[manifest::ExternalFile], mtime: FileTime) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut fetches = Vec::new();
         for f in files {
-            let url_file_name = Self::extract_file_name(&f.url)?;
-            let path = &f.path.as_ref().unwrap_or(&url_file_name);
+            let path = Self::file_name(f)?;
+            // Two fetches of the same file would write the same temporary file.
             ensure!(
-                path.components().count() == 1,
-                error::ExternalFileNameSnafu { path }
+                names.insert(path.clone()),
+                error::DuplicateExternalFileSnafu { path }
             );
+            fetches.push((f, path));
+        }
 
-            let hash = &f.sha512;
-            if path.is_file() {
-                match Self::verify_file(path, hash) {
-                    Ok(_) => continue,
-                    Err(e) => {
-                        println!("{}", e);
-                        fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
-                    }
-                }
+        let next = AtomicUsize::new(0);
+        let workers = fetches.len().min(MAX_CONCURRENT_FETCHES);
+        thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        while let Some((f, path)) = fetches.get(next.fetch_add(1, Ordering::SeqCst))
+                        {
+                            self.fetch_one(f, path, mtime)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect::<Result<Vec<()>>>()
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns the external files that are missing from the lookaside cache, which is the first
+    /// one when there are mirrors. Each file is paired with the directory of its package.
+    pub(crate) fn missing<'a>(
+        &self,
+        files: &[(&'a Path, &'a manifest::ExternalFile)],
+    ) -> Result<Vec<(&'a Path, &'a manifest::ExternalFile)>> {
+        let mirror = self.target()?;
+        let mut missing = Vec::new();
+        for &(package_dir, f) in files {
+            let name = Self::file_name(f)?.display().to_string();
+            if !self.contains(&mirror.source(&name, &f.sha512)?)? {
+                missing.push((package_dir, f));
             }
+        }
+        Ok(missing)
+    }
+
+    /// Downloads external files from upstream, checks them, and adds them to the lookaside cache,
+    /// which is the first one when there are mirrors. Each file is paired with the directory of
+    /// its package, which its signing key is relative to.
+    pub(crate) fn populate(&self, files: &[(&Path, &manifest::ExternalFile)]) -> Result<()> {
+        let mirror = self.target()?;
+        let work_dir = tempfile::tempdir().context(error::TempDirSnafu)?;
+        for &(package_dir, f) in files {
+            let name = Self::file_name(f)?.display().to_string();
+            let tmp = work_dir.path().join(&name);
+            println!("Fetching {:?} from upstream source", name);
+            self.fetch_upstream(f, &tmp, package_dir)?;
+            let target = mirror.source(&name, &f.sha512)?;
+            println!("Adding {:?} to {}", name, target);
+            self.upload(&target, &tmp)?;
+            fs::remove_file(&tmp).context(error::ExternalFileDeleteSnafu { path: &tmp })?;
+        }
+        Ok(())
+    }
 
-            let name = &path.display().to_string();
-            let tmp = PathBuf::from(format!(".{}", name));
+    /// The lookaside cache to populate.
+    fn target(&self) -> Result<&Mirror> {
+        self.mirrors.first().context(error::NoTargetSnafu)
+    }
 
-            // first check the lookaside cache
-            let mut url = self.lookaside_cache.clone();
-            url.path_segments_mut()
-                .map_err(|_| {
-                    error::UrlPathSegmentsSnafu {
-                        url: self.lookaside_cache.clone(),
-                    }
-                    .build()
+    /// Whether a source has a file.
+    fn contains(&self, source: &Source<'_>) -> Result<bool> {
+        let request = match source {
+            Source::Path(path) => return Ok(path.is_file()),
+            Source::Url(url) => self.client.head(url),
+            Source::S3(bucket, key) => bucket.request(&self.client, Method::HEAD, key)?,
+        };
+        let url = &source.to_string();
+        let resp = request
+            .send()
+            .context(error::ExternalFileRequestSnafu { url })?;
+        match resp.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => error::ExternalFileFetchSnafu { url, status }.fail(),
+        }
+    }
+
+    /// Copies a file to a source.
+    fn upload(&self, source: &Source<'_>, from: &Path) -> Result<()> {
+        match source {
+            Source::Path(path) => {
+                let dir = path
+                    .parent()
+                    .context(error::ExternalFileNameSnafu { path })?;
+                fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+                // Readers never see a partial file.
+                let tmp = dir.join(format!(
+                    ".{}",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                ));
+                fs::copy(from, &tmp).context(error::ExternalFileCopySnafu { path: from })?;
+                fs::rename(&tmp, path).context(error::ExternalFileRenameSnafu { path: &tmp })
+            }
+            Source::S3(bucket, key) => {
+                let file = File::open(from).context(error::ExternalFileOpenSnafu { path: from })?;
+                let len = file
+                    .metadata()
+                    .context(error::ExternalFileLoadSnafu { path: from })?
+                    .len();
+                let url = source.to_string();
+                let resp = bucket
+                    .request(&self.client, Method::PUT, key)?
+                    .body(Body::sized(file, len))
+                    .send()
+                    .context(error::ExternalFileRequestSnafu { url: &url })?;
+                let status = resp.status();
+                ensure!(
+                    status.is_success(),
+                    error::ExternalFileUploadSnafu { url, status }
+                );
+                Ok(())
+            }
+            Source::Url(url) => error::UploadUnsupportedSnafu { url }.fail(),
+        }
+    }
+
+    /// The name of an external file, which it is stored under locally and in the lookaside cache.
+    fn file_name(f: &manifest::ExternalFile) -> Result<PathBuf> {
+        let path = match &f.path {
+            Some(path) => path.clone(),
+            None => f
+                .default_name()
+                .context(error::ExternalFileNameSnafu {
+                    path: f.upstream().to_string(),
                 })?
-                .extend([name, hash, name]);
-            let url = url.to_string();
-            match self.fetch_file(&url, &tmp, hash) {
-                Ok(_) => {
-                    fs::rename(&tmp, path)
-                        .context(error::ExternalFileRenameSnafu { path: &tmp })?;
-                    set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
-                    continue;
+                .into(),
+        };
+        ensure!(
+            path.components().count() == 1,
+            error::ExternalFileNameSnafu { path }
+        );
+        Ok(path)
+    }
+
+    /// Fetch a single file to `path`, unless it is already there.
+    fn fetch_one(&self, f: &manifest::ExternalFile, path: &Path, mtime: FileTime) -> Result<()> {
+        let hash = &f.sha512;
+        if path.is_file() {
+            match Self::verify_file(path, hash) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    println!("{}", e);
+                    fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
                 }
+            }
+        }
+
+        let name = &path.display().to_string();
+        let tmp = PathBuf::from(format!(".{}", name));
+
+        // first check the lookaside caches
+        let mut lookaside_error = None;
+        for source in self.lookaside_sources(name, hash)? {
+            match self.fetch_file(&source, &tmp, hash) {
+                Ok(_) => return Self::finish(&tmp, path, mtime),
                 Err(e) => {
-                    // next check with upstream, if permitted
-                    if f.force_upstream.unwrap_or(false) || self.upstream_fallback {
-                        println!("Error fetching from lookaside cache: {}", e);
-                        println!("Fetching {:?} from upstream source", url_file_name);
-                        self.fetch_file(&f.url, &tmp, hash)?;
-                        fs::rename(&tmp, path)
-                            .context(error::ExternalFileRenameSnafu { path: &tmp })?;
-                        set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })?;
-                    } else {
-                        // we failed to fetch from the lookaside cache, and we cannot fall back to
-                        // upstream sources, so we should not continue, we need to return the error
-                        return Err(e);
-                    }
+                    println!("Error fetching from lookaside cache: {}", e);
+                    lookaside_error = Some(e);
                 }
             }
         }
 
-        Ok(())
+        // next check with upstream, if permitted
+        if f.force_upstream.unwrap_or(false) || self.upstream_fallback {
+            println!("Fetching {:?} from upstream source", name);
+            // Packages are built from their own directories.
+            self.fetch_upstream(f, &tmp, Path::new("."))?;
+            return Self::finish(&tmp, path, mtime);
+        }
+
+        // we failed to fetch from the lookaside caches, and we cannot fall back to upstream
+        // sources, so we should not continue, we need to return the error
+        match lookaside_error {
+            Some(e) => Err(e),
+            None => error::NoLookasideCacheSnafu { path }.fail(),
+        }
     }
 
-    /// Retrieves a file from the specified URL and write it to the given path,
-    /// then verifies the contents against the SHA-512 hash provided.
-    fn fetch_file<P: AsRef<Path>>(&self, url: &str, path: P, hash: &str) -> Result<()> {
-        let path = path.as_ref();
+    /// Where to find a file in each of the lookaside caches.
+    fn lookaside_sources(&self, name: &str, hash: &str) -> Result<Vec<Source<'_>>> {
+        self.mirrors
+            .iter()
+            .map(|mirror| mirror.source(name, hash))
+            .collect()
+    }
 
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            USER_AGENT,
-            HeaderValue::from_str(&format!(
-                "Bottlerocket buildsys {} (https://github.com/bottlerocket-os/bottlerocket)",
-                self.version
-            ))
-            .unwrap_or(HeaderValue::from_static(
-                "Bottlerocket buildsys (https://github.com/bottlerocket-os/bottlerocket)",
-            )),
-        );
+    /// Moves a fetched file into place.
+    fn finish(tmp: &Path, path: &Path, mtime: FileTime) -> Result<()> {
+        fs::rename(tmp, path).context(error::ExternalFileRenameSnafu { path: tmp })?;
+        set_file_mtime(path, mtime).context(error::SetMtimeSnafu { path })
+    }
 
-        let client = reqwest::blocking::Client::new();
-        let mut resp = client
-            .get(url)
-            .headers(headers)
-            .send()
-            .context(error::ExternalFileRequestSnafu { url })?;
-        let status = resp.status();
-        ensure!(
-            status.is_success(),
-            error::ExternalFileFetchSnafu { url, status }
-        );
+    /// Retrieves a file from the specified source and write it to the given path,
+    /// then verifies the contents against the SHA-512 hash provided.
+    fn fetch_file<P: AsRef<Path>>(&self, source: &Source<'_>, path: P, hash: &str) -> Result<()> {
+        let path = path.as_ref();
+        // A partial file left by an earlier run may not belong to this file at all.
+        let resumed = path.is_file();
 
-        let f = File::create(path).context(error::ExternalFileOpenSnafu { path })?;
-        let mut f = BufWriter::new(f);
-        resp.copy_to(&mut f)
-            .context(error::ExternalFileSaveSnafu { path })?;
-        drop(f);
+        self.download_with_retries(source, path)?;
 
         match Self::verify_file(path, hash) {
             Ok(_) => Ok(()),
             Err(e) => {
                 fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                if resumed {
+                    println!("{}; downloading it again", e);
+                    return self.fetch_file(source, path, hash);
+                }
                 Err(e)
             }
         }
     }
 
-    fn extract_file_name(url: &str) -> Result<PathBuf> {
-        let parsed = reqwest::Url::parse(url).context(error::ExternalFileUrlSnafu { url })?;
-        let name = parsed
-            .path_segments()
-            .context(error::ExternalFileNameSnafu { path: url })?
-            .last()
-            .context(error::ExternalFileNameSnafu { path: url })?;
-        Ok(name.into())
+    /// Fetches a file from upstream and checks its hash, and its signature if it has one, which
+    /// is relative to `package_dir`. A file from a git repository is exported from it rather than
+    /// downloaded.
+    fn fetch_upstream(
+        &self,
+        f: &manifest::ExternalFile,
+        path: &Path,
+        package_dir: &Path,
+    ) -> Result<()> {
+        match f.upstream() {
+            Upstream::Url(url) => {
+                self.fetch_file(&Source::Url(url.to_string()), path, &f.sha512)?;
+            }
+            upstream @ Upstream::Git { repo, rev } => {
+                let prefix = upstream
+                    .archive_prefix()
+                    .context(error::ExternalFileNameSnafu { path: repo })?;
+                git::export(repo, rev, &prefix, path)?;
+                if let Err(e) = Self::verify_file(path, &f.sha512) {
+                    fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                    return Err(e);
+                }
+            }
+        }
+        self.verify_signature(f, path, package_dir)
+    }
+
+    /// Downloads a file, trying again after failures that look temporary.
+    fn download_with_retries(&self, source: &Source<'_>, path: &Path) -> Result<()> {
+        let mut backoff = self.backoff;
+        for attempt in 1..=ATTEMPTS {
+            match self.download(source, path) {
+                Ok(_) => break,
+                Err(e) if attempt < ATTEMPTS && e.is_transient() => {
+                    println!("{}; retrying in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a fetched file against its detached signature, if its manifest names a signing key,
+    /// which is relative to `package_dir`. The file is removed if the check fails.
+    fn verify_signature(
+        &self,
+        f: &manifest::ExternalFile,
+        path: &Path,
+        package_dir: &Path,
+    ) -> Result<()> {
+        let Some((signature_url, signing_key)) = Self::signature(f)? else {
+            return Ok(());
+        };
+        let mut signature = path.as_os_str().to_owned();
+        signature.push(".sig");
+        let signature = PathBuf::from(signature);
+        // Signatures are small, so there is nothing worth resuming.
+        if signature.exists() {
+            fs::remove_file(&signature)
+                .context(error::ExternalFileDeleteSnafu { path: &signature })?;
+        }
+        self.download_with_retries(&Source::Url(signature_url.clone()), &signature)?;
+
+        let verified = signature::verify(path, &signature, &package_dir.join(signing_key));
+        fs::remove_file(&signature).context(error::ExternalFileDeleteSnafu { path: &signature })?;
+        if verified.is_err() {
+            fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+        }
+        verified
+    }
+
+    /// The signature URL and signing key of an external file, if it has them.
+    fn signature(f: &manifest::ExternalFile) -> Result<Option<(&String, &PathBuf)>> {
+        match (&f.signature_url, &f.signing_key) {
+            (Some(signature_url), Some(signing_key)) => {
+                ensure!(
+                    matches!(f.upstream(), Upstream::Url(_)),
+                    error::GitSignatureSnafu {
+                        repo: f.upstream().to_string()
+                    }
+                );
+                Ok(Some((signature_url, signing_key)))
+            }
+            (None, None) => Ok(None),
+            _ => error::SignatureIncompleteSnafu {
+                url: f.upstream().to_string(),
+            }
+            .fail(),
+        }
+    }
+
+    /// Downloads an external file from upstream and checks its signature, then returns its
+    /// SHA-512 hash. Returns `None` for a file without a signature, whose hash can't be trusted.
+    pub(crate) fn signed_hash(
+        &self,
+        f: &manifest::ExternalFile,
+        package_dir: &Path,
+    ) -> Result<Option<String>> {
+        if Self::signature(f)?.is_none() {
+            return Ok(None);
+        }
+        let work_dir = tempfile::tempdir().context(error::TempDirSnafu)?;
+        let path = work_dir.path().join(Self::file_name(f)?);
+        self.download_with_retries(&Source::Url(f.upstream().to_string()), &path)?;
+        self.verify_signature(f, &path, package_dir)?;
+        Ok(Some(Self::sha512(&path)?))
+    }
+
+    /// Downloads a file from the specified source to the given path. If part of the file is
+    /// already there, only the rest of it is requested.
+    fn download(&self, source: &Source<'_>, path: &Path) -> Result<()> {
+        let mut request = match source {
+            Source::Url(url) => self.client.get(url),
+            Source::S3(bucket, key) => bucket.request(&self.client, Method::GET, key)?,
+            Source::Path(from) => {
+                fs::copy(from, path).context(error::ExternalFileCopySnafu { path: from })?;
+                return Ok(());
+            }
+        };
+        let url = &source.to_string();
+        let offset = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let mut resp = request
+            .send()
+            .context(error::ExternalFileRequestSnafu { url })?;
+
+        let status = resp.status();
+        let append = match status {
+            StatusCode::PARTIAL_CONTENT => {
+                if Self::range_start(&resp) != Some(offset) {
+                    // Start over on the next attempt.
+                    fs::remove_file(path).context(error::ExternalFileDeleteSnafu { path })?;
+                    return error::ExternalFileResumeSnafu { url, path }.fail();
+                }
+                true
+            }
+            // There is nothing after the end of the partial file, so it is either complete or
+            // wrong, and verifying it will tell.
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+            // The server ignored the range, and sent the whole file.
+            _ if status.is_success() => false,
+            _ => return error::ExternalFileFetchSnafu { url, status }.fail(),
+        };
+
+        let f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .context(error::ExternalFileOpenSnafu { path })?;
+        let mut f = BufWriter::new(f);
+        resp.copy_to(&mut f)
+            .context(error::ExternalFileSaveSnafu { path })?;
+        Ok(())
+    }
+
+    /// The first byte of a partial response, from a `Content-Range` header such as
+    /// `bytes 100-199/200`.
+    fn range_start(resp: &reqwest::blocking::Response) -> Option<u64> {
+        resp.headers()
+            .get(CONTENT_RANGE)?
+            .to_str()
+            .ok()?
+            .strip_prefix("bytes ")?
+            .split('-')
+            .next()?
+            .parse()
+            .ok()
     }
 
     /// Reads a file from disk and compares it to the expected SHA-512 hash.
     fn verify_file<P: AsRef<Path>>(path: P, hash: &str) -> Result<()> {
         let path = path.as_ref();
-        let mut f = File::open(path).context(error::ExternalFileOpenSnafu { path })?;
-        let mut d = Sha512::new();
-
-        io::copy(&mut f, &mut d).context(error::ExternalFileLoadSnafu { path })?;
-        let digest = hex::encode(d.finalize());
+        let digest = Self::sha512(path)?;
 
         ensure!(
             digest == hash,
@@ -180,4 +532,355 @@ impl LookasideCache {
         );
         Ok(())
     }
+
+    /// Reads a file from disk and returns its SHA-512 hash.
+    fn sha512(path: &Path) -> Result<String> {
+        let mut f = File::open(path).context(error::ExternalFileOpenSnafu { path })?;
+        let mut d = Sha512::new();
+        io::copy(&mut f, &mut d).context(error::ExternalFileLoadSnafu { path })?;
+        Ok(hex::encode(d.finalize()))
+    }
+}
+
+/// A lookaside cache.
+enum Mirror {
+    Http(Url),
+    Directory(PathBuf),
+    S3(s3::Bucket),
+}
+
+impl Mirror {
+    fn new(url: &Url) -> Result<Self> {
+        match url.scheme() {
+            "http" | "https" => Ok(Self::Http(url.clone())),
+            "file" => Ok(Self::Directory(url.to_file_path().map_err(|_| {
+                error::LookasideCacheSchemeSnafu { url: url.as_str() }.build()
+            })?)),
+            "s3" => Ok(Self::S3(s3::Bucket::new(url)?)),
+            _ => error::LookasideCacheSchemeSnafu { url: url.as_str() }.fail(),
+        }
+    }
+
+    /// Where the mirror holds a file, at `<name>/<sha512>/<name>`.
+    fn source(&self, name: &str, hash: &str) -> Result<Source<'_>> {
+        Ok(match self {
+            Mirror::Http(lookaside_cache) => {
+                let mut url = lookaside_cache.clone();
+                url.path_segments_mut()
+                    .map_err(|_| {
+                        error::UrlPathSegmentsSnafu {
+                            url: lookaside_cache.clone(),
+                        }
+                        .build()
+                    })?
+                    .pop_if_empty()
+                    .extend([name, hash, name]);
+                Source::Url(url.to_string())
+            }
+            Mirror::Directory(dir) => Source::Path(dir.join(name).join(hash).join(name)),
+            Mirror::S3(bucket) => Source::S3(
+                bucket,
+                vec![name.to_string(), hash.to_string(), name.to_string()],
+            ),
+        })
+    }
+}
+
+/// Where to get a copy of an external file.
+enum Source<'a> {
+    Url(String),
+    Path(PathBuf),
+    /// A bucket and the key of an object in it.
+    S3(&'a s3::Bucket, Vec<String>),
+}
+
+impl fmt::Display for Source<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Url(url) => write!(f, "{}", url),
+            Source::Path(path) => write!(f, "{}", path.display()),
+            Source::S3(bucket, key) => write!(f, "{}", bucket.object_name(key)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    const CONTENT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    fn sha512(data: &[u8]) -> String {
+        hex::encode(Sha512::digest(data))
+    }
+
+    /// A server that answers each request with the next of `responses`, which are status codes.
+    /// A 200 sends all of `CONTENT` and a 206 sends the range that was asked for. Returns the base
+    /// URL and the `Range` headers of the requests, if any.
+    fn serve(responses: Vec<u16>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let seen = ranges.clone();
+        thread::spawn(move || {
+            for status in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut range = None;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        range = Some(value.trim_end_matches('-').to_string());
+                    }
+                }
+                seen.lock().unwrap().push(range.clone());
+                let (body, extra) = match status {
+                    200 => (CONTENT, String::new()),
+                    206 => {
+                        let start: usize = range.unwrap().parse().unwrap();
+                        let header = format!(
+                            "Content-Range: bytes {}-{}/{}\r\n",
+                            start,
+                            CONTENT.len() - 1,
+                            CONTENT.len()
+                        );
+                        (&CONTENT[start..], header)
+                    }
+                    _ => (&b""[..], String::new()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+                    status,
+                    body.len(),
+                    extra
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        (base, ranges)
+    }
+
+    fn cache() -> LookasideCache {
+        let mut cache = LookasideCache::new("0.0.0", Vec::new(), false).unwrap();
+        cache.backoff = Duration::from_millis(1);
+        cache
+    }
+
+    fn url(base: &str) -> Source<'static> {
+        Source::Url(format!("{}/file", base))
+    }
+
+    #[test]
+    fn test_lookaside_sources() {
+        let mut cache = cache();
+        cache.mirrors = [
+            "https://cache.example.com",
+            "https://mirror.example.com/sources/",
+            "file:///srv/sources",
+        ]
+        .iter()
+        .map(|url| Mirror::new(&url.parse().unwrap()).unwrap())
+        .collect();
+        assert_eq!(
+            cache
+                .lookaside_sources("a.tar.gz", "abc")
+                .unwrap()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "https://cache.example.com/a.tar.gz/abc/a.tar.gz",
+                "https://mirror.example.com/sources/a.tar.gz/abc/a.tar.gz",
+                "/srv/sources/a.tar.gz/abc/a.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_populate_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("cache");
+        let (base, _) = serve(vec![200]);
+        let f = manifest::ExternalFile {
+            path: None,
+            sha512: sha512(CONTENT),
+            url: Some(format!("{}/a.tar.gz", base)),
+            git: None,
+            rev: None,
+            force_upstream: None,
+            bundle_modules: None,
+            bundle_root_path: None,
+            bundle_output_path: None,
+            signature_url: None,
+            signing_key: None,
+        };
+        let mut cache = cache();
+        cache.mirrors = vec![Mirror::new(&Url::from_directory_path(&target).unwrap()).unwrap()];
+
+        let files = [(temp_dir.path(), &f)];
+        let missing = cache.missing(&files).unwrap();
+        assert_eq!(missing.len(), 1);
+        cache.populate(&missing).unwrap();
+        assert!(cache.missing(&files).unwrap().is_empty());
+        let cached = target
+            .join("a.tar.gz")
+            .join(sha512(CONTENT))
+            .join("a.tar.gz");
+        assert_eq!(fs::read(cached).unwrap(), CONTENT);
+    }
+
+    #[test]
+    fn test_populate_checks_signature() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("cache");
+        // The file is found, but its signature isn't.
+        let (base, ranges) = serve(vec![200, 404]);
+        let f = manifest::ExternalFile {
+            path: None,
+            sha512: sha512(CONTENT),
+            url: Some(format!("{}/a.tar.gz", base)),
+            git: None,
+            rev: None,
+            force_upstream: None,
+            bundle_modules: None,
+            bundle_root_path: None,
+            bundle_output_path: None,
+            signature_url: Some(format!("{}/a.tar.gz.sig", base)),
+            signing_key: Some(PathBuf::from("key.asc")),
+        };
+        let mut cache = cache();
+        cache.mirrors = vec![Mirror::new(&Url::from_directory_path(&target).unwrap()).unwrap()];
+
+        let files = [(temp_dir.path(), &f)];
+        assert!(cache.populate(&files).is_err());
+        assert_eq!(ranges.lock().unwrap().len(), 2);
+        assert_eq!(cache.missing(&files).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_signature_needs_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, CONTENT).unwrap();
+        let f = manifest::ExternalFile {
+            path: None,
+            sha512: sha512(CONTENT),
+            url: Some("https://example.com/file".to_string()),
+            git: None,
+            rev: None,
+            force_upstream: None,
+            bundle_modules: None,
+            bundle_root_path: None,
+            bundle_output_path: None,
+            signature_url: Some("https://example.com/file.sig".to_string()),
+            signing_key: None,
+        };
+        assert!(cache()
+            .verify_signature(&f, &path, temp_dir.path())
+            .is_err());
+        assert!(cache().signed_hash(&f, temp_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_mirror() {
+        assert!(Mirror::new(&"ftp://cache.example.com".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_fetch_file_from_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("from");
+        fs::write(&from, CONTENT).unwrap();
+        let path = temp_dir.path().join("file");
+        cache()
+            .fetch_file(&Source::Path(from.clone()), &path, &sha512(CONTENT))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), CONTENT);
+        assert!(cache()
+            .fetch_file(&Source::Path(from), &path, &sha512(b"other"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_fetch_file_retries() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        let (base, ranges) = serve(vec![503, 200]);
+        cache()
+            .fetch_file(&url(&base), &path, &sha512(CONTENT))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), CONTENT);
+        assert_eq!(ranges.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_fetch_file_does_not_retry_missing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        let (base, ranges) = serve(vec![404, 200]);
+        assert!(cache()
+            .fetch_file(&url(&base), &path, &sha512(CONTENT))
+            .is_err());
+        assert_eq!(ranges.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fetch_file_resumes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, &CONTENT[..10]).unwrap();
+        let (base, ranges) = serve(vec![206]);
+        cache()
+            .fetch_file(&url(&base), &path, &sha512(CONTENT))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), CONTENT);
+        assert_eq!(*ranges.lock().unwrap(), [Some("10".to_string())]);
+    }
+
+    #[test]
+    fn test_fetch_file_restarts_bad_resume() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, b"garbage").unwrap();
+        let (base, ranges) = serve(vec![206, 200]);
+        cache()
+            .fetch_file(&url(&base), &path, &sha512(CONTENT))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), CONTENT);
+        assert_eq!(*ranges.lock().unwrap(), [Some("7".to_string()), None]);
+    }
+
+    #[test]
+    fn test_fetch_file_restarts_when_range_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        fs::write(&path, b"garbage").unwrap();
+        let (base, _) = serve(vec![200]);
+        cache()
+            .fetch_file(&url(&base), &path, &sha512(CONTENT))
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), CONTENT);
+    }
+
+    #[test]
+    fn test_fetch_file_removes_bad_download() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file");
+        let (base, _) = serve(vec![200]);
+        assert!(cache()
+            .fetch_file(&url(&base), &path, &sha512(b"other"))
+            .is_err());
+        assert!(!path.exists());
+    }
 }
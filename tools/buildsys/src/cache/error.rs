@@ -50,6 +50,120 @@ pub(crate) enum Error {
 
     #[snafu(display("Failed to get path segments from URL '{}'", url))]
     UrlPathSegments { url: String },
+
+    #[snafu(display("More than one external file is named '{}'", path.display()))]
+    DuplicateExternalFile { path: PathBuf },
+
+    #[snafu(display("No lookaside cache is configured to fetch '{}' from", path.display()))]
+    NoLookasideCache { path: PathBuf },
+
+    #[snafu(display("No lookaside cache was given to populate"))]
+    NoTarget,
+
+    #[snafu(display("Failed to create HTTP client: {}", source))]
+    HttpClient { source: reqwest::Error },
+
+    #[snafu(display("Failed to create async runtime: {}", source))]
+    AsyncRuntime { source: io::Error },
+
+    #[snafu(display(
+        "Unsupported lookaside cache '{}': expected an http, https, file or s3 URL",
+        url
+    ))]
+    LookasideCacheScheme { url: String },
+
+    #[snafu(display("Failed to copy '{}' from the lookaside cache: {}", path.display(), source))]
+    ExternalFileCopy { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Bad S3 bucket URL '{}'", url))]
+    S3Bucket { url: String },
+
+    #[snafu(display("No AWS credentials are available to read from S3"))]
+    S3CredentialsMissing,
+
+    #[snafu(display("Failed to load AWS credentials: {}", source))]
+    S3Credentials {
+        source: aws_credential_types::provider::error::CredentialsError,
+    },
+
+    #[snafu(display("Failed to sign request for '{}': {}", url, source))]
+    S3Sign {
+        url: String,
+        source: aws_sigv4::http_request::SigningError,
+    },
+
+    #[snafu(display("Failed to set up request signing: {}", source))]
+    S3SigningParams {
+        source: aws_sigv4::sign::v4::signing_params::BuildError,
+    },
+
+    #[snafu(display(
+        "S3 bucket '{}' is unavailable, since its AWS configuration failed to load",
+        url
+    ))]
+    S3Unavailable { url: String },
+
+    #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+    DirectoryCreate { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to create temporary directory: {}", source))]
+    TempDir { source: io::Error },
+
+    #[snafu(display("Failed to upload to '{}': {}", url, status))]
+    ExternalFileUpload {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[snafu(display(
+        "Unable to add files to '{}': only file and s3 lookaside caches can be populated",
+        url
+    ))]
+    UploadUnsupported { url: String },
+
+    #[snafu(display("External file '{}' needs both a signature-url and a signing-key", url))]
+    SignatureIncomplete { url: String },
+
+    #[snafu(display("Failed to read signing key '{}': {}", path.display(), source))]
+    SigningKeyRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to import signing key '{}': {}", path.display(), source))]
+    SigningKeyImport { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to verify the signature of '{}': {}", path.display(), source))]
+    SignatureVerify { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to export from git repository '{}': {}", repo, source))]
+    Git { repo: String, source: io::Error },
+
+    #[snafu(display("Failed to write git archive '{}': {}", path.display(), source))]
+    GitArchiveWrite { path: PathBuf, source: io::Error },
+
+    #[snafu(display(
+        "External file '{}' is from git, so it has no detached signature",
+        repo
+    ))]
+    GitSignature { repo: String },
+
+    #[snafu(display("'{}' did not resume the download of '{}' where it left off", url, path.display()))]
+    ExternalFileResume { url: String, path: PathBuf },
+}
+
+impl Error {
+    /// Whether trying the same URL again might succeed.
+    pub(super) fn is_transient(&self) -> bool {
+        match self {
+            Error::ExternalFileRequest { .. }
+            | Error::ExternalFileSave { .. }
+            | Error::ExternalFileResume { .. } => true,
+            Error::ExternalFileFetch { status, .. } => {
+                status.is_server_error()
+                    || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
 }
 
 pub(super) type Result<T> = std::result::Result<T, Error>;
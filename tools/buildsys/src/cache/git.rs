@@ -0,0 +1,145 @@
+/*!
+Exports a revision of a git repository to a `.tar.gz` archive, for external files that name a
+repository rather than a URL.
+
+`git archive` sorts the files and sets every mtime to the time of the commit, so with a fixed
+umask its output depends only on the commit. The archive is compressed here rather than by
+`git`, with the gzip header's mtime left at zero, so that the compressed bytes don't depend on
+the version of `git` or `gzip` either.
+*/
+use super::error::{self, Result};
+use duct::cmd;
+use flate2::{Compression, GzBuilder};
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes `rev` of `repo` to `path` as a compressed archive, with its files under `prefix`.
+pub(super) fn export(repo: &str, rev: &str, prefix: &str, path: &Path) -> Result<()> {
+    let git_dir = tempfile::tempdir().context(error::TempDirSnafu)?;
+    let git_dir = git_dir.path();
+    git(git_dir, &["init", "--quiet", "--bare"], repo)?;
+    // Most servers let us fetch just the commit. Otherwise, fetch everything and find it.
+    if git(
+        git_dir,
+        &["fetch", "--quiet", "--depth", "1", repo, rev],
+        repo,
+    )
+    .is_err()
+    {
+        git(
+            git_dir,
+            &[
+                "fetch",
+                "--quiet",
+                "--tags",
+                repo,
+                "+refs/heads/*:refs/heads/*",
+            ],
+            repo,
+        )?;
+    }
+
+    let commit = format!("{}^{{commit}}", rev);
+    let archive_prefix = format!("{}/", prefix);
+    // The modes of files would otherwise depend on the user's git configuration.
+    let mut archive = cmd!(
+        "git",
+        "-c",
+        "tar.umask=0022",
+        "archive",
+        "--format=tar",
+        "--prefix",
+        &archive_prefix,
+        &commit
+    )
+    .env("GIT_DIR", git_dir)
+    .reader()
+    .context(error::GitSnafu { repo })?;
+
+    let file = File::create(path).context(error::ExternalFileOpenSnafu { path })?;
+    let mut gz = GzBuilder::new().write(BufWriter::new(file), Compression::default());
+    io::copy(&mut archive, &mut gz).context(error::GitSnafu { repo })?;
+    gz.finish()
+        .and_then(|mut f| f.flush())
+        .context(error::GitArchiveWriteSnafu { path })?;
+    Ok(())
+}
+
+/// Runs a git command against the repository in `git_dir`.
+fn git(git_dir: &Path, args: &[&str], repo: &str) -> Result<()> {
+    cmd("git", args.to_vec())
+        .env("GIT_DIR", git_dir)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .run()
+        .context(error::GitSnafu { repo })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    /// Makes a repository with one commit, and returns its hash.
+    fn repository(dir: &Path) -> String {
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::write(dir.join("a").join("c.txt"), "c").unwrap();
+        let git = |args: &[&str]| {
+            cmd("git", args.to_vec())
+                .dir(dir)
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_AUTHOR_DATE", "2024-01-02T03:04:05Z")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_DATE", "2024-01-02T03:04:05Z")
+                .read()
+                .unwrap()
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "test"]);
+        git(&["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn test_export_is_reproducible() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo_dir = temp_dir.path().join("repo");
+        fs::create_dir(&repo_dir).unwrap();
+        let rev = repository(&repo_dir);
+        let repo = repo_dir.to_str().unwrap();
+
+        let first = temp_dir.path().join("first.tar.gz");
+        let second = temp_dir.path().join("second.tar.gz");
+        export(repo, &rev, "repo-1", &first).unwrap();
+        // Newer files in the working tree don't matter.
+        fs::write(repo_dir.join("b.txt"), "changed").unwrap();
+        export(repo, &rev, "repo-1", &second).unwrap();
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(File::open(&first).unwrap()));
+        let mut files = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.header().entry_type().is_file() {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                files.push((entry.path().unwrap().display().to_string(), contents));
+            }
+        }
+        assert_eq!(
+            files,
+            [
+                ("repo-1/a/c.txt".to_string(), "c".to_string()),
+                ("repo-1/b.txt".to_string(), "b".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+/*!
+Checks external files against detached signatures, when their manifests name a signing key.
+
+The key may be a GPG public key, armored or not, or a minisign public key. The `gpg` or
+`minisign` program does the checking, and must be installed to fetch a signed file.
+*/
+use super::error::{self, Result};
+use duct::cmd;
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
+
+/// Minisign public keys start with this, and GPG keys don't.
+const MINISIGN_KEY_PREFIX: &[u8] = b"untrusted comment:";
+
+#[derive(Debug, PartialEq)]
+enum KeyFormat {
+    Gpg,
+    Minisign,
+}
+
+impl KeyFormat {
+    fn detect(key: &[u8]) -> Self {
+        if key.starts_with(MINISIGN_KEY_PREFIX) {
+            KeyFormat::Minisign
+        } else {
+            KeyFormat::Gpg
+        }
+    }
+}
+
+/// Checks that `signature` is a signature of `file` by `key`.
+pub(super) fn verify(file: &Path, signature: &Path, key: &Path) -> Result<()> {
+    let key_data = fs::read(key).context(error::SigningKeyReadSnafu { path: key })?;
+    match KeyFormat::detect(&key_data) {
+        KeyFormat::Minisign => {
+            cmd!("minisign", "-V", "-q", "-p", key, "-x", signature, "-m", file)
+                .run()
+                .context(error::SignatureVerifySnafu { path: file })?;
+        }
+        KeyFormat::Gpg => {
+            // A keyring of its own, so that no other key is trusted.
+            let home = tempfile::tempdir().context(error::TempDirSnafu)?;
+            cmd!("gpg", "--batch", "--quiet", "--import", key)
+                .env("GNUPGHOME", home.path())
+                .run()
+                .context(error::SigningKeyImportSnafu { path: key })?;
+            cmd!("gpg", "--batch", "--quiet", "--verify", signature, file)
+                .env("GNUPGHOME", home.path())
+                .run()
+                .context(error::SignatureVerifySnafu { path: file })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_format() {
+        assert_eq!(
+            KeyFormat::detect(b"untrusted comment: minisign public key 1234\nRWQ..."),
+            KeyFormat::Minisign
+        );
+        assert_eq!(
+            KeyFormat::detect(b"-----BEGIN PGP PUBLIC KEY BLOCK-----\n"),
+            KeyFormat::Gpg
+        );
+        assert_eq!(KeyFormat::detect(&[0x99, 0x01, 0x0d]), KeyFormat::Gpg);
+    }
+}
@@ -0,0 +1,58 @@
+/*!
+Packages written in Rust may have upstream tar archives that include only the
+source code of the project, but not the source code of the crates it depends on.
+Projects that commit a `Cargo.lock` pin those crates, so they can be vendored
+ahead of the package build, which has no network access.
+
+This Rust module extends the functionality of `packages.metadata.build-package.external-files`
+and provides the ability to retrieve the crates declared in the `Cargo.lock`
+of a tar archive containing a Cargo project.
+
+Crates are retrieved through the project's Cargo home directory, `.cargo`,
+which caches them across builds. The `cargo vendor` configuration is not
+included in the output archive; a spec should point Cargo at the vendored
+crates itself, for example with `--config 'source.crates-io.replace-with="vendored-sources"'`
+and `--config 'source.vendored-sources.directory="vendor"'`.
+
+ */
+
+use crate::bundle::BundleScript;
+
+// The following bash template script is run within a container by the bundle
+// module, in the same way as the Go modules script.
+//
+// It uses the top level directory found in the upstream archive as the Cargo
+// project if no explicit path was provided. It untars the archive, vendors the
+// crates from the project's `Cargo.lock` into {project-path}/vendor, and
+// archives that directory under the output path. The untar'd source code is
+// then removed, leaving the upstream archive intact.
+//
+// `--locked` makes vendoring fail rather than resolve new versions when the
+// lock file is missing or out of date, so that the bundle is reproducible.
+pub(crate) const SCRIPT: BundleScript = BundleScript {
+    name: "docker-cargo-script.sh",
+    template: r#"#!/bin/bash
+
+set -e
+
+export CARGO_HOME="__PROJECT_DIR__/.cargo"
+mkdir -p "${CARGO_HOME}"
+
+toplevel=$(tar tf __LOCAL_FILE_NAME__ | head -1)
+if [ -z __ROOT_DIR__ ] ; then
+    targetdir="${toplevel}"
+else
+    targetdir="__ROOT_DIR__"
+fi
+
+tar xf __LOCAL_FILE_NAME__
+
+pushd "${targetdir}"
+    cargo vendor --locked --versioned-dirs vendor >/dev/null
+popd
+
+tar czf __OUTPUT__ "${targetdir}"/vendor
+rm -rf "${targetdir}"
+touch -r __LOCAL_FILE_NAME__ __OUTPUT__
+"#,
+};
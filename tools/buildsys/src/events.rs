@@ -0,0 +1,270 @@
+/*!
+# Build events
+
+buildsys can record the start and finish of each package, kit and variant build as a stream of
+JSON objects, one per line, appended to the file named by `BUILDSYS_EVENTS_PATH`. Many buildsys
+processes may be running at once for a single `cargo make` invocation, so each event is written
+with a single append to keep lines from interleaving.
+
+A `start` event lists the local packages and kits that the build depends on, so that a consumer
+can rebuild the dependency graph without reading any manifests. A `finish` event carries the
+duration of the build, whether it succeeded, and the path of the log file that holds the output of
+`docker build`.
+```ignore
+{"phase":"start","kind":"package","name":"glibc","arch":"x86_64","timestamp-ms":1700000000000,"package-dependencies":["libgcc"]}
+{"phase":"finish","kind":"package","name":"glibc","arch":"x86_64","timestamp-ms":1700000093000,"duration-secs":93.0,"status":"success","log-path":"/project/build/state/x86_64/logs/packages/glibc.log"}
+```
+*/
+
+use crate::BuildType;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The environment variable that holds the path of the event log.
+pub const BUILD_EVENTS_ENV: &str = "BUILDSYS_EVENTS_PATH";
+
+#[derive(Debug, Snafu)]
+pub struct Error(error::Error);
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventPhase {
+    Start,
+    Finish,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildStatus {
+    Success,
+    Failure,
+}
+
+/// A single line of the event log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildEvent {
+    pub phase: EventPhase,
+    pub kind: BuildType,
+    pub name: String,
+    pub arch: String,
+    /// Milliseconds since the Unix epoch at which the event was recorded.
+    pub timestamp_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<BuildStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The local packages that the build depends on. Only recorded in `start` events.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub package_dependencies: Vec<String>,
+    /// The local kits that the build depends on. Only recorded in `start` events.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kit_dependencies: Vec<String>,
+}
+
+impl BuildEvent {
+    /// Create a `start` event for the given build, stamped with the current time.
+    pub fn start(kind: BuildType, name: impl Into<String>, arch: impl Into<String>) -> Self {
+        Self {
+            phase: EventPhase::Start,
+            kind,
+            name: name.into(),
+            arch: arch.into(),
+            timestamp_ms: now_ms(),
+            duration_secs: None,
+            status: None,
+            log_path: None,
+            error: None,
+            package_dependencies: Vec::new(),
+            kit_dependencies: Vec::new(),
+        }
+    }
+
+    /// Record the packages and kits that the build depends on.
+    pub fn dependencies(mut self, packages: &[String], kits: &[String]) -> Self {
+        self.package_dependencies = packages.to_vec();
+        self.kit_dependencies = kits.to_vec();
+        self
+    }
+
+    /// Create the `finish` event that matches this `start` event.
+    pub fn finish(&self, duration: Duration, error: Option<String>, log_path: &Path) -> Self {
+        Self {
+            phase: EventPhase::Finish,
+            timestamp_ms: now_ms(),
+            duration_secs: Some(duration.as_secs_f64()),
+            status: Some(if error.is_some() {
+                BuildStatus::Failure
+            } else {
+                BuildStatus::Success
+            }),
+            log_path: Some(log_path.to_path_buf()),
+            error,
+            package_dependencies: Vec::new(),
+            kit_dependencies: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// An append-only file of build events.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an event to the log, creating the file and its parent directory if needed.
+    pub fn append(&self, event: &BuildEvent) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(error::DirectoryCreateSnafu { path: parent })?;
+        }
+        let mut line = serde_json::to_vec(event).context(error::SerializeSnafu)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .context(error::WriteSnafu { path: &self.path })?;
+        Ok(())
+    }
+
+    /// Read every event in the log. A missing log holds no events.
+    pub fn read(&self) -> Result<Vec<BuildEvent>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => parse(&contents, &self.path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(source) => Err(error::Error::Read {
+                path: self.path.clone(),
+                source,
+            }
+            .into()),
+        }
+    }
+}
+
+/// Parse the contents of an event log. Blank lines are ignored.
+pub fn parse(contents: &str, path: &Path) -> Result<Vec<BuildEvent>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).context(error::ParseSnafu {
+                path,
+                line_number: i + 1,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, error::Error>>()
+        .map_err(Error)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(super) enum Error {
+        #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+        DirectoryCreate {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to parse line {} of build event log '{}': {}", line_number, path.display(), source))]
+        Parse {
+            path: PathBuf,
+            line_number: usize,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Failed to read build event log '{}': {}", path.display(), source))]
+        Read {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize build event: {}", source))]
+        Serialize { source: serde_json::Error },
+
+        #[snafu(display("Failed to write build event log '{}': {}", path.display(), source))]
+        Write {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_append_and_read() {
+        let dir = TempDir::new().unwrap();
+        let log = EventLog::new(dir.path().join("events/build.jsonl"));
+        assert!(log.read().unwrap().is_empty());
+
+        let start = BuildEvent::start(BuildType::Package, "glibc", "x86_64")
+            .dependencies(&["libgcc".to_string()], &[]);
+        let finish = start.finish(
+            Duration::from_secs(93),
+            None,
+            Path::new("/logs/packages/glibc.log"),
+        );
+        log.append(&start).unwrap();
+        log.append(&finish).unwrap();
+
+        let events = log.read().unwrap();
+        assert_eq!(events, vec![start, finish]);
+        assert_eq!(events[1].phase, EventPhase::Finish);
+        assert_eq!(events[1].status, Some(BuildStatus::Success));
+        assert_eq!(events[1].duration_secs, Some(93.0));
+        assert_eq!(events[0].package_dependencies, ["libgcc"]);
+        assert!(events[1].package_dependencies.is_empty());
+    }
+
+    #[test]
+    fn test_parse_format() {
+        let contents = r#"
+{"phase":"start","kind":"kit","name":"core-kit","arch":"aarch64","timestamp-ms":10}
+{"phase":"finish","kind":"kit","name":"core-kit","arch":"aarch64","timestamp-ms":20,"duration-secs":0.01,"status":"failure","error":"boom"}
+"#;
+        let events = parse(contents, Path::new("events.jsonl")).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, BuildType::Kit);
+        assert_eq!(events[1].status, Some(BuildStatus::Failure));
+        assert_eq!(events[1].error.as_deref(), Some("boom"));
+
+        let err = parse("{}\n", Path::new("events.jsonl")).unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+}
@@ -1,7 +1,11 @@
+pub mod events;
 pub mod manifest;
 
+use serde::{Deserialize, Serialize};
+
 /// The thing that buildsys is being asked to build.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum BuildType {
     Package,
     Kit,
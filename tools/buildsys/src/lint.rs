@@ -0,0 +1,312 @@
+/*!
+Checks a package against the conventions that reviewers would otherwise check by eye:
+
+* every Source and Patch in the spec is a file in the package directory, or an external file
+* every external file is used by the spec, either itself or through its bundle
+* the patches apply, in order, to the first Source when it is unpacked in the SDK container
+* the spec doesn't use deprecated macros
+
+Patches are applied with `-p1`, as `%autosetup -p1` does, inside the single top-level directory
+of the archive if it has one.
+
+*/
+pub(crate) mod error;
+use error::Result;
+
+use crate::external_file_names_for;
+use crate::spec::SpecInfo;
+use buildsys::manifest::ManifestInfo;
+use duct::cmd;
+use lazy_static::lazy_static;
+use regex::Regex;
+use snafu::ResultExt;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    /// Macros that shouldn't be used any more, and what to use instead.
+    static ref DEPRECATED_MACROS: Vec<(Regex, &'static str)> = [
+        (r"%patch[0-9]+", "%patch -P <number>"),
+        (r"%\{?makeinstall\b\}?", "%make_install"),
+        (r"%\{?PACKAGE_VERSION\b\}?", "%{version}"),
+        (r"%\{?PACKAGE_RELEASE\b\}?", "%{release}"),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), replacement))
+    .collect();
+}
+
+// Unpacks an archive and applies patches to it, stopping at the first that fails. It is run in
+// the SDK container with the package directory mounted at /package, and given the archive and
+// the patches as arguments.
+const APPLY_PATCHES_SCRIPT: &str = r#"
+set -eu
+source="${1:?}"
+shift
+work="$(mktemp -d)"
+tar -xof "/package/${source}" -C "${work}"
+cd "${work}"
+entries=( * )
+if [ "${#entries[@]}" -eq 1 ] && [ -d "${entries[0]}" ]; then
+  cd "${entries[0]}"
+fi
+for patch in "$@"; do
+  if ! patch -p1 --batch --forward --silent < "/package/${patch}"; then
+    echo "FAILED ${patch}"
+    exit 1
+  fi
+done
+"#;
+
+/// What is wrong with a package. Errors break conventions, while warnings point out what
+/// couldn't be checked.
+#[derive(Debug, Default)]
+pub(crate) struct Findings {
+    pub(crate) errors: Vec<String>,
+    pub(crate) warnings: Vec<String>,
+}
+
+/// Checks the package in `package_dir`. Patches are only applied if an SDK image is given, in
+/// which case the package's external files must already be fetched.
+pub(crate) fn lint_package(
+    package_dir: &Path,
+    info: &ManifestInfo,
+    sdk_image: Option<&str>,
+) -> Result<Findings> {
+    let spec_path = package_dir.join(format!("{}.spec", info.package_name()));
+    let spec_text =
+        fs::read_to_string(&spec_path).context(error::SpecReadSnafu { path: &spec_path })?;
+    let spec = SpecInfo::new(&spec_path).context(error::SpecParseSnafu)?;
+    let external_files = info.external_files().map(Vec::as_slice).unwrap_or_default();
+
+    let mut findings = Findings::default();
+    for value in &spec.unresolved {
+        findings.warnings.push(format!(
+            "'{}' uses macros that aren't defined in the spec, so it wasn't checked",
+            value
+        ));
+    }
+
+    let external_names = external_files
+        .iter()
+        .flat_map(external_file_names_for)
+        .collect::<HashSet<_>>();
+    for file in spec.sources.iter().chain(&spec.patches) {
+        if !package_dir.join(file).is_file() && !external_names.contains(file.as_os_str()) {
+            findings.errors.push(format!(
+                "'{}' is neither in the package directory nor an external file",
+                file.display()
+            ));
+        }
+    }
+
+    let used = spec
+        .sources
+        .iter()
+        .map(|p| p.as_os_str().to_owned())
+        .collect::<HashSet<OsString>>();
+    for f in external_files {
+        if !external_file_names_for(f).iter().any(|n| used.contains(n)) {
+            let message = format!("external file {} is not a Source in the spec", f.upstream());
+            // It may be one of the sources we couldn't resolve.
+            if spec.unresolved.is_empty() {
+                findings.errors.push(message);
+            } else {
+                findings.warnings.push(message);
+            }
+        }
+    }
+
+    findings.errors.extend(deprecated_macros(&spec_text));
+
+    if let Some(sdk_image) = sdk_image {
+        check_patches(package_dir, &spec, sdk_image, &mut findings)?;
+    }
+    Ok(findings)
+}
+
+/// Finds the deprecated macros used in a spec, skipping comments.
+fn deprecated_macros(spec_text: &str) -> Vec<String> {
+    let mut found = Vec::new();
+    for (number, line) in spec_text.lines().enumerate() {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        for (pattern, replacement) in DEPRECATED_MACROS.iter() {
+            if let Some(m) = pattern.find(line) {
+                found.push(format!(
+                    "line {} uses the deprecated '{}'; use '{}' instead",
+                    number + 1,
+                    m.as_str(),
+                    replacement
+                ));
+            }
+        }
+    }
+    found
+}
+
+/// Applies the spec's patches to its first Source in the SDK container.
+fn check_patches(
+    package_dir: &Path,
+    spec: &SpecInfo,
+    sdk_image: &str,
+    findings: &mut Findings,
+) -> Result<()> {
+    if spec.patches.is_empty() {
+        return Ok(());
+    }
+    let Some(source) = spec.sources.first() else {
+        findings
+            .warnings
+            .push("the spec has patches but no Source to apply them to".to_string());
+        return Ok(());
+    };
+    let name = source.to_string_lossy();
+    if !(name.contains(".tar") || name.ends_with(".tgz")) {
+        findings.warnings.push(format!(
+            "patches weren't applied, because '{}' isn't a tar archive",
+            name
+        ));
+        return Ok(());
+    }
+    if !package_dir.join(source).is_file() {
+        // This was reported above, unless it is an external file that wasn't fetched.
+        findings.warnings.push(format!(
+            "patches weren't applied, because '{}' wasn't found",
+            name
+        ));
+        return Ok(());
+    }
+
+    let mount = format!("{}:/package:ro", package_dir.display());
+    let mut args: Vec<OsString> = [
+        "run",
+        "--rm",
+        "--network=none",
+        "--security-opt=label=disable",
+        "-v",
+        &mount,
+        sdk_image,
+        "bash",
+        "-c",
+        APPLY_PATCHES_SCRIPT,
+        "apply-patches",
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect();
+    args.push(source.into());
+    args.extend(
+        spec.patches
+            .iter()
+            .map(PathBuf::as_os_str)
+            .map(OsString::from),
+    );
+
+    let output = cmd("docker", args)
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()
+        .context(error::CommandStartSnafu)?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    match output.lines().find_map(|line| line.strip_prefix("FAILED ")) {
+        Some(patch) => findings.errors.push(format!(
+            "'{}' does not apply to '{}':\n{}",
+            patch,
+            name,
+            output.trim_end()
+        )),
+        None => findings.errors.push(format!(
+            "failed to unpack '{}' to apply patches:\n{}",
+            name,
+            output.trim_end()
+        )),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lint_package() {
+        let temp_dir = TempDir::new().unwrap();
+        let package_dir = temp_dir.path();
+        let manifest_path = package_dir.join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            r#"[package]
+name = "hello"
+version = "0.1.0"
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/hello-1.0.tar.gz"
+sha512 = "abc"
+bundle-modules = ["go"]
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/unused-1.0.tar.gz"
+sha512 = "def"
+"#,
+        )
+        .unwrap();
+        // The spec names the bundle of the first external file, which hasn't been created.
+        fs::write(
+            package_dir.join("hello.spec"),
+            "\
+Name: hello
+Version: 1.0
+Source0: https://example.com/%{name}-%{version}.tar.gz
+Source1: bundled-%{name}-%{version}.tar.gz
+Source2: hello.conf
+Source3: missing.conf
+Patch0001: 0001-missing.patch
+",
+        )
+        .unwrap();
+        fs::write(package_dir.join("hello.conf"), "").unwrap();
+
+        let info = ManifestInfo::new(&manifest_path).unwrap();
+        let findings = lint_package(package_dir, &info, None).unwrap();
+        assert_eq!(
+            findings.errors,
+            [
+                "'missing.conf' is neither in the package directory nor an external file",
+                "'0001-missing.patch' is neither in the package directory nor an external file",
+                "external file https://example.com/unused-1.0.tar.gz is not a Source in the spec",
+            ]
+        );
+        assert!(findings.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_deprecated_macros() {
+        let spec = "\
+Version: 1.0
+%prep
+%setup -q
+%patch1 -p1
+# %patch2 -p1 is commented out
+%patch -P 3 -p1
+%install
+%makeinstall
+%make_install
+";
+        assert_eq!(
+            deprecated_macros(spec),
+            [
+                "line 4 uses the deprecated '%patch1'; use '%patch -P <number>' instead",
+                "line 8 uses the deprecated '%makeinstall'; use '%make_install' instead",
+            ]
+        );
+    }
+}
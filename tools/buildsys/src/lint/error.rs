@@ -0,0 +1,18 @@
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to read spec file '{}': {}", path.display(), source))]
+    SpecRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("{source}"))]
+    SpecParse { source: crate::spec::error::Error },
+
+    #[snafu(display("Failed to start docker to apply patches: {}", source))]
+    CommandStart { source: io::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -10,26 +10,38 @@ The implementation is closely tied to the top-level Dockerfile.
 */
 mod args;
 mod builder;
+mod bundle;
 mod cache;
+mod cargo_vendor;
 mod gomod;
+mod lint;
 mod project;
+mod remote_cache;
 mod spec;
 
 use crate::args::{
-    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, RepackVariantArgs,
+    BuildKitArgs, BuildPackageArgs, BuildVariantArgs, Buildsys, Command, DependencyGraphArgs,
+    GraphFormat, LintPackageArgs, PopulateLookasideCacheArgs, RepackVariantArgs,
+    UpdateExternalFilesArgs,
 };
 use crate::builder::DockerBuild;
-use buildsys::manifest::{BundleModule, Manifest, ManifestInfo, SupportedArch};
+use buildsys::manifest::graph::DependencyGraph;
+use buildsys::manifest::{
+    ExternalFile, ExternalKitMetadataView, Manifest, ManifestInfo, SupportedArch,
+};
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use cache::LookasideCache;
 use clap::Parser;
 use filetime::FileTime;
-use gomod::GoMod;
 use project::ProjectInfo;
-use snafu::{ensure, ResultExt};
+use remote_cache::{PackageHash, RemoteCache};
+use snafu::{ensure, OptionExt, ResultExt};
 use spec::SpecInfo;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::process;
+use toml_edit::{DocumentMut, Item, Value};
 
 mod error {
     use snafu::Snafu;
@@ -47,6 +59,34 @@ mod error {
         #[snafu(display("{source}"))]
         ExternalFileFetch { source: super::cache::error::Error },
 
+        #[snafu(display("{source}"))]
+        Bundle { source: super::bundle::error::Error },
+
+        #[snafu(display("Failed to update '{}': {}", path.display(), source))]
+        ManifestUpdate {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to parse '{}' for editing: {}", path.display(), source))]
+        ManifestEdit {
+            path: PathBuf,
+            source: toml_edit::TomlError,
+        },
+
+        #[snafu(display(
+            "Failed to find the hash of external file {} in '{}'",
+            index,
+            path.display()
+        ))]
+        ExternalFileHash { path: PathBuf, index: usize },
+
+        #[snafu(display("Failed to read packages directory '{}': {}", path.display(), source))]
+        PackagesDir {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Failed to get metadata for '{}': {}", path.display(), source))]
         FileMetadata {
             path: PathBuf,
@@ -54,7 +94,22 @@ mod error {
         },
 
         #[snafu(display("{source}"))]
-        GoMod { source: super::gomod::error::Error },
+        Lint { source: super::lint::error::Error },
+
+        #[snafu(display("Found {} problems in package specs", count))]
+        LintFailed { count: usize },
+
+        #[snafu(display("No package named '{}' was found in '{}'", package, path.display()))]
+        UnknownPackage { package: String, path: PathBuf },
+
+        #[snafu(display("Failed to change to package directory '{}': {}", path.display(), source))]
+        PackageDir {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to serialize dependency graph: {}", source))]
+        GraphSerialize { source: serde_json::Error },
 
         #[snafu(display("{source}"))]
         ProjectCrawl {
@@ -66,6 +121,11 @@ mod error {
             source: super::builder::error::Error,
         },
 
+        #[snafu(display("{source}"))]
+        RemoteCache {
+            source: super::remote_cache::error::Error,
+        },
+
         #[snafu(display("Unable to instantiate the builder: {source}"))]
         BuilderInstantiation {
             source: crate::builder::error::Error,
@@ -107,12 +167,18 @@ fn main() {
 }
 
 fn run(args: Buildsys) -> Result<()> {
-    args::rerun_for_envs(args.command.build_type());
+    if let Some(build_type) = args.command.build_type() {
+        args::rerun_for_envs(build_type);
+    }
     match args.command {
         Command::BuildPackage(args) => build_package(*args),
         Command::BuildKit(args) => build_kit(*args),
         Command::BuildVariant(args) => build_variant(*args),
         Command::RepackVariant(args) => repack_variant(*args),
+        Command::PopulateLookasideCache(args) => populate_lookaside_cache(*args),
+        Command::UpdateExternalFiles(args) => update_external_files(*args),
+        Command::LintPackage(args) => lint_package(*args),
+        Command::DependencyGraph(args) => dependency_graph(*args),
     }
 }
 
@@ -131,41 +197,53 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
     // Check for a deprecated key and error if it is detected.
     ensure_package_is_not_variant_sensitive(&manifest, &manifest_path)?;
 
+    // Everything that goes into the build is added to the hash that identifies it, alongside the
+    // files that Cargo watches.
+    let mut hash = PackageHash::new();
+    hash.file(manifest_file, &manifest_path)
+        .context(error::RemoteCacheSnafu)?;
+
+    // External files are identified by their hashes rather than their contents, which can be
+    // large. Any sources or patches that name them are skipped below.
+    let mut external_file_names = HashSet::new();
+
     if let Some(files) = manifest.info().external_files() {
         // We need the modification time for any external files or bundled modules to be no later
         // than the manifest's modification time, to avoid triggering spurious rebuilds.
-        let metadata =
-            std::fs::metadata(manifest_path.clone()).context(error::FileMetadataSnafu {
-                path: manifest_path,
-            })?;
-        let mtime = FileTime::from_last_modification_time(&metadata);
-
-        let lookaside_cache = LookasideCache::new(
-            &args.common.version_full,
-            args.lookaside_cache.clone(),
-            args.upstream_source_fallback == "true",
-        );
-
-        lookaside_cache
-            .fetch(files, mtime)
+        let mtime = manifest_mtime(&manifest_path)?;
+
+        // A dry run doesn't fetch anything, so that it is quick and leaves the tree as it was.
+        if !args.common.dry_run {
+            let lookaside_cache = LookasideCache::new(
+                &args.common.version_full,
+                args.lookaside_cache.clone(),
+                args.upstream_source_fallback == "true",
+            )
             .context(error::ExternalFileFetchSnafu)?;
 
+            lookaside_cache
+                .fetch(files, mtime)
+                .context(error::ExternalFileFetchSnafu)?;
+        }
+
         for f in files {
-            if f.bundle_modules.is_none() {
+            hash.value("external-file", format!("{} {}", f.upstream(), f.sha512));
+            external_file_names.extend(external_file_names_for(f));
+
+            if f.bundle_modules.is_none() || args.common.dry_run {
                 continue;
             }
 
             for b in f.bundle_modules.as_ref().unwrap() {
-                match b {
-                    BundleModule::Go => GoMod::vendor(
-                        &args.common.root_dir,
-                        &args.common.cargo_manifest_dir,
-                        f,
-                        &args.common.sdk_image,
-                        mtime,
-                    )
-                    .context(error::GoModSnafu)?,
-                }
+                bundle::vendor(
+                    b,
+                    &args.common.root_dir,
+                    &args.common.cargo_manifest_dir,
+                    f,
+                    &args.common.sdk_image,
+                    mtime,
+                )
+                .context(error::BundleSnafu)?;
             }
         }
     }
@@ -178,6 +256,9 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
         let info = ProjectInfo::crawl(&dirs).context(error::ProjectCrawlSnafu)?;
         for f in info.files {
             println!("cargo:rerun-if-changed={}", f.display());
+            let name = f.strip_prefix(&args.common.root_dir).unwrap_or(&f);
+            hash.file(&name.to_string_lossy(), &f)
+                .context(error::RemoteCacheSnafu)?;
         }
     }
 
@@ -186,25 +267,134 @@ fn build_package(args: BuildPackageArgs) -> Result<()> {
     let package = manifest.info().package_name();
     let spec = format!("{}.spec", package);
     println!("cargo:rerun-if-changed={}", spec);
+    hash.file(&spec, Path::new(&spec))
+        .context(error::RemoteCacheSnafu)?;
 
     let info = SpecInfo::new(PathBuf::from(&spec)).context(error::SpecParseSnafu)?;
 
-    for f in info.sources {
-        println!("cargo:rerun-if-changed={}", f.display());
-    }
-
-    for f in info.patches {
+    for f in info.sources.iter().chain(info.patches.iter()) {
         println!("cargo:rerun-if-changed={}", f.display());
+        if f.is_file() && !external_file_names.contains(f.as_os_str()) {
+            hash.file(&f.to_string_lossy(), f)
+                .context(error::RemoteCacheSnafu)?;
+        }
     }
 
     if args.common.cicd_hack {
+        println!(
+            "cargo:warning=BUILDSYS_CICD_HACK is deprecated, set BUILDSYS_REMOTE_CACHE to \
+            restore packages from a remote cache instead"
+        );
         return Ok(());
     }
 
-    DockerBuild::new_package(args, &manifest)
-        .context(error::BuilderInstantiationSnafu)?
-        .build()
-        .context(error::BuildAttemptSnafu)
+    let arch = args.common.arch.to_string();
+    let hash_path = remote_cache::hash_path(&args.common.state_dir, &arch, package);
+    let remote_cache = args
+        .remote_cache
+        .as_deref()
+        .filter(|repository| !repository.is_empty())
+        .map(|repository| RemoteCache::new(repository, args.remote_cache_push));
+    let package_hash = finish_package_hash(hash, &args, &manifest)?;
+    // Only a build in the remote cache needs to be told apart by the SDK image it was built in,
+    // which takes a call to docker to identify.
+    let cache_key = match (&remote_cache, &package_hash) {
+        (Some(_), Some(package_hash)) => {
+            match remote_cache::cache_key(package_hash, &args.common.sdk_image) {
+                Ok(cache_key) => Some(cache_key),
+                Err(e) => {
+                    println!(
+                        "Unable to identify the SDK image, so this build can't be cached: {e}"
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let dry_run = args.common.dry_run;
+    let mut build =
+        DockerBuild::new_package(args, &manifest).context(error::BuilderInstantiationSnafu)?;
+    if let (Some(remote_cache), Some(cache_key)) = (remote_cache, &cache_key) {
+        build = build.remote_cache(remote_cache, cache_key);
+    }
+    build.build().context(error::BuildAttemptSnafu)?;
+
+    // Record the hash for the packages that depend on this one, or make sure that they don't use
+    // the hash of an earlier build.
+    if !dry_run {
+        match package_hash {
+            Some(package_hash) => remote_cache::write_hash(&hash_path, &package_hash),
+            None => remote_cache::remove_hash(&hash_path),
+        }
+        .context(error::RemoteCacheSnafu)?;
+    }
+    Ok(())
+}
+
+/// Adds the inputs to a package build that don't come from its own directory, other than the SDK
+/// image. Returns `None` if the build can't be identified by a hash, in which case it can't use
+/// the remote cache either.
+fn finish_package_hash(
+    mut hash: PackageHash,
+    args: &BuildPackageArgs,
+    manifest: &Manifest,
+) -> Result<Option<String>> {
+    let arch = args.common.arch.to_string();
+    hash.value("arch", &arch);
+    // The build ID is left out, since Cargo doesn't rebuild packages when it changes either.
+
+    let kit_metadata = args.common.root_dir.join(EXTERNAL_KIT_METADATA);
+    if kit_metadata.is_file() {
+        hash.file(EXTERNAL_KIT_METADATA, &kit_metadata)
+            .context(error::RemoteCacheSnafu)?;
+    }
+
+    if !manifest
+        .kit_dependencies()
+        .context(error::ManifestParseSnafu)?
+        .is_empty()
+    {
+        println!("Packages that depend on local kits can't be cached");
+        return Ok(None);
+    }
+
+    for dependency in manifest
+        .package_dependencies()
+        .context(error::ManifestParseSnafu)?
+    {
+        let path = remote_cache::hash_path(&args.common.state_dir, &arch, &dependency);
+        match remote_cache::read_hash(&path).context(error::RemoteCacheSnafu)? {
+            Some(dependency_hash) => hash.value(&dependency, dependency_hash),
+            None => {
+                println!("No hash was recorded for '{dependency}', so this build can't be cached");
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(hash.finish()))
+}
+
+/// The modification time of a manifest, which its fetched external files are given.
+fn manifest_mtime(manifest_path: &Path) -> Result<FileTime> {
+    let metadata = std::fs::metadata(manifest_path).context(error::FileMetadataSnafu {
+        path: manifest_path,
+    })?;
+    Ok(FileTime::from_last_modification_time(&metadata))
+}
+
+/// The names that an external file may be referred to by in a spec: its own, and the one it's
+/// bundled into, if any.
+fn external_file_names_for(f: &ExternalFile) -> Vec<OsString> {
+    let name = f.path.clone().map(PathBuf::into_os_string);
+    let default_name = f.default_name().map(OsString::from);
+    let bundle_name = bundle::output_path(f).and_then(|path| path.file_name().map(OsString::from));
+    [name, default_name, bundle_name]
+        .into_iter()
+        .flatten()
+        .collect()
 }
 
 fn build_kit(args: BuildKitArgs) -> Result<()> {
@@ -278,6 +468,210 @@ fn repack_variant(args: RepackVariantArgs) -> Result<()> {
         .context(error::BuildAttemptSnafu)
 }
 
+fn populate_lookaside_cache(args: PopulateLookasideCacheArgs) -> Result<()> {
+    let manifests = package_manifests(&args.root_dir.join("packages"))?;
+    // Packages may share an external file.
+    let mut seen = HashSet::new();
+    let files = manifests
+        .iter()
+        .filter_map(|(package_dir, info)| {
+            let files = info.external_files()?;
+            Some(files.iter().map(move |f| (package_dir.as_path(), f)))
+        })
+        .flatten()
+        .filter(|(_, f)| seen.insert((f.upstream(), f.sha512.as_str())))
+        .collect::<Vec<_>>();
+
+    let lookaside_cache = LookasideCache::new(
+        env!("CARGO_PKG_VERSION"),
+        vec![args.lookaside_cache.clone()],
+        true,
+    )
+    .context(error::ExternalFileFetchSnafu)?;
+    let missing = lookaside_cache
+        .missing(&files)
+        .context(error::ExternalFileFetchSnafu)?;
+    for (_, f) in &missing {
+        println!("Missing {} ({})", f.upstream(), f.sha512);
+    }
+    println!(
+        "{} of {} external files are missing from '{}'",
+        missing.len(),
+        files.len(),
+        args.lookaside_cache
+    );
+    if args.dry_run {
+        return Ok(());
+    }
+
+    lookaside_cache
+        .populate(&missing)
+        .context(error::ExternalFileFetchSnafu)
+}
+
+fn update_external_files(args: UpdateExternalFilesArgs) -> Result<()> {
+    let manifest_path = args.package_dir.join("Cargo.toml");
+    let info = ManifestInfo::new(&manifest_path).context(error::ManifestParseSnafu)?;
+    let Some(files) = info.external_files() else {
+        println!("'{}' has no external files", manifest_path.display());
+        return Ok(());
+    };
+    let manifest = std::fs::read_to_string(&manifest_path).context(error::ManifestUpdateSnafu {
+        path: &manifest_path,
+    })?;
+    let mut manifest: DocumentMut = manifest.parse().context(error::ManifestEditSnafu {
+        path: &manifest_path,
+    })?;
+
+    let lookaside_cache = LookasideCache::new(env!("CARGO_PKG_VERSION"), Vec::new(), true)
+        .context(error::ExternalFileFetchSnafu)?;
+    for (index, f) in files.iter().enumerate() {
+        let hash = lookaside_cache
+            .signed_hash(f, &args.package_dir)
+            .context(error::ExternalFileFetchSnafu)?;
+        match hash {
+            None => println!("Skipping {}, which has no signature", f.upstream()),
+            Some(hash) if hash == f.sha512 => println!("{} is up to date", f.upstream()),
+            Some(hash) => {
+                println!("Updating the hash of {}", f.upstream());
+                set_external_file_hash(&mut manifest, index, &hash).context(
+                    error::ExternalFileHashSnafu {
+                        path: &manifest_path,
+                        index,
+                    },
+                )?;
+            }
+        }
+    }
+
+    std::fs::write(&manifest_path, manifest.to_string()).context(error::ManifestUpdateSnafu {
+        path: &manifest_path,
+    })
+}
+
+/// Sets the `sha512` of the external file at `index` in a package manifest, keeping the rest of
+/// the manifest as it was written. The files may be listed as an array of tables or as an inline
+/// array. Returns `None` if the file has no `sha512` to set.
+fn set_external_file_hash(manifest: &mut DocumentMut, index: usize, hash: &str) -> Option<()> {
+    let files = manifest
+        .get_mut("package")?
+        .get_mut("metadata")?
+        .get_mut("build-package")?
+        .get_mut("external-files")?;
+    let sha512 = match files {
+        Item::ArrayOfTables(files) => files.get_mut(index)?.get_mut("sha512")?.as_value_mut()?,
+        Item::Value(Value::Array(files)) => files
+            .get_mut(index)?
+            .as_inline_table_mut()?
+            .get_mut("sha512")?,
+        _ => return None,
+    };
+    let decor = sha512.decor().clone();
+    *sha512 = Value::from(hash);
+    *sha512.decor_mut() = decor;
+    Some(())
+}
+
+fn lint_package(args: LintPackageArgs) -> Result<()> {
+    // Packages are linted from their own directories, so relative paths would break.
+    let packages_dir = args.root_dir.join("packages");
+    let packages_dir = std::fs::canonicalize(&packages_dir).context(error::PackagesDirSnafu {
+        path: &packages_dir,
+    })?;
+    let mut packages = package_manifests(&packages_dir)?;
+    if let Some(package) = &args.package {
+        packages.retain(|(dir, _)| dir.file_name() == Some(OsStr::new(package)));
+        ensure!(
+            !packages.is_empty(),
+            error::UnknownPackageSnafu {
+                package,
+                path: packages_dir
+            }
+        );
+    }
+
+    // Patches are applied to the sources, so external files have to be fetched first.
+    let lookaside_cache = if args.skip_patches {
+        None
+    } else {
+        Some(
+            LookasideCache::new(
+                env!("CARGO_PKG_VERSION"),
+                args.lookaside_cache.clone(),
+                args.upstream_source_fallback == "true",
+            )
+            .context(error::ExternalFileFetchSnafu)?,
+        )
+    };
+    let sdk_image = (!args.skip_patches).then_some(args.sdk_image.as_str());
+
+    let mut count = 0;
+    for (package_dir, info) in &packages {
+        if let (Some(lookaside_cache), Some(files)) = (&lookaside_cache, info.external_files()) {
+            // External files are fetched to the current directory, as they are by build scripts.
+            std::env::set_current_dir(package_dir)
+                .context(error::PackageDirSnafu { path: package_dir })?;
+            lookaside_cache
+                .fetch(files, manifest_mtime(Path::new("Cargo.toml"))?)
+                .context(error::ExternalFileFetchSnafu)?;
+        }
+
+        let findings =
+            lint::lint_package(package_dir, info, sdk_image).context(error::LintSnafu)?;
+        let name = info.package_name();
+        for warning in &findings.warnings {
+            println!("warning: {}: {}", name, warning);
+        }
+        for error in &findings.errors {
+            println!("error: {}: {}", name, error);
+        }
+        count += findings.errors.len();
+    }
+
+    println!("Checked {} packages", packages.len());
+    ensure!(count == 0, error::LintFailedSnafu { count });
+    Ok(())
+}
+
+fn dependency_graph(args: DependencyGraphArgs) -> Result<()> {
+    let external_kits =
+        ExternalKitMetadataView::load(&args.root_dir).context(error::ManifestParseSnafu)?;
+    let graph = DependencyGraph::new(&args.cargo_metadata_path, &external_kits)
+        .context(error::ManifestParseSnafu)?;
+    match args.format {
+        GraphFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&graph).context(error::GraphSerializeSnafu)?
+        ),
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+    }
+    Ok(())
+}
+
+/// Reads the manifest of each package in `packages_dir`, in order of their directory names.
+/// Returns each package's directory with its manifest.
+fn package_manifests(packages_dir: &Path) -> Result<Vec<(PathBuf, ManifestInfo)>> {
+    let mut manifest_paths = Vec::new();
+    let entries =
+        std::fs::read_dir(packages_dir).context(error::PackagesDirSnafu { path: packages_dir })?;
+    for entry in entries {
+        let entry = entry.context(error::PackagesDirSnafu { path: packages_dir })?;
+        let manifest_path = entry.path().join("Cargo.toml");
+        if manifest_path.is_file() {
+            manifest_paths.push(manifest_path);
+        }
+    }
+    manifest_paths.sort();
+    manifest_paths
+        .iter()
+        .map(|path| {
+            let info = ManifestInfo::new(path).context(error::ManifestParseSnafu)?;
+            let package_dir = path.parent().unwrap_or(packages_dir).to_path_buf();
+            Ok((package_dir, info))
+        })
+        .collect()
+}
+
 /// Ensure that the current arch is supported by the current variant
 fn check_arch_support(manifest: &ManifestInfo, arch: SupportedArch) {
     if let Some(supported_arches) = manifest.supported_arches() {
@@ -318,3 +712,41 @@ fn ensure_package_is_not_variant_sensitive(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_external_file_hash() {
+        // Both files have the same placeholder hash, and comments are kept.
+        let manifest = r#"[package]
+name = "pkg"
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/a.tar.gz"
+sha512 = "0" # placeholder
+
+[[package.metadata.build-package.external-files]]
+url = "https://example.com/b.tar.gz"
+sha512 = "0"
+"#;
+        let mut doc: DocumentMut = manifest.parse().unwrap();
+        set_external_file_hash(&mut doc, 1, "bbb").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            manifest.replacen("sha512 = \"0\"\n", "sha512 = \"bbb\"\n", 1)
+        );
+        set_external_file_hash(&mut doc, 0, "aaa").unwrap();
+        assert!(doc.to_string().contains("sha512 = \"aaa\" # placeholder"));
+        assert!(set_external_file_hash(&mut doc, 2, "ccc").is_none());
+
+        let mut doc: DocumentMut = r#"[package.metadata.build-package]
+external-files = [{ url = "https://example.com/a.tar.gz", sha512 = "0" }]
+"#
+        .parse()
+        .unwrap();
+        set_external_file_hash(&mut doc, 0, "aaa").unwrap();
+        assert!(doc.to_string().contains("sha512 = \"aaa\" }"));
+    }
+}
@@ -33,6 +33,20 @@ url = "https://bar"
 sha512 = "123456"
 ```
 
+An external file may instead be a revision of a git repository, given by `git`
+and `rev`, for upstream projects that don't publish an archive of the commit
+a package needs. The revision is exported to a `.tar.gz` archive whose files
+are under a `<repository name>-<rev>` directory, and which is the same for the
+same commit no matter when or where it is made, so that `sha512` can check it.
+Lookaside caches hold the archive like any other file. If the path is not
+provided, it will be `<repository name>-<rev>.tar.gz`.
+```ignore
+[[package.metadata.build-package.external-files]]
+git = "https://github.com/foo/foo.git"
+rev = "0123456789abcdef0123456789abcdef01234567"
+sha512 = "abcdef"
+```
+
 The `bundle-*` keys on `external-files` are a group of optional modifiers
 and are used to untar an upstream external file archive, vendor any dependent
 code, and produce an additional archive with those dependencies.
@@ -41,7 +55,8 @@ Only `bundle-modules` is required when bundling an archive's dependences.
 `bundle-modules` is a list of module "paradigms" the external-file should
 be vendored through. For example, if a project contains a `go.mod` and `go.sum`
 file, adding "go" to the list will vendor the dependencies through go modules.
-Currently, only "go" is supported.
+If a project contains a `Cargo.toml` and `Cargo.lock`, adding "cargo" will vendor
+its crates with `cargo vendor`. Currently, "go" and "cargo" are supported.
 
 `bundle-root-path` is an optional argument that provides the filepath
 within the archive that contains the module. By default, the first top level
@@ -71,6 +86,21 @@ bundle-root-path = "path/to/module"
 bundle-output-path = "path/to/output.tar.gz"
 ```
 
+`signature-url` and `signing-key` are optional, and must be given together.
+`signature-url` is the URL of a detached signature of the external file, and
+`signing-key` is the path of the public key that made it, relative to the
+package directory. The key may be a GPG key or a minisign key. A file that is
+fetched is checked against the signature after its hash, which lets
+`buildsys update-external-files` compute the hash of a new upstream version
+without trusting whatever the URL serves.
+```ignore
+[[package.metadata.build-package.external-files]]
+url = "https://foo/foo-1.0.tar.gz"
+sha512 = "abcdef"
+signature-url = "https://foo/foo-1.0.tar.gz.sig"
+signing-key = "foo.asc"
+```
+
 `package-name` lets you override the package name in Cargo.toml; this is useful
 if you have a package with "." in its name, for example, which Cargo doesn't
 allow.  This means the directory name and spec file name can use your preferred
@@ -242,13 +272,14 @@ fips = true
 */
 
 mod error;
+pub mod graph;
 
 use crate::BuildType;
 use buildsys_config::EXTERNAL_KIT_METADATA;
 use guppy::graph::{DependencyDirection, PackageGraph, PackageLink, PackageMetadata};
 use guppy::{CargoMetadata, PackageId};
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
@@ -271,19 +302,7 @@ impl Manifest {
     /// Extract the settings we understand from `Cargo.toml` and construct a dependency graph.
     pub fn new(manifest: impl AsRef<Path>, cargo_metadata: impl AsRef<Path>) -> Result<Self> {
         let manifest_info = ManifestInfo::new(manifest)?;
-        let cargo_metadata = cargo_metadata.as_ref();
-        let cargo_metadata_json_contents =
-            fs::read_to_string(cargo_metadata).context(error::CargoMetadataReadSnafu {
-                path: &cargo_metadata,
-            })?;
-        let graph = CargoMetadata::parse_json(cargo_metadata_json_contents)
-            .context(error::CargoMetadataParseSnafu {
-                path: cargo_metadata,
-            })?
-            .build_graph()
-            .context(error::GraphBuildSnafu {
-                path: cargo_metadata,
-            })?;
+        let graph = package_graph(cargo_metadata.as_ref())?;
         Ok(Self {
             manifest_info,
             graph,
@@ -398,6 +417,16 @@ impl ManifestInfo {
             fs::read_to_string(path).context(error::ManifestFileReadSnafu { path })?;
         let manifest_info: ManifestInfo =
             toml::from_str(&manifest_data).context(error::ManifestFileLoadSnafu { path })?;
+        for f in manifest_info.external_files().into_iter().flatten() {
+            let sources = (f.url.is_some(), f.git.is_some(), f.rev.is_some());
+            ensure!(
+                matches!(sources, (true, false, false) | (false, true, true)),
+                error::ExternalFileSourceSnafu {
+                    path,
+                    sha512: &f.sha512
+                }
+            );
+        }
         Ok(manifest_info)
     }
 
@@ -547,6 +576,23 @@ impl ManifestInfo {
     }
 }
 
+/// Build the Cargo dependency graph from the output of `cargo metadata`.
+fn package_graph(cargo_metadata: &Path) -> Result<PackageGraph> {
+    let cargo_metadata_json_contents =
+        fs::read_to_string(cargo_metadata).context(error::CargoMetadataReadSnafu {
+            path: &cargo_metadata,
+        })?;
+    let graph = CargoMetadata::parse_json(cargo_metadata_json_contents)
+        .context(error::CargoMetadataParseSnafu {
+            path: cargo_metadata,
+        })?
+        .build_graph()
+        .context(error::GraphBuildSnafu {
+            path: cargo_metadata,
+        })?;
+    Ok(graph)
+}
+
 /// For the "top-level manifest", i.e. the thing that `buildsys` is building, only
 /// `build-dependencies` are valid. This is because we would need all artifacts before the top-level
 /// manifest's `build.rs` runs. Once we go deeper in the graph, then both `build-dependencies` and
@@ -840,6 +886,7 @@ impl fmt::Display for ImageFeature {
 #[serde(rename_all = "lowercase")]
 pub enum BundleModule {
     Go,
+    Cargo,
 }
 
 #[derive(Deserialize, Debug)]
@@ -847,11 +894,74 @@ pub enum BundleModule {
 pub struct ExternalFile {
     pub path: Option<PathBuf>,
     pub sha512: String,
-    pub url: String,
+    pub url: Option<String>,
+    pub git: Option<String>,
+    pub rev: Option<String>,
     pub force_upstream: Option<bool>,
     pub bundle_modules: Option<Vec<BundleModule>>,
     pub bundle_root_path: Option<PathBuf>,
     pub bundle_output_path: Option<PathBuf>,
+    pub signature_url: Option<String>,
+    pub signing_key: Option<PathBuf>,
+}
+
+impl ExternalFile {
+    /// Where the file comes from upstream. Loaded manifests have either a URL, or a git
+    /// repository and revision; otherwise the URL is empty.
+    pub fn upstream(&self) -> Upstream<'_> {
+        match (&self.url, &self.git, &self.rev) {
+            (None, Some(repo), Some(rev)) => Upstream::Git { repo, rev },
+            (url, _, _) => Upstream::Url(url.as_deref().unwrap_or_default()),
+        }
+    }
+
+    /// The name of the file when its path is not given: the last path component of its URL, or
+    /// the name of the archive exported from a git repository.
+    pub fn default_name(&self) -> Option<String> {
+        match self.upstream() {
+            Upstream::Url(url) => url::Url::parse(url)
+                .ok()?
+                .path_segments()?
+                .last()
+                .map(str::to_string),
+            upstream @ Upstream::Git { .. } => {
+                Some(format!("{}.tar.gz", upstream.archive_prefix()?))
+            }
+        }
+    }
+}
+
+/// The upstream source of an external file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Upstream<'a> {
+    Url(&'a str),
+    Git { repo: &'a str, rev: &'a str },
+}
+
+impl Upstream<'_> {
+    /// The directory that the files of a git revision are exported under, which is
+    /// `<repository name>-<rev>`.
+    pub fn archive_prefix(&self) -> Option<String> {
+        let Upstream::Git { repo, rev } = self else {
+            return None;
+        };
+        // Repositories may be named with URLs or as `host:path`.
+        let name = repo
+            .trim_end_matches('/')
+            .rsplit(['/', ':'])
+            .next()?
+            .trim_end_matches(".git");
+        (!name.is_empty()).then(|| format!("{}-{}", name, rev))
+    }
+}
+
+impl Display for Upstream<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Url(url) => write!(f, "{}", url),
+            Upstream::Git { repo, rev } => write!(f, "{}#{}", repo, rev),
+        }
+    }
 }
 
 // =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^= =^..^=
@@ -889,7 +999,7 @@ mod test {
             .unwrap_or_else(|_| panic!("unable to canonicalize {}", path.display()))
     }
 
-    fn cargo_metadata_path(temp_dir: &TempDir) -> PathBuf {
+    pub(super) fn cargo_metadata_path(temp_dir: &TempDir) -> PathBuf {
         let output_path = temp_dir.path().join("cargo_metadata.json");
         let output = MetadataCommand::new()
             .manifest_path(test_projects_dir().join("local-kit").join("Cargo.toml"))
@@ -975,4 +1085,56 @@ mod test {
         ];
         assert_eq!(kit_list, expected);
     }
+
+    #[test]
+    fn test_bundle_modules() {
+        let external_file: ExternalFile = toml::from_str(
+            r#"
+            url = "https://example.com/foo.tar.gz"
+            sha512 = "abcdef"
+            bundle-modules = ["go", "cargo"]
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            external_file.bundle_modules.as_deref(),
+            Some([BundleModule::Go, BundleModule::Cargo])
+        ));
+    }
+
+    #[test]
+    fn test_external_file_default_name() {
+        let external_file: ExternalFile = toml::from_str(
+            r#"
+            url = "https://example.com/releases/foo-1.0.tar.gz"
+            sha512 = "abcdef"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(external_file.default_name().unwrap(), "foo-1.0.tar.gz");
+
+        for repo in [
+            "https://github.com/example/foo.git",
+            "https://github.com/example/foo/",
+            "git@github.com:example/foo.git",
+        ] {
+            let external_file: ExternalFile = toml::from_str(&format!(
+                r#"
+                git = "{}"
+                rev = "0123abcd"
+                sha512 = "abcdef"
+                "#,
+                repo
+            ))
+            .unwrap();
+            assert_eq!(
+                external_file.upstream(),
+                Upstream::Git {
+                    repo,
+                    rev: "0123abcd"
+                }
+            );
+            assert_eq!(external_file.default_name().unwrap(), "foo-0123abcd.tar.gz");
+        }
+    }
 }
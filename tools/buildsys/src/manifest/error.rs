@@ -30,6 +30,13 @@ pub(super) enum Error {
         source: toml::de::Error,
     },
 
+    #[snafu(display(
+        "External file with sha512 '{}' in '{}' needs either a url, or a git repository and rev",
+        sha512,
+        path.display()
+    ))]
+    ExternalFileSource { path: PathBuf, sha512: String },
+
     #[snafu(display("Failed to read external kit metadata file '{}': {}", path.display(), source))]
     ExternalKitMetadataFileRead { path: PathBuf, source: io::Error },
 
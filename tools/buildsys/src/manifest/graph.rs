@@ -0,0 +1,249 @@
+/*!
+The graph of every package, kit and variant in a project, and the external kits they are built
+with.
+
+An edge goes from a manifest to each manifest that it names as a dependency, whether it is a
+build dependency or not, since a change to either means a rebuild. Packages and variants are built
+with every external kit, so each of them has an edge to each external kit.
+
+Each node is identified by its kind and its buildsys name, like `package/glibc` or
+`external-kit/bottlerocket/core-kit`, because packages, kits and variants may share names.
+*/
+
+use super::{is_manifest_type, package_graph, ExternalKitMetadataView, Result};
+use crate::BuildType;
+use guppy::graph::PackageMetadata;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Write};
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+pub struct DependencyGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Node {
+    pub id: String,
+    pub kind: NodeKind,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeKind {
+    Package,
+    Kit,
+    Variant,
+    ExternalKit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+}
+
+impl DependencyGraph {
+    /// Build the graph of the workspace described by the `cargo metadata` output at
+    /// `cargo_metadata`, with the given external kits.
+    pub fn new(
+        cargo_metadata: impl AsRef<Path>,
+        external_kits: &ExternalKitMetadataView,
+    ) -> Result<Self> {
+        let graph = package_graph(cargo_metadata.as_ref())?;
+
+        let mut nodes = BTreeMap::new();
+        for pkg_metadata in graph.packages().filter(|p| p.in_workspace()) {
+            nodes.insert(pkg_metadata.id().clone(), Node::for_manifest(&pkg_metadata));
+        }
+
+        let mut edges = BTreeSet::new();
+        for pkg_metadata in graph.packages().filter(|p| p.in_workspace()) {
+            let from = &nodes[pkg_metadata.id()];
+            for link in pkg_metadata.direct_links() {
+                if let Some(to) = nodes.get(link.to().id()) {
+                    edges.insert(Edge::new(from, to));
+                }
+            }
+        }
+
+        let mut nodes: Vec<Node> = nodes.into_values().collect();
+        let external_nodes: Vec<Node> = external_kits
+            .list()
+            .into_iter()
+            .map(|name| Node::new(NodeKind::ExternalKit, name))
+            .collect();
+        for from in nodes
+            .iter()
+            .filter(|n| matches!(n.kind, NodeKind::Package | NodeKind::Variant))
+        {
+            for to in &external_nodes {
+                edges.insert(Edge::new(from, to));
+            }
+        }
+        nodes.extend(external_nodes);
+        nodes.sort();
+
+        Ok(Self {
+            nodes,
+            edges: edges.into_iter().collect(),
+        })
+    }
+
+    /// Render the graph in the DOT language, for Graphviz.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph buildsys {\n    rankdir=LR;\n");
+        for node in &self.nodes {
+            let shape = match node.kind {
+                NodeKind::Package => "box",
+                NodeKind::Kit => "box3d",
+                NodeKind::Variant => "doubleoctagon",
+                NodeKind::ExternalKit => "box3d, style=dashed",
+            };
+            // Writing to a `String` can't fail.
+            let _ = writeln!(
+                dot,
+                "    {} [label={}, shape={}];",
+                quote(&node.id),
+                quote(&node.name),
+                shape
+            );
+        }
+        for edge in &self.edges {
+            let _ = writeln!(dot, "    {} -> {};", quote(&edge.from), quote(&edge.to));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl Node {
+    fn new(kind: NodeKind, name: String) -> Self {
+        Self {
+            id: format!("{}/{}", kind, name),
+            kind,
+            name,
+        }
+    }
+
+    /// The node for a workspace member, named the way buildsys names what it builds.
+    fn for_manifest(pkg_metadata: &PackageMetadata) -> Self {
+        let metadata_table = pkg_metadata.metadata_table();
+        let name_override = |table: &str, key: &str| {
+            metadata_table
+                .get(table)
+                .and_then(|v| v.get(key))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+        let name = pkg_metadata.name().to_string();
+        if is_manifest_type(pkg_metadata, BuildType::Kit) {
+            let name = name_override("build-kit", "kit-name").unwrap_or(name);
+            Self::new(NodeKind::Kit, name)
+        } else if is_manifest_type(pkg_metadata, BuildType::Variant) {
+            // Variants are named for their directory.
+            let name = pkg_metadata
+                .manifest_path()
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(str::to_string)
+                .unwrap_or(name);
+            Self::new(NodeKind::Variant, name)
+        } else {
+            let name = name_override("build-package", "package-name").unwrap_or(name);
+            Self::new(NodeKind::Package, name)
+        }
+    }
+}
+
+impl Edge {
+    fn new(from: &Node, to: &Node) -> Self {
+        Self {
+            from: from.id.clone(),
+            to: to.id.clone(),
+        }
+    }
+}
+
+impl Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeKind::Package => write!(f, "package"),
+            NodeKind::Kit => write!(f, "kit"),
+            NodeKind::Variant => write!(f, "variant"),
+            NodeKind::ExternalKit => write!(f, "external-kit"),
+        }
+    }
+}
+
+/// Quote a DOT identifier.
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::cargo_metadata_path;
+    use super::*;
+    use tempfile::TempDir;
+
+    fn external_kits() -> ExternalKitMetadataView {
+        serde_json::from_str(r#"{"kit": [{"name": "bottlerocket-sdk", "vendor": "example"}]}"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_dependency_graph() {
+        let temp_dir = TempDir::new().unwrap();
+        let graph = DependencyGraph::new(cargo_metadata_path(&temp_dir), &external_kits()).unwrap();
+        let has_node = |id: &str| graph.nodes.iter().any(|n| n.id == id);
+        let has_edge = |from: &str, to: &str| {
+            graph.edges.contains(&Edge {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+        };
+
+        // The package-name override is used, and variants are named for their directory.
+        assert!(has_node("package/pkg-a-1.27"));
+        assert!(has_node("kit/core-kit"));
+        assert!(has_node("variant/hello-ootb"));
+        assert!(has_node("external-kit/example/bottlerocket-sdk"));
+
+        assert!(has_edge("kit/core-kit", "package/pkg-a-1.27"));
+        assert!(has_edge("kit/extra-1-kit", "kit/core-kit"));
+        assert!(has_edge("package/pkg-b", "kit/core-kit"));
+        assert!(has_edge("variant/hello-ootb", "kit/extra-3-kit"));
+        assert!(has_edge(
+            "package/pkg-b",
+            "external-kit/example/bottlerocket-sdk"
+        ));
+        assert!(!has_edge(
+            "kit/core-kit",
+            "external-kit/example/bottlerocket-sdk"
+        ));
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let package = Node::new(NodeKind::Package, "glibc".to_string());
+        let variant = Node::new(NodeKind::Variant, "aws-\"dev\"".to_string());
+        let graph = DependencyGraph {
+            edges: vec![Edge::new(&variant, &package)],
+            nodes: vec![package, variant],
+        };
+        assert_eq!(
+            graph.to_dot(),
+            r#"digraph buildsys {
+    rankdir=LR;
+    "package/glibc" [label="glibc", shape=box];
+    "variant/aws-\"dev\"" [label="aws-\"dev\"", shape=doubleoctagon];
+    "variant/aws-\"dev\"" -> "package/glibc";
+}
+"#
+        );
+    }
+}
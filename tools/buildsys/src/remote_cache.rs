@@ -0,0 +1,377 @@
+/*!
+This module shares the RPMs built for a package through an OCI registry, so that a package only
+needs to be built once for a given set of inputs.
+
+Each package build is identified by a content hash of everything that goes into it: the manifest,
+the spec with its local sources and patches, the files in its source groups, the hashes of its
+external files, the architecture, and the hashes of the packages it depends on. The build ID is
+left out, so that builds of other commits can be shared; like a package that Cargo finds fresh, a
+restored package keeps the release of the commit it was first built from.
+
+The hash of every package that buildsys builds is recorded in the state directory, so that the
+packages that depend on it can include it in their own hash. It is recorded whether or not a remote
+cache is in use, since Cargo won't rerun the build of a fresh package when one is set up later, and
+its dependents could never be cached otherwise. The SDK image is identified with a call to docker,
+so it is only added to the hash when a build is looked up in the remote cache.
+
+The RPMs are stored as a single, uncompressed layer of an image tagged with the hash, in the
+repository given by `BUILDSYS_REMOTE_CACHE`, e.g. `localhost:5000/bottlerocket-rpms`. Builds only
+push to the cache when `BUILDSYS_REMOTE_CACHE_PUSH` is `true`.
+
+*/
+pub(crate) mod error;
+
+use duct::cmd;
+use error::Result;
+use oci_cli_wrapper::ImageTool;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Bump this whenever the inputs to the hash, or the layout of cached images, change.
+const HASH_VERSION: &str = "3";
+
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// Accumulates the inputs to a package build into a single hash.
+pub(crate) struct PackageHash {
+    hasher: Sha256,
+}
+
+impl PackageHash {
+    pub(crate) fn new() -> Self {
+        let mut hash = Self {
+            hasher: Sha256::new(),
+        };
+        hash.value("version", HASH_VERSION);
+        hash
+    }
+
+    /// Add a named value. Names and values are length-prefixed, so that no two different sets of
+    /// inputs can produce the same stream of bytes.
+    pub(crate) fn value(&mut self, name: &str, value: impl AsRef<[u8]>) {
+        for bytes in [name.as_bytes(), value.as_ref()] {
+            self.hasher.update((bytes.len() as u64).to_le_bytes());
+            self.hasher.update(bytes);
+        }
+    }
+
+    /// Add the contents of a file. The name should not depend on where the project is checked
+    /// out, so that builds on different hosts can share the cache.
+    pub(crate) fn file(&mut self, name: &str, path: &Path) -> Result<()> {
+        let contents = fs::read(path).context(error::FileReadSnafu { path })?;
+        self.value(name, contents);
+        Ok(())
+    }
+
+    /// Add the image ID of a local container image, which changes whenever its contents do.
+    pub(crate) fn image(&mut self, name: &str, image: &str) -> Result<()> {
+        let id = cmd!("docker", "image", "inspect", "--format", "{{.Id}}", image)
+            .stderr_null()
+            .read()
+            .context(error::ImageInspectSnafu { image })?;
+        self.value(name, id.trim());
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+/// The tag of a package build in the remote cache, which adds the SDK image that the package is
+/// built in to its hash.
+pub(crate) fn cache_key(package_hash: &str, sdk_image: &str) -> Result<String> {
+    let mut hash = PackageHash::new();
+    hash.value("package", package_hash);
+    hash.image("sdk", sdk_image)?;
+    Ok(hash.finish())
+}
+
+/// The file in which the hash of a package's last build is recorded.
+pub(crate) fn hash_path(state_dir: &Path, arch: &str, package: &str) -> PathBuf {
+    state_dir.join(arch).join("package-hashes").join(package)
+}
+
+/// Read the hash recorded for a package, if it has one.
+pub(crate) fn read_hash(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(hash) => Ok(Some(hash.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(error::Error::FileRead {
+            path: path.to_path_buf(),
+            source,
+        }),
+    }
+}
+
+pub(crate) fn write_hash(path: &Path, hash: &str) -> Result<()> {
+    let dir = path.parent().context(error::BadDirectorySnafu { path })?;
+    fs::create_dir_all(dir).context(error::DirectoryCreateSnafu { path: dir })?;
+    fs::write(path, hash).context(error::FileWriteSnafu { path })
+}
+
+/// Remove the hash recorded for a package, which is stale once it is built without one.
+pub(crate) fn remove_hash(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(error::Error::FileRemove {
+            path: path.to_path_buf(),
+            source: e,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// A repository in an OCI registry that holds built packages.
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteCache {
+    repository: String,
+    push: bool,
+}
+
+impl RemoteCache {
+    pub(crate) fn new(repository: impl Into<String>, push: bool) -> Self {
+        Self {
+            repository: repository.into().trim_end_matches('/').to_string(),
+            push,
+        }
+    }
+
+    fn uri(&self, hash: &str) -> String {
+        format!("{}:{}", self.repository, hash)
+    }
+
+    /// Unpack the cached build of a package into `output_dir`. Returns `false` if the cache has
+    /// no build for the hash, or couldn't be reached; either way, the package should be built.
+    pub(crate) fn restore(&self, hash: &str, output_dir: &Path) -> Result<bool> {
+        let uri = self.uri(hash);
+        let runtime = tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?;
+        let image_tool = ImageTool::from_builtin_krane();
+
+        if let Err(e) = runtime.block_on(image_tool.get_manifest(&uri)) {
+            println!("No cached build found at '{}': {}", uri, e);
+            return Ok(false);
+        }
+
+        let temp_dir = TempDir::new().context(error::TempDirSnafu)?;
+        let layout_dir = temp_dir.path().join("image");
+        fs::create_dir_all(&layout_dir)
+            .context(error::DirectoryCreateSnafu { path: &layout_dir })?;
+        runtime
+            .block_on(image_tool.pull_oci_image(&layout_dir, &uri))
+            .context(error::ImagePullSnafu { uri: &uri })?;
+
+        let index = read_json(&layout_dir.join("index.json"))?;
+        let manifest_digest = index["manifests"][0]["digest"]
+            .as_str()
+            .context(error::ImageLayoutSnafu { uri: &uri })?;
+        let manifest = read_json(&blob_path(&layout_dir, manifest_digest))?;
+        let layer_digest = manifest["layers"][0]["digest"]
+            .as_str()
+            .context(error::ImageLayoutSnafu { uri: &uri })?;
+        let layer_path = blob_path(&layout_dir, layer_digest);
+
+        let layer = File::open(&layer_path).context(error::FileReadSnafu { path: &layer_path })?;
+        tar::Archive::new(layer)
+            .unpack(output_dir)
+            .context(error::ArchiveUnpackSnafu { path: output_dir })?;
+
+        println!("Restored cached build from '{}'", uri);
+        Ok(true)
+    }
+
+    /// Push the files in `output_dir` to the cache, if pushing is enabled.
+    pub(crate) fn store(&self, hash: &str, output_dir: &Path, goarch: &str) -> Result<()> {
+        if !self.push {
+            return Ok(());
+        }
+        let uri = self.uri(hash);
+        let temp_dir = TempDir::new().context(error::TempDirSnafu)?;
+        let layout_dir = temp_dir.path().join("image");
+        let blobs_dir = layout_dir.join("blobs/sha256");
+        fs::create_dir_all(&blobs_dir).context(error::DirectoryCreateSnafu { path: &blobs_dir })?;
+
+        // Write the layer straight into the blobs directory, then move it to its digest.
+        let layer_path = blobs_dir.join("layer.tar");
+        write_layer(output_dir, &layer_path)?;
+        let (layer_digest, layer_size) = file_digest(&layer_path)?;
+        let layer_blob = blob_path(&layout_dir, &layer_digest);
+        fs::rename(&layer_path, &layer_blob)
+            .context(error::FileWriteSnafu { path: &layer_blob })?;
+
+        let config = json!({
+            "architecture": goarch,
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": [layer_digest] },
+        });
+        let config_descriptor = write_blob(&layout_dir, CONFIG_MEDIA_TYPE, &config)?;
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": config_descriptor,
+            "layers": [{
+                "mediaType": LAYER_MEDIA_TYPE,
+                "digest": layer_digest,
+                "size": layer_size,
+            }],
+        });
+        let manifest_descriptor = write_blob(&layout_dir, MANIFEST_MEDIA_TYPE, &manifest)?;
+        write_json(
+            &layout_dir.join("index.json"),
+            &json!({ "schemaVersion": 2, "manifests": [manifest_descriptor] }),
+        )?;
+        write_json(
+            &layout_dir.join("oci-layout"),
+            &json!({ "imageLayoutVersion": "1.0.0" }),
+        )?;
+
+        // The image tool expects the layout as a tar archive.
+        let archive_path = temp_dir.path().join("image.tar");
+        let archive = File::create(&archive_path).context(error::FileWriteSnafu {
+            path: &archive_path,
+        })?;
+        let mut builder = tar::Builder::new(archive);
+        builder
+            .append_dir_all(".", &layout_dir)
+            .and_then(|_| builder.finish())
+            .context(error::FileWriteSnafu {
+                path: &archive_path,
+            })?;
+
+        let runtime = tokio::runtime::Runtime::new().context(error::AsyncRuntimeSnafu)?;
+        runtime
+            .block_on(ImageTool::from_builtin_krane().push_oci_archive(&archive_path, &uri))
+            .context(error::ImagePushSnafu { uri: &uri })?;
+
+        println!("Pushed build to '{}'", uri);
+        Ok(())
+    }
+}
+
+/// Archive every file under `dir`, with paths relative to it. The archive's mtimes and owners are
+/// normalized, so that the same files always produce the same layer.
+fn write_layer(dir: &Path, path: &Path) -> Result<()> {
+    let file = File::create(path).context(error::FileWriteSnafu { path })?;
+    let mut builder = tar::Builder::new(file);
+    builder.mode(tar::HeaderMode::Deterministic);
+    let mut files = WalkDir::new(dir)
+        .into_iter()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(error::DirectoryWalkSnafu { path: dir })?;
+    files.sort_by(|a, b| a.path().cmp(b.path()));
+    for entry in files.iter().filter(|e| e.file_type().is_file()) {
+        let name = entry
+            .path()
+            .strip_prefix(dir)
+            .context(error::StripPathPrefixSnafu { path: entry.path() })?;
+        builder
+            .append_path_with_name(entry.path(), name)
+            .context(error::FileWriteSnafu { path })?;
+    }
+    builder.finish().context(error::FileWriteSnafu { path })
+}
+
+/// Returns the digest and size of a file, without reading it all into memory.
+fn file_digest(path: &Path) -> Result<(String, u64)> {
+    let mut file = File::open(path).context(error::FileReadSnafu { path })?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher).context(error::FileReadSnafu { path })?;
+    Ok((format!("sha256:{}", hex::encode(hasher.finalize())), size))
+}
+
+fn digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+fn blob_path(layout_dir: &Path, digest: &str) -> PathBuf {
+    layout_dir.join("blobs").join(digest.replace(':', "/"))
+}
+
+/// Write a JSON blob and return its descriptor.
+fn write_blob(
+    layout_dir: &Path,
+    media_type: &str,
+    value: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let bytes = serde_json::to_vec(value).context(error::JsonSerializeSnafu)?;
+    let digest = digest(&bytes);
+    let path = blob_path(layout_dir, &digest);
+    fs::write(&path, &bytes).context(error::FileWriteSnafu { path: &path })?;
+    Ok(json!({ "mediaType": media_type, "digest": digest, "size": bytes.len() }))
+}
+
+fn write_json(path: &Path, value: &serde_json::Value) -> Result<()> {
+    let bytes = serde_json::to_vec(value).context(error::JsonSerializeSnafu)?;
+    fs::write(path, bytes).context(error::FileWriteSnafu { path })
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value> {
+    let bytes = fs::read(path).context(error::FileReadSnafu { path })?;
+    serde_json::from_slice(&bytes).context(error::JsonParseSnafu { path })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_package_hash() {
+        let hash = |values: &[(&str, &str)]| {
+            let mut hash = PackageHash::new();
+            for (name, value) in values {
+                hash.value(name, value);
+            }
+            hash.finish()
+        };
+        let a = hash(&[("arch", "x86_64"), ("spec", "Name: a")]);
+        assert_eq!(a, hash(&[("arch", "x86_64"), ("spec", "Name: a")]));
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, hash(&[("arch", "aarch64"), ("spec", "Name: a")]));
+        // Moving bytes between a name and its value changes the hash.
+        assert_ne!(a, hash(&[("arch", "x86_64s"), ("pec", "Name: a")]));
+    }
+
+    #[test]
+    fn test_hash_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = hash_path(temp_dir.path(), "x86_64", "glibc");
+        assert_eq!(read_hash(&path).unwrap(), None);
+        write_hash(&path, "abc123").unwrap();
+        assert_eq!(read_hash(&path).unwrap().as_deref(), Some("abc123"));
+        remove_hash(&path).unwrap();
+        assert_eq!(read_hash(&path).unwrap(), None);
+        remove_hash(&path).unwrap();
+    }
+
+    #[test]
+    fn test_layer_is_deterministic() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(output_dir.join("sub")).unwrap();
+        fs::write(output_dir.join("a.rpm"), "a").unwrap();
+        fs::write(output_dir.join("sub/b.rpm"), "b").unwrap();
+
+        let first = temp_dir.path().join("first.tar");
+        let second = temp_dir.path().join("second.tar");
+        write_layer(&output_dir, &first).unwrap();
+        filetime::set_file_mtime(
+            output_dir.join("a.rpm"),
+            filetime::FileTime::from_unix_time(0, 0),
+        )
+        .unwrap();
+        write_layer(&output_dir, &second).unwrap();
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        let restored = temp_dir.path().join("restored");
+        tar::Archive::new(File::open(&first).unwrap())
+            .unpack(&restored)
+            .unwrap();
+        assert_eq!(fs::read_to_string(restored.join("sub/b.rpm")).unwrap(), "b");
+    }
+}
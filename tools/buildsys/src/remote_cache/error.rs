@@ -0,0 +1,72 @@
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(super)))]
+pub(crate) enum Error {
+    #[snafu(display("Failed to unpack cached build into '{}': {}", path.display(), source))]
+    ArchiveUnpack { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to create async runtime: {}", source))]
+    AsyncRuntime { source: io::Error },
+
+    #[snafu(display("Failed to get parent directory for '{}'", path.display()))]
+    BadDirectory { path: PathBuf },
+
+    #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
+    DirectoryCreate { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to walk directory '{}': {}", path.display(), source))]
+    DirectoryWalk {
+        path: PathBuf,
+        source: walkdir::Error,
+    },
+
+    #[snafu(display("Failed to read file '{}': {}", path.display(), source))]
+    FileRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to remove file '{}': {}", path.display(), source))]
+    FileRemove { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to write file '{}': {}", path.display(), source))]
+    FileWrite { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to inspect image '{}': {}", image, source))]
+    ImageInspect { image: String, source: io::Error },
+
+    #[snafu(display("Cached build at '{}' is not a single-layer image", uri))]
+    ImageLayout { uri: String },
+
+    #[snafu(display("Failed to pull cached build from '{}': {}", uri, source))]
+    ImagePull {
+        uri: String,
+        source: oci_cli_wrapper::error::Error,
+    },
+
+    #[snafu(display("Failed to push build to '{}': {}", uri, source))]
+    ImagePush {
+        uri: String,
+        source: oci_cli_wrapper::error::Error,
+    },
+
+    #[snafu(display("Failed to parse '{}': {}", path.display(), source))]
+    JsonParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to serialize image metadata: {}", source))]
+    JsonSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to find the path of '{}' in the build output: {}", path.display(), source))]
+    StripPathPrefix {
+        path: PathBuf,
+        source: std::path::StripPrefixError,
+    },
+
+    #[snafu(display("Failed to create temporary directory: {}", source))]
+    TempDir { source: io::Error },
+}
+
+pub(super) type Result<T> = std::result::Result<T, Error>;
@@ -1,16 +1,18 @@
 /*!
 This module provides a very simple parser for RPM spec files.
 
-It does not attempt to expand macros or perform any meaningful validation. Its
-only purpose is to extract Source and Patch declarations so they can be passed
-to Cargo as files to watch for changes.
+It does not attempt to perform any meaningful validation. Its only purpose is
+to extract Source and Patch declarations so they can be passed to Cargo as
+files to watch for changes. Macros in those declarations are expanded when they
+come from the spec itself; see the `macros` module for which ones.
 
 */
 pub(crate) mod error;
+mod macros;
 use error::Result;
 
+use macros::Macros;
 use snafu::ResultExt;
-use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
@@ -18,57 +20,149 @@ use std::path::{Path, PathBuf};
 pub(crate) struct SpecInfo {
     pub(crate) sources: Vec<PathBuf>,
     pub(crate) patches: Vec<PathBuf>,
+    /// The values of Source and Patch lines whose macros couldn't be expanded.
+    pub(crate) unresolved: Vec<String>,
 }
 
 impl SpecInfo {
     /// Returns a list of 'Source' and 'Patch' lines found in a spec file.
     pub(crate) fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let (sources, patches) = Self::parse(path)?;
+        let (sources, patches, unresolved) = Self::parse(path)?;
         let sources = Self::filter(&sources);
         let patches = Self::filter(&patches);
-        Ok(Self { sources, patches })
+        Ok(Self {
+            sources,
+            patches,
+            unresolved,
+        })
     }
 
     /// "Parse" a spec file, extracting values of potential interest.
-    fn parse<P: AsRef<Path>>(path: P) -> Result<(Vec<String>, Vec<String>)> {
+    fn parse<P: AsRef<Path>>(path: P) -> Result<(Vec<String>, Vec<String>, Vec<String>)> {
         let path = path.as_ref();
         let f = File::open(path).context(error::SpecFileReadSnafu { path })?;
         let f = BufReader::new(f);
 
         let mut sources = Vec::new();
         let mut patches = Vec::new();
+        let mut unresolved = Vec::new();
+        let mut macros = Macros::default();
 
         for line in f.lines() {
             let line = line.context(error::SpecFileReadSnafu { path })?;
+            let line = line.trim();
 
-            let mut tokens = line.split_whitespace().collect::<VecDeque<&str>>();
-            if let Some(t) = tokens.pop_front() {
-                if t.starts_with("Source") {
-                    if let Some(s) = tokens.pop_front() {
-                        sources.push(s.into());
+            if let Some((keyword, definition)) = line.split_once(char::is_whitespace) {
+                if keyword == "%define" || keyword == "%global" {
+                    let definition = definition.trim_start();
+                    let (name, body) = definition
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((definition, ""));
+                    // Parametric macros take options, which we can't expand.
+                    if name.contains('(') {
+                        continue;
                     }
-                } else if t.starts_with("Patch") {
-                    if let Some(p) = tokens.pop_front() {
-                        patches.push(p.into());
+                    if keyword == "%define" {
+                        macros.define(name, body.trim());
+                    } else {
+                        macros.define_expanded(name, body.trim());
+                    }
+                    continue;
+                }
+            }
+
+            let Some((tag, value)) = line.split_once(':') else {
+                continue;
+            };
+            if tag.contains(char::is_whitespace) {
+                continue;
+            }
+            let value = value.trim();
+            if ["Name", "Version", "Release"]
+                .iter()
+                .any(|t| tag.eq_ignore_ascii_case(t))
+            {
+                macros.define_expanded(&tag.to_ascii_lowercase(), value);
+            } else if tag.starts_with("Source") || tag.starts_with("Patch") {
+                let Some(expanded) = macros.expand(value) else {
+                    unresolved.push(value.to_string());
+                    continue;
+                };
+                if let Some(file) = expanded.split_whitespace().next() {
+                    if tag.starts_with("Source") {
+                        sources.push(file.into());
+                    } else {
+                        patches.push(file.into());
                     }
                 }
             }
         }
 
-        Ok((sources, patches))
+        Ok((sources, patches, unresolved))
     }
 
     /// Emitting a non-existent file for `rerun-if-changed` will cause Cargo
     /// to always repeat the build. Therefore we exclude "files" that do not
-    /// exist or that point outside the package directory. We also exclude
-    /// anything that appears to be an unexpanded macro.
+    /// exist or that point outside the package directory. Like RPM, we look
+    /// for a source given by URL under its file name.
     fn filter(input: &[String]) -> Vec<PathBuf> {
         input
             .iter()
-            .filter(|s| !s.contains("%{"))
+            .map(|s| {
+                if s.contains("://") {
+                    s.rsplit('/').next().unwrap_or_default()
+                } else {
+                    s.as_str()
+                }
+            })
             .map(PathBuf::from)
             .filter(|p| p.components().count() == 1)
             .filter(|p| p.file_name().is_some())
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_spec_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("foo.spec");
+        fs::write(
+            &path,
+            r#"%global goproject github.com/example
+%define tarball %{name}-%{version}.tar.gz
+
+Name: foo
+Version: 1.2
+Release: 1%{?dist}
+Source0: https://%{goproject}/foo/archive/v%{version}/%{tarball}
+Source1: bundled-%{tarball}
+Source2: foo.service
+Source3: %{_cross_os}bar.conf
+Patch0001: 0001-fix-%{version}.patch
+
+%description
+%{summary}.
+"#,
+        )
+        .unwrap();
+
+        let info = SpecInfo::new(&path).unwrap();
+        // `_cross_os` is defined by RPM rather than the spec, so it can't be expanded.
+        assert_eq!(
+            info.sources,
+            [
+                PathBuf::from("foo-1.2.tar.gz"),
+                PathBuf::from("bundled-foo-1.2.tar.gz"),
+                PathBuf::from("foo.service"),
+            ]
+        );
+        assert_eq!(info.patches, [PathBuf::from("0001-fix-1.2.patch")]);
+        assert_eq!(info.unresolved, ["%{_cross_os}bar.conf"]);
+    }
+}
@@ -0,0 +1,196 @@
+/*!
+A small evaluator for the RPM macros that name sources and patches.
+
+It knows the macros that a spec defines with `%define` and `%global`, and the `name`, `version`
+and `release` macros that RPM defines from the preamble. References may be written as `%name` or
+`%{name}`, and may be conditional: `%{?name}` expands to nothing if `name` is undefined, while
+`%{?name:text}` and `%{!?name:text}` expand to `text` if `name` is or isn't defined.
+
+Anything else, such as a macro that RPM itself defines, a parametric macro or a shell expansion,
+can't be expanded, and the text that refers to it is ignored.
+*/
+use std::collections::HashMap;
+
+/// How deeply macros may refer to other macros, which stops a macro that refers to itself.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Default)]
+pub(super) struct Macros {
+    definitions: HashMap<String, Definition>,
+}
+
+#[derive(Debug)]
+enum Definition {
+    /// The body of a `%define`, which is expanded each time the macro is used.
+    Lazy(String),
+    /// The value of a `%global` or a preamble tag, which was expanded when it was defined.
+    Expanded(String),
+}
+
+impl Macros {
+    /// Records a `%define`.
+    pub(super) fn define(&mut self, name: &str, body: &str) {
+        self.definitions
+            .insert(name.to_string(), Definition::Lazy(body.to_string()));
+    }
+
+    /// Records a `%global`, or a macro defined by a preamble tag. A value that can't be expanded
+    /// leaves the macro undefined, so that nothing is expanded from it.
+    pub(super) fn define_expanded(&mut self, name: &str, body: &str) {
+        match self.expand(body) {
+            Some(value) => self
+                .definitions
+                .insert(name.to_string(), Definition::Expanded(value)),
+            None => self.definitions.remove(name),
+        };
+    }
+
+    /// Expands the macros in `text`, or returns `None` if any of them can't be expanded.
+    pub(super) fn expand(&self, text: &str) -> Option<String> {
+        self.expand_at(text, 0)
+    }
+
+    fn expand_at(&self, text: &str, depth: usize) -> Option<String> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(i) = rest.find('%') {
+            expanded.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(after) = rest.strip_prefix('%') {
+                expanded.push('%');
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('{') {
+                let end = closing_brace(after)?;
+                expanded.push_str(&self.expand_braced(&after[..end], depth)?);
+                rest = &after[end + 1..];
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                // Shell and expression expansions, like `%(...)` and `%[...]`, have no name.
+                if end == 0 {
+                    return None;
+                }
+                expanded.push_str(&self.value(&rest[..end], depth)?);
+                rest = &rest[end..];
+            }
+        }
+        expanded.push_str(rest);
+        Some(expanded)
+    }
+
+    /// Expands the inside of `%{...}`.
+    fn expand_braced(&self, inner: &str, depth: usize) -> Option<String> {
+        let name_start = inner
+            .find(|c: char| c != '!' && c != '?')
+            .unwrap_or(inner.len());
+        let (flags, inner) = inner.split_at(name_start);
+        let (name, text) = match inner.split_once(':') {
+            Some((name, text)) => (name, Some(text)),
+            None => (inner, None),
+        };
+
+        if !flags.contains('?') {
+            // Built-in macros like `%{basename:...}` take arguments this way.
+            if !flags.is_empty() || text.is_some() {
+                return None;
+            }
+            return self.value(name, depth);
+        }
+
+        let negated = flags.contains('!');
+        let defined = self.definitions.contains_key(name);
+        match text {
+            Some(text) if defined != negated => self.expand_at(text, depth + 1),
+            None if defined && !negated => self.value(name, depth),
+            _ => Some(String::new()),
+        }
+    }
+
+    /// The expanded value of a macro, if it is defined.
+    fn value(&self, name: &str, depth: usize) -> Option<String> {
+        match self.definitions.get(name)? {
+            Definition::Lazy(body) => self.expand_at(body, depth + 1),
+            Definition::Expanded(value) => Some(value.clone()),
+        }
+    }
+}
+
+/// The index of the brace that closes a `%{`, given the text after it.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut open = 1;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => open += 1,
+            '}' => {
+                open -= 1;
+                if open == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn macros() -> Macros {
+        let mut macros = Macros::default();
+        macros.define_expanded("name", "foo");
+        macros.define_expanded("version", "1.2");
+        macros.define("tarball", "%{name}-%{version}.tar.gz");
+        macros
+    }
+
+    #[test]
+    fn test_expand() {
+        let macros = macros();
+        assert_eq!(macros.expand("%{tarball}").unwrap(), "foo-1.2.tar.gz");
+        assert_eq!(
+            macros.expand("%name-%version.tar.gz").unwrap(),
+            "foo-1.2.tar.gz"
+        );
+        assert_eq!(macros.expand("100%% %{name}").unwrap(), "100% foo");
+        assert_eq!(macros.expand("no macros").unwrap(), "no macros");
+    }
+
+    #[test]
+    fn test_expand_conditionals() {
+        let macros = macros();
+        assert_eq!(macros.expand("%{name}%{?dist}").unwrap(), "foo");
+        assert_eq!(macros.expand("%{?name}").unwrap(), "foo");
+        assert_eq!(macros.expand("%{!?name}").unwrap(), "");
+        assert_eq!(macros.expand("%{?name:-%{version}}").unwrap(), "-1.2");
+        assert_eq!(macros.expand("%{!?name:bar}").unwrap(), "");
+        assert_eq!(macros.expand("%{!?dist:%{name}}.el").unwrap(), "foo.el");
+        assert_eq!(macros.expand("%{?dist:.%{dist}}").unwrap(), "");
+    }
+
+    #[test]
+    fn test_expand_unknown() {
+        let macros = macros();
+        assert!(macros.expand("%{_cross_os}foo").is_none());
+        assert!(macros.expand("%{basename:a/b}").is_none());
+        assert!(macros.expand("%(echo foo)").is_none());
+        assert!(macros.expand("%{name").is_none());
+    }
+
+    #[test]
+    fn test_define_global() {
+        let mut macros = macros();
+        macros.define("lazy", "%{version}");
+        macros.define_expanded("eager", "%{version}");
+        macros.define_expanded("version", "2.0");
+        assert_eq!(macros.expand("%{lazy} %{eager}").unwrap(), "2.0 1.2");
+
+        macros.define("loop", "%{loop}");
+        assert!(macros.expand("%{loop}").is_none());
+    }
+}
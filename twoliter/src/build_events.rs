@@ -0,0 +1,238 @@
+//! Twoliter asks buildsys to record the start and finish of every package, kit and variant build
+//! in a JSON-lines event log, one log per Twoliter command. This module reads those logs back and
+//! pairs the events up into [`BuildStep`]s.
+//!
+//! Each log is written to `build/logs/events/<id>.jsonl`, and `build/logs/events/latest.jsonl`
+//! points at the log of the most recent command.
+use crate::common::fs;
+use anyhow::{Context, Result};
+use buildsys::events::{self, BuildEvent, BuildStatus, EventPhase, BUILD_EVENTS_ENV};
+use buildsys::BuildType;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const EVENTS_DIR: &str = "build/logs/events";
+const LATEST_LOG: &str = "latest.jsonl";
+
+/// The event log for a single Twoliter command.
+#[derive(Debug, Clone)]
+pub(crate) struct BuildEventLog {
+    path: PathBuf,
+}
+
+impl BuildEventLog {
+    /// Creates a new, uniquely named, event log in the project.
+    pub(crate) fn new(project_dir: &Path) -> Self {
+        Self {
+            path: project_dir
+                .join(EVENTS_DIR)
+                .join(format!("{}.jsonl", Uuid::new_v4())),
+        }
+    }
+
+    /// The log of the most recent command run in the project.
+    pub(crate) fn latest(project_dir: &Path) -> Self {
+        Self {
+            path: project_dir.join(EVENTS_DIR).join(LATEST_LOG),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The environment variable that tells buildsys to write to this log.
+    pub(crate) fn env(&self) -> (&'static str, String) {
+        (BUILD_EVENTS_ENV, self.path.display().to_string())
+    }
+
+    /// Reads the events in the log and pairs them up into steps, ordered by when they started.
+    /// A log that was never written to, because nothing needed to be built, has no steps.
+    pub(crate) async fn steps(&self) -> Result<Vec<BuildStep>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path).await?;
+        let events = events::parse(&contents, &self.path)
+            .context(format!("Unable to parse '{}'", self.path.display()))?;
+        Ok(BuildStep::from_events(events))
+    }
+
+    /// Points `latest.jsonl` at this log.
+    pub(crate) async fn link_latest(&self) -> Result<()> {
+        let dir = self.path.parent().context("Event log has no parent")?;
+        let file_name = self
+            .path
+            .file_name()
+            .context("Event log has no file name")?;
+        // Swap the link into place so that readers never see it missing.
+        let temp_link = dir.join(format!(".{}", Uuid::new_v4()));
+        fs::symlink(file_name, &temp_link).await?;
+        fs::rename(&temp_link, dir.join(LATEST_LOG)).await
+    }
+}
+
+/// One package, kit or variant build, as recorded by buildsys.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildStep {
+    pub(crate) kind: BuildType,
+    pub(crate) name: String,
+    pub(crate) arch: String,
+    /// Milliseconds since the Unix epoch at which the build started.
+    pub(crate) start_ms: u64,
+    /// `None` if the build never finished, for example because it was interrupted.
+    pub(crate) duration_secs: Option<f64>,
+    pub(crate) status: Option<BuildStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) log_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(skip)]
+    pub(crate) package_dependencies: Vec<String>,
+    #[serde(skip)]
+    pub(crate) kit_dependencies: Vec<String>,
+}
+
+impl BuildStep {
+    /// Pairs each `finish` event with the earliest unfinished `start` event for the same build.
+    /// A `finish` without a `start` still becomes a step, with its start worked out from its
+    /// duration.
+    pub(crate) fn from_events(events: impl IntoIterator<Item = BuildEvent>) -> Vec<Self> {
+        let mut steps: Vec<BuildStep> = Vec::new();
+        let mut unfinished: HashMap<(BuildType, String, String), Vec<usize>> = HashMap::new();
+
+        for event in events {
+            let key = (event.kind, event.name.clone(), event.arch.clone());
+            match event.phase {
+                EventPhase::Start => {
+                    unfinished.entry(key).or_default().push(steps.len());
+                    steps.push(BuildStep {
+                        kind: event.kind,
+                        name: event.name,
+                        arch: event.arch,
+                        start_ms: event.timestamp_ms,
+                        duration_secs: None,
+                        status: None,
+                        log_path: None,
+                        error: None,
+                        package_dependencies: event.package_dependencies,
+                        kit_dependencies: event.kit_dependencies,
+                    });
+                }
+                EventPhase::Finish => {
+                    let started = unfinished
+                        .get_mut(&key)
+                        .filter(|started| !started.is_empty())
+                        .map(|started| started.remove(0));
+                    let index = match started {
+                        Some(index) => index,
+                        None => {
+                            let duration_ms = (event.duration_secs.unwrap_or(0.0) * 1000.0) as u64;
+                            steps.push(BuildStep {
+                                kind: event.kind,
+                                name: event.name.clone(),
+                                arch: event.arch.clone(),
+                                start_ms: event.timestamp_ms.saturating_sub(duration_ms),
+                                duration_secs: None,
+                                status: None,
+                                log_path: None,
+                                error: None,
+                                package_dependencies: Vec::new(),
+                                kit_dependencies: Vec::new(),
+                            });
+                            steps.len() - 1
+                        }
+                    };
+                    let step = &mut steps[index];
+                    step.duration_secs = event.duration_secs;
+                    step.status = event.status;
+                    step.log_path = event.log_path;
+                    step.error = event.error;
+                }
+            }
+        }
+
+        steps.sort_by_key(|step| step.start_ms);
+        steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn finished(event: &BuildEvent, secs: u64, error: Option<&str>) -> BuildEvent {
+        let mut finish = event.finish(
+            Duration::from_secs(secs),
+            error.map(str::to_string),
+            Path::new("/build.log"),
+        );
+        finish.timestamp_ms = event.timestamp_ms + secs * 1000;
+        finish
+    }
+
+    #[test]
+    fn test_from_events() {
+        let mut glibc = BuildEvent::start(BuildType::Package, "glibc", "x86_64");
+        glibc.timestamp_ms = 1_000;
+        let mut kernel = BuildEvent::start(BuildType::Package, "kernel-6.1", "x86_64");
+        kernel.timestamp_ms = 500;
+        let mut variant = BuildEvent::start(BuildType::Variant, "aws-dev", "x86_64");
+        variant.timestamp_ms = 9_000;
+
+        let mut orphan = finished(
+            &BuildEvent::start(BuildType::Kit, "core-kit", "aarch64"),
+            3,
+            None,
+        );
+        orphan.timestamp_ms = 5_000;
+
+        let events = vec![
+            glibc.clone(),
+            kernel.clone(),
+            finished(&kernel, 2, Some("boom")),
+            orphan,
+            finished(&glibc, 4, None),
+            variant,
+        ];
+        let steps = BuildStep::from_events(events);
+
+        let summary = steps
+            .iter()
+            .map(|s| (s.name.as_str(), s.start_ms, s.duration_secs, s.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("kernel-6.1", 500, Some(2.0), Some(BuildStatus::Failure)),
+                ("glibc", 1_000, Some(4.0), Some(BuildStatus::Success)),
+                ("core-kit", 2_000, Some(3.0), Some(BuildStatus::Success)),
+                ("aws-dev", 9_000, None, None),
+            ]
+        );
+        assert_eq!(steps[0].error.as_deref(), Some("boom"));
+    }
+
+    #[tokio::test]
+    async fn test_log_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let log = BuildEventLog::new(temp_dir.path());
+        assert!(log.steps().await.unwrap().is_empty());
+
+        let start = BuildEvent::start(BuildType::Kit, "core-kit", "x86_64");
+        let writer = events::EventLog::new(log.path());
+        writer.append(&start).unwrap();
+        writer.append(&finished(&start, 7, None)).unwrap();
+        log.link_latest().await.unwrap();
+
+        let latest = BuildEventLog::latest(temp_dir.path());
+        let steps = latest.steps().await.unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].duration_secs, Some(7.0));
+        assert_eq!(log.env().0, "BUILDSYS_EVENTS_PATH");
+    }
+}
@@ -0,0 +1,308 @@
+//! Summarizes where the time in a build went, from the steps in its event log: the slowest builds,
+//! the critical path through the dependency graph, and how much of the possible parallelism the
+//! build achieved.
+//!
+//! The critical path is the chain of dependent builds with the greatest total duration. No matter
+//! how many builds run at once, the whole build can't finish faster than its critical path, so
+//! the total build time divided by the critical path is the most parallelism that was possible.
+//! Builds that Cargo skipped because they were up to date take no time and are left out.
+use crate::build_events::BuildStep;
+use buildsys::BuildType;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use tabled::{Table, Tabled};
+
+/// How many of the slowest builds to list.
+const SLOWEST_COUNT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BuildTiming {
+    /// The time from the start of the first build to the end of the last.
+    wall_secs: f64,
+    /// The sum of the durations of all builds.
+    build_secs: f64,
+    critical_path_secs: f64,
+    /// `build_secs / wall_secs`, the average number of builds running at once.
+    achieved_parallelism: f64,
+    /// `build_secs / critical_path_secs`, the most builds that could have run at once on average.
+    possible_parallelism: f64,
+    /// Ordered from the first build to the last.
+    critical_path: Vec<TimedStep>,
+    /// Ordered from the slowest build.
+    slowest: Vec<TimedStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Tabled)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct TimedStep {
+    #[tabled(rename = "KIND", display_with = "display_kind")]
+    kind: BuildType,
+    #[tabled(rename = "NAME")]
+    name: String,
+    #[tabled(rename = "ARCH")]
+    arch: String,
+    #[tabled(rename = "DURATION", display_with = "display_secs")]
+    duration_secs: f64,
+}
+
+/// Where a step is in the search for the longest path that ends with it.
+#[derive(Clone, Copy)]
+enum Visit {
+    New,
+    InProgress,
+    /// The duration of the longest path ending with this step, and the step before it on that path.
+    Done(f64, Option<usize>),
+}
+
+impl BuildTiming {
+    /// Returns `None` if no build finished.
+    pub(crate) fn new(steps: &[BuildStep]) -> Option<Self> {
+        let steps = steps
+            .iter()
+            .filter_map(|step| step.duration_secs.map(|secs| (step, secs)))
+            .collect::<Vec<_>>();
+        if steps.is_empty() {
+            return None;
+        }
+
+        let start_ms = steps.iter().map(|(step, _)| step.start_ms).min()?;
+        let end_ms = steps
+            .iter()
+            .map(|(step, secs)| step.start_ms + (secs * 1000.0) as u64)
+            .max()?;
+        let wall_secs = end_ms.saturating_sub(start_ms) as f64 / 1000.0;
+        let build_secs = steps.iter().map(|(_, secs)| secs).sum::<f64>();
+
+        let critical_path = critical_path(&steps)
+            .into_iter()
+            .map(|i| TimedStep::from(steps[i]))
+            .collect::<Vec<_>>();
+        let critical_path_secs = critical_path.iter().map(|s| s.duration_secs).sum::<f64>();
+
+        let mut slowest = steps
+            .iter()
+            .map(|s| TimedStep::from(*s))
+            .collect::<Vec<_>>();
+        slowest.sort_by(|a, b| b.duration_secs.total_cmp(&a.duration_secs));
+        slowest.truncate(SLOWEST_COUNT);
+
+        Some(Self {
+            wall_secs,
+            build_secs,
+            critical_path_secs,
+            achieved_parallelism: ratio(build_secs, wall_secs),
+            possible_parallelism: ratio(build_secs, critical_path_secs),
+            critical_path,
+            slowest,
+        })
+    }
+}
+
+/// Finds the chain of dependent steps with the greatest total duration, and returns the indices
+/// of its steps in the order they were built.
+fn critical_path(steps: &[(&BuildStep, f64)]) -> Vec<usize> {
+    let index = steps
+        .iter()
+        .enumerate()
+        .map(|(i, (step, _))| ((step.kind, step.name.as_str(), step.arch.as_str()), i))
+        .collect::<HashMap<_, _>>();
+    let dependencies = steps
+        .iter()
+        .map(|(step, _)| {
+            let packages = step
+                .package_dependencies
+                .iter()
+                .map(|name| (BuildType::Package, name));
+            let kits = step
+                .kit_dependencies
+                .iter()
+                .map(|name| (BuildType::Kit, name));
+            packages
+                .chain(kits)
+                .filter_map(|(kind, name)| {
+                    index
+                        .get(&(kind, name.as_str(), step.arch.as_str()))
+                        .copied()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut visits = vec![Visit::New; steps.len()];
+    for i in 0..steps.len() {
+        longest_path(i, steps, &dependencies, &mut visits);
+    }
+
+    let mut end = None;
+    let mut longest = f64::MIN;
+    for (i, visit) in visits.iter().enumerate() {
+        if let Visit::Done(secs, _) = visit {
+            if *secs > longest {
+                longest = *secs;
+                end = Some(i);
+            }
+        }
+    }
+
+    let mut path = Vec::new();
+    while let Some(i) = end {
+        path.push(i);
+        end = match visits[i] {
+            Visit::Done(_, previous) => previous,
+            _ => None,
+        };
+    }
+    path.reverse();
+    path
+}
+
+/// Fills in `visits[i]` with the longest path that ends with step `i`. The dependency graph comes
+/// from Cargo and can't have cycles, but a dependency that is still being visited is ignored all
+/// the same rather than recursing forever.
+fn longest_path(
+    i: usize,
+    steps: &[(&BuildStep, f64)],
+    dependencies: &[Vec<usize>],
+    visits: &mut [Visit],
+) -> f64 {
+    match visits[i] {
+        Visit::Done(secs, _) => return secs,
+        Visit::InProgress => return 0.0,
+        Visit::New => visits[i] = Visit::InProgress,
+    }
+
+    let mut previous = None;
+    let mut longest = 0.0;
+    for &dependency in &dependencies[i] {
+        let secs = longest_path(dependency, steps, dependencies, visits);
+        if previous.is_none() || secs > longest {
+            longest = secs;
+            previous = Some(dependency);
+        }
+    }
+
+    let secs = longest + steps[i].1;
+    visits[i] = Visit::Done(secs, previous);
+    secs
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > 0.0 {
+        numerator / denominator
+    } else {
+        1.0
+    }
+}
+
+impl From<(&BuildStep, f64)> for TimedStep {
+    fn from((step, duration_secs): (&BuildStep, f64)) -> Self {
+        Self {
+            kind: step.kind,
+            name: step.name.clone(),
+            arch: step.arch.clone(),
+            duration_secs,
+        }
+    }
+}
+
+fn display_kind(kind: &BuildType) -> String {
+    match kind {
+        BuildType::Package => "package",
+        BuildType::Kit => "kit",
+        BuildType::Variant => "variant",
+        BuildType::Repack => "repack",
+    }
+    .to_string()
+}
+
+fn display_secs(secs: &f64) -> String {
+    let secs = secs.round() as u64;
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, s) => format!("{h}h {m:02}m {s:02}s"),
+    }
+}
+
+impl Display for BuildTiming {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Build time: {}", display_secs(&self.wall_secs))?;
+        writeln!(
+            f,
+            "Time spent building: {} in total, {:.1}x parallelism achieved of {:.1}x possible",
+            display_secs(&self.build_secs),
+            self.achieved_parallelism,
+            self.possible_parallelism,
+        )?;
+        writeln!(f, "\nSlowest builds:")?;
+        writeln!(f, "{}", Table::new(&self.slowest))?;
+        writeln!(
+            f,
+            "\nCritical path ({}):",
+            display_secs(&self.critical_path_secs)
+        )?;
+        write!(f, "{}", Table::new(&self.critical_path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn step(
+        kind: BuildType,
+        name: &str,
+        start: u64,
+        secs: Option<f64>,
+        deps: &[&str],
+    ) -> BuildStep {
+        BuildStep {
+            kind,
+            name: name.to_string(),
+            arch: "x86_64".to_string(),
+            start_ms: start * 1000,
+            duration_secs: secs,
+            status: None,
+            log_path: None,
+            error: None,
+            package_dependencies: deps.iter().map(|d| d.to_string()).collect(),
+            kit_dependencies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_build_timing() {
+        let mut kit = step(BuildType::Kit, "core-kit", 70, Some(10.0), &["a", "b", "c"]);
+        kit.kit_dependencies = vec!["not-built".to_string()];
+        let steps = vec![
+            // `a` and `b` run side by side, then `c`, which needs both.
+            step(BuildType::Package, "a", 0, Some(20.0), &[]),
+            step(BuildType::Package, "b", 0, Some(40.0), &["cached"]),
+            step(BuildType::Package, "c", 40, Some(30.0), &["a", "b"]),
+            kit,
+            // Interrupted builds don't count.
+            step(BuildType::Package, "d", 0, None, &[]),
+        ];
+        let timing = BuildTiming::new(&steps).unwrap();
+
+        assert_eq!(timing.wall_secs, 80.0);
+        assert_eq!(timing.build_secs, 100.0);
+        assert_eq!(timing.critical_path_secs, 80.0);
+        assert_eq!(timing.achieved_parallelism, 1.25);
+        assert_eq!(timing.possible_parallelism, 1.25);
+        let names = |steps: &[TimedStep]| steps.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&timing.critical_path), ["b", "c", "core-kit"]);
+        assert_eq!(names(&timing.slowest), ["b", "c", "a", "core-kit"]);
+
+        assert!(BuildTiming::new(&steps[4..]).is_none());
+    }
+
+    #[test]
+    fn test_display_secs() {
+        assert_eq!(display_secs(&4.4), "4s");
+        assert_eq!(display_secs(&125.0), "2m 05s");
+        assert_eq!(display_secs(&3725.0), "1h 02m 05s");
+    }
+}
@@ -1,26 +1,50 @@
 use super::build_clean::BuildClean;
+use super::build_reproducible::BuildVerifyReproducible;
+use super::build_workspace::BuildWorkspace;
+use crate::build_events::BuildEventLog;
 use crate::cargo_make::CargoMake;
+use crate::cmd::report::{ArtifactKind, ErrorCode, Report, ReportedArtifact, WithErrorCode};
 use crate::common::fs;
-use crate::project::{self, Locked};
+use crate::makefile::project_makefile;
+use crate::project::{self, Locked, Project};
 use crate::tools::install_tools;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use clap::Parser;
-use std::path::PathBuf;
+use futures::{stream, StreamExt};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tabled::{Table, Tabled};
 use tempfile::TempDir;
+use tracing::{error, info, warn};
 
 #[derive(Debug, Parser)]
 pub(crate) enum BuildCommand {
     Clean(BuildClean),
     Kit(BuildKit),
     Variant(BuildVariant),
+    VerifyReproducible(BuildVerifyReproducible),
+    Workspace(BuildWorkspace),
 }
 
 impl BuildCommand {
-    pub(crate) async fn run(self) -> Result<()> {
+    pub(crate) async fn run(self, report: &mut Report) -> Result<()> {
         match self {
             BuildCommand::Clean(command) => command.run().await,
-            BuildCommand::Kit(command) => command.run().await,
-            BuildCommand::Variant(command) => command.run().await,
+            BuildCommand::Kit(command) => command.run(report).await,
+            BuildCommand::Variant(command) => command.run(report).await,
+            BuildCommand::VerifyReproducible(command) => command.run(report).await,
+            BuildCommand::Workspace(command) => command.run(report).await,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            BuildCommand::Clean(_) => "clean",
+            BuildCommand::Kit(_) => "kit",
+            BuildCommand::Variant(_) => "variant",
+            BuildCommand::VerifyReproducible(_) => "verify-reproducible",
+            BuildCommand::Workspace(_) => "workspace",
         }
     }
 }
@@ -40,9 +64,15 @@ pub(crate) struct BuildKit {
     pub(crate) kit: String,
 
     /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// Mirrors may be given as a comma-separated list, and are tried in order.
     /// Defaults to https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
     pub(crate) lookaside_cache: Option<String>,
 
+    /// The lookaside cache, as it was given before `--lookaside-cache`. Deprecated.
+    #[clap(hide = true, conflicts_with = "lookaside_cache")]
+    pub(crate) legacy_lookaside_cache: Option<String>,
+
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
@@ -50,20 +80,39 @@ pub(crate) struct BuildKit {
 }
 
 impl BuildKit {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
+        self.build(&project, report).await
+    }
+
+    /// Builds the kit in a project whose lock has already been verified.
+    pub(super) async fn build(&self, project: &Project<Locked>, report: &mut Report) -> Result<()> {
         let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        install_tools(&toolsdir)
+            .await
+            .error_code(ErrorCode::Tools)?;
+        let makefile_path = project_makefile(project, &toolsdir)
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
 
         let mut optional_envs = Vec::new();
 
-        if let Some(lookaside_cache) = &self.lookaside_cache {
-            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache))
+        if let Some(lookaside_cache) = self.lookaside_cache() {
+            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
         }
 
-        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+        let events = BuildEventLog::new(&project.project_dir());
+        optional_envs.push(events.env());
+
+        let start = Instant::now();
+        let result = CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
             .env("BUILDSYS_ARCH", &self.arch)
             .env("BUILDSYS_KIT", &self.kit)
@@ -78,44 +127,100 @@ impl BuildKit {
             .project_dir(project.project_dir())
             .exec("build-kit")
             .await
+            .error_code(ErrorCode::Build);
+        record_build_events(&events, report).await;
+
+        report.artifact(
+            ReportedArtifact::new(ArtifactKind::Kit, &self.kit)
+                .arch(&self.arch)
+                .path(project.project_dir().join("build/kits").join(&self.kit))
+                .duration(start.elapsed())
+                .result(&result),
+        );
+        result
+    }
+
+    /// The lookaside cache from `--lookaside-cache`, or from where it was given before that option
+    /// existed.
+    fn lookaside_cache(&self) -> Option<&String> {
+        if self.legacy_lookaside_cache.is_some() {
+            warn!("{LEGACY_LOOKASIDE_CACHE_WARNING}");
+        }
+        self.lookaside_cache
+            .as_ref()
+            .or(self.legacy_lookaside_cache.as_ref())
     }
 }
 
-/// Build a Bottlerocket variant image.
+/// Build one or more Bottlerocket variant images.
 #[derive(Debug, Parser)]
 pub(crate) struct BuildVariant {
     /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
     #[clap(long = "project-path")]
-    project_path: Option<PathBuf>,
+    pub(crate) project_path: Option<PathBuf>,
 
-    /// The architecture to build for.
-    #[clap(long = "arch", default_value = "x86_64")]
-    arch: String,
+    /// The architectures to build for. May be given more than once, or as a comma-separated list.
+    #[clap(long = "arch", default_value = "x86_64", value_delimiter = ',')]
+    pub(crate) arches: Vec<String>,
 
-    /// The variant to build.
-    variant: String,
+    /// The variants to build. Each may be a variant name or a glob pattern, such as `aws-k8s-*`,
+    /// matched against the directories in `variants`. A URL here is taken as the lookaside cache,
+    /// as it was before `--lookaside-cache`, but this is deprecated.
+    #[clap(required = true, num_args = 1..)]
+    pub(crate) variants: Vec<String>,
 
     /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// Mirrors may be given as a comma-separated list, and are tried in order.
     /// Defaults to https://cache.bottlerocket.aws
-    lookaside_cache: Option<String>,
+    #[clap(long = "lookaside-cache")]
+    pub(crate) lookaside_cache: Option<String>,
 
     /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
     /// from the upstream URL found in a package's `Cargo.toml`.
     #[clap(long = "upstream-source-fallback")]
-    upstream_source_fallback: bool,
+    pub(crate) upstream_source_fallback: bool,
 
     /// Path to the Infra.toml file
     #[clap(long)]
-    infra_toml: Option<PathBuf>,
+    pub(crate) infra_toml: Option<PathBuf>,
+
+    /// The number of variant builds to run at once. The setup that they share, such as fetching
+    /// sources and reading the Cargo metadata, runs once for each architecture before any of them.
+    #[clap(long = "jobs", default_value = "1")]
+    pub(crate) jobs: NonZeroUsize,
 }
 
 impl BuildVariant {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let variants = self.resolve_variants(&project.project_dir()).await?;
+
+        // Verify the lock and install tools once, and share them between every build.
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
+        self.build(&project, &variants, report).await
+    }
+
+    /// Builds the given variants, as resolved by [`Self::resolve_variants`], in a project whose
+    /// lock has already been verified.
+    pub(super) async fn build(
+        &self,
+        project: &Project<Locked>,
+        variants: &[String],
+        report: &mut Report,
+    ) -> Result<()> {
         let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        install_tools(&toolsdir)
+            .await
+            .error_code(ErrorCode::Tools)?;
+        let makefile_path = project_makefile(project, &toolsdir)
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
         // A temporary directory in the `build` directory
         let build_temp_dir = TempDir::new_in(project.project_dir())
             .context("Unable to create a tempdir for Twoliter's build")?;
@@ -124,7 +229,7 @@ impl BuildVariant {
 
         let mut optional_envs = Vec::new();
 
-        if let Some(lookaside_cache) = &self.lookaside_cache {
+        if let Some(lookaside_cache) = self.lookaside_cache() {
             optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
         }
 
@@ -135,10 +240,12 @@ impl BuildVariant {
             ))
         }
 
-        CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
+        // Every build shares one event log, since each event records its variant and arch.
+        let events = BuildEventLog::new(&project.project_dir());
+        optional_envs.push(events.env());
+
+        let cargo_make = CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
-            .env("BUILDSYS_ARCH", &self.arch)
-            .env("BUILDSYS_VARIANT", &self.variant)
             .env("BUILDSYS_VERSION_IMAGE", project.release_version())
             .env("GO_MODULES", project.find_go_modules().await?.join(" "))
             .env(
@@ -147,8 +254,339 @@ impl BuildVariant {
             )
             .envs(optional_envs.into_iter())
             .makefile(makefile_path)
-            .project_dir(project.project_dir())
-            .exec("build")
+            .project_dir(project.project_dir());
+
+        // Variant builds share their setup, which must not change under builds that are running.
+        for arch in &self.arches {
+            cargo_make
+                .clone()
+                .env("BUILDSYS_ARCH", arch)
+                .env("BUILDSYS_VARIANT", &variants[0])
+                .exec("build-setup")
+                .await
+                .error_code(ErrorCode::Build)?;
+        }
+
+        let matrix = self
+            .arches
+            .iter()
+            .flat_map(|arch| variants.iter().map(move |variant| (arch, variant)));
+
+        let results: Vec<VariantBuildResult> = stream::iter(matrix)
+            .map(|(arch, variant)| {
+                let cargo_make = cargo_make
+                    .clone()
+                    .env("BUILDSYS_ARCH", arch)
+                    .env("BUILDSYS_VARIANT", variant);
+                let output_dir = project
+                    .project_dir()
+                    .join("build/images")
+                    .join(format!("{arch}-{variant}"))
+                    .join("latest");
+                async move {
+                    info!("Building variant '{variant}' for '{arch}'");
+                    let start = Instant::now();
+                    let result = cargo_make
+                        .exec("build-variant-only")
+                        .await
+                        .error_code(ErrorCode::Build);
+                    if let Err(e) = &result {
+                        error!("Build of variant '{variant}' for '{arch}' failed: {e:?}");
+                    }
+                    VariantBuildResult {
+                        variant: variant.clone(),
+                        arch: arch.clone(),
+                        duration: start.elapsed(),
+                        output_dir,
+                        result,
+                    }
+                }
+            })
+            .buffer_unordered(self.jobs.get())
+            .collect()
+            .await;
+        record_build_events(&events, report).await;
+
+        // Only print a summary when there is more than one build to summarize.
+        if results.len() > 1 && report.human_readable() {
+            println!("{}", VariantBuildResult::summary(&results));
+        }
+        for result in &results {
+            report.artifact(result.into());
+        }
+
+        let total = results.len();
+        if total == 1 {
+            // A single build's error is returned as-is to preserve its context.
+            return results.into_iter().map(|r| r.result).collect();
+        }
+        let failed = results.iter().filter(|r| r.result.is_err()).count();
+        if failed > 0 {
+            return Err(anyhow!("{failed} of {total} variant builds failed"))
+                .error_code(ErrorCode::Build);
+        }
+        Ok(())
+    }
+
+    /// The lookaside cache from `--lookaside-cache`, or from among the variants, where it was given
+    /// before that option existed.
+    fn lookaside_cache(&self) -> Option<&String> {
+        if self.lookaside_cache.is_some() {
+            return self.lookaside_cache.as_ref();
+        }
+        let legacy_lookaside_cache = self.variants.iter().find(|v| is_url(v));
+        if legacy_lookaside_cache.is_some() {
+            warn!("{LEGACY_LOOKASIDE_CACHE_WARNING}");
+        }
+        legacy_lookaside_cache
+    }
+
+    /// Expands the requested variants into the names of variants in the project, in the order
+    /// given, without duplicates.
+    pub(super) async fn resolve_variants(&self, project_dir: &Path) -> Result<Vec<String>> {
+        let variants_dir = project_dir.join("variants");
+        let mut available = Vec::new();
+        let mut entries = tokio::fs::read_dir(&variants_dir)
+            .await
+            .context(format!("Unable to read '{}'", variants_dir.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
             .await
+            .context(format!("Unable to read '{}'", variants_dir.display()))?
+        {
+            if entry.path().join("Cargo.toml").is_file() {
+                available.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        available.sort();
+
+        let mut resolved: Vec<String> = Vec::new();
+        for pattern in self.variants.iter().filter(|v| !is_url(v)) {
+            let matches = available
+                .iter()
+                .filter(|variant| glob_match(pattern, variant))
+                .collect::<Vec<_>>();
+            ensure!(
+                !matches.is_empty(),
+                "No variants in '{}' match '{pattern}'",
+                variants_dir.display()
+            );
+            for variant in matches {
+                if !resolved.contains(variant) {
+                    resolved.push(variant.clone());
+                }
+            }
+        }
+        ensure!(!resolved.is_empty(), "No variants were given to build");
+        Ok(resolved)
+    }
+}
+
+const LEGACY_LOOKASIDE_CACHE_WARNING: &str = "Giving the lookaside cache as a positional argument \
+    is deprecated and will be removed in a future release. Use --lookaside-cache instead.";
+
+/// Whether a command line argument is a URL rather than a name or pattern.
+fn is_url(arg: &str) -> bool {
+    arg.contains("://")
+}
+
+/// Adds the builds that buildsys recorded to the report, and prints where the time went. The build
+/// itself has already finished by now, so a problem with the event log is only worth a warning.
+async fn record_build_events(events: &BuildEventLog, report: &mut Report) {
+    let steps = match events.steps().await {
+        Ok(steps) => steps,
+        Err(e) => {
+            warn!("Unable to read build events: {e:?}");
+            return;
+        }
+    };
+    if steps.is_empty() {
+        return;
+    }
+    if let Err(e) = events.link_latest().await {
+        warn!("Unable to link the latest build events: {e:?}");
+    }
+    info!(
+        "Recorded {} builds in '{}'",
+        steps.len(),
+        events.path().display()
+    );
+    report.build_steps(events.path(), steps);
+    if report.human_readable() {
+        if let Some(timing) = report.build_timing() {
+            println!("{timing}");
+        }
+    }
+}
+
+/// The outcome of building one variant for one architecture.
+struct VariantBuildResult {
+    variant: String,
+    arch: String,
+    duration: Duration,
+    output_dir: PathBuf,
+    result: Result<()>,
+}
+
+impl VariantBuildResult {
+    fn summary(results: &[Self]) -> Table {
+        #[derive(Tabled)]
+        struct Row {
+            #[tabled(rename = "VARIANT")]
+            variant: String,
+            #[tabled(rename = "ARCH")]
+            arch: String,
+            #[tabled(rename = "RESULT")]
+            result: &'static str,
+            #[tabled(rename = "DURATION")]
+            duration: String,
+            #[tabled(rename = "ARTIFACTS")]
+            artifacts: String,
+        }
+
+        let mut rows = results
+            .iter()
+            .map(|r| Row {
+                variant: r.variant.clone(),
+                arch: r.arch.clone(),
+                result: if r.result.is_ok() { "pass" } else { "FAIL" },
+                duration: format!("{}s", r.duration.as_secs()),
+                artifacts: if r.result.is_ok() {
+                    r.output_dir.display().to_string()
+                } else {
+                    "-".to_string()
+                },
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| (&a.variant, &a.arch).cmp(&(&b.variant, &b.arch)));
+        Table::new(rows)
+    }
+}
+
+impl From<&VariantBuildResult> for ReportedArtifact {
+    fn from(result: &VariantBuildResult) -> Self {
+        let artifact = ReportedArtifact::new(ArtifactKind::Variant, &result.variant)
+            .arch(&result.arch)
+            .duration(result.duration)
+            .result(&result.result);
+        if result.result.is_ok() {
+            artifact.path(&result.output_dir)
+        } else {
+            artifact
+        }
+    }
+}
+
+/// Matches `name` against a shell-style glob `pattern` supporting `*` and `?`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` seen in the pattern, and the position in the name it matched up
+    // to, so that we can backtrack and let it consume one more character.
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("aws-dev", "aws-dev"));
+        assert!(!glob_match("aws-dev", "aws-dev-2"));
+        assert!(glob_match("aws-*", "aws-k8s-1.30"));
+        assert!(glob_match("*-k8s-*", "vmware-k8s-1.30"));
+        assert!(!glob_match("*-k8s-*", "aws-ecs-2"));
+        assert!(glob_match("aws-k8s-1.?0", "aws-k8s-1.30"));
+        assert!(glob_match("*", "metal-dev"));
+        assert!(!glob_match("metal-?", "metal-dev"));
+    }
+
+    #[test]
+    fn test_build_variant_args() {
+        let args = BuildVariant::try_parse_from([
+            "variant",
+            "--arch",
+            "x86_64,aarch64",
+            "--jobs",
+            "2",
+            "aws-dev",
+            "metal-*",
+        ])
+        .unwrap();
+        assert_eq!(args.arches, ["x86_64", "aarch64"]);
+        assert_eq!(args.variants, ["aws-dev", "metal-*"]);
+        assert_eq!(args.jobs.get(), 2);
+
+        let args = BuildVariant::try_parse_from(["variant", "aws-dev"]).unwrap();
+        assert_eq!(args.arches, ["x86_64"]);
+        assert_eq!(args.jobs.get(), 1);
+    }
+
+    #[test]
+    fn test_legacy_lookaside_cache() {
+        let args =
+            BuildVariant::try_parse_from(["variant", "aws-dev", "https://cache.example.com"])
+                .unwrap();
+        assert_eq!(args.lookaside_cache().unwrap(), "https://cache.example.com");
+        let args = BuildVariant::try_parse_from([
+            "variant",
+            "--lookaside-cache",
+            "s3://bucket/prefix",
+            "aws-dev",
+        ])
+        .unwrap();
+        assert_eq!(args.lookaside_cache().unwrap(), "s3://bucket/prefix");
+
+        let args =
+            BuildKit::try_parse_from(["kit", "core-kit", "https://cache.example.com"]).unwrap();
+        assert_eq!(args.lookaside_cache().unwrap(), "https://cache.example.com");
+        assert!(BuildKit::try_parse_from([
+            "kit",
+            "--lookaside-cache",
+            "s3://bucket/prefix",
+            "core-kit",
+            "https://cache.example.com",
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_variants() {
+        let project_dir = crate::test::projects_dir().join("local-kit");
+        let args = BuildVariant::try_parse_from(["variant", "hello-*", "hello-ootb"]).unwrap();
+        let variants = args.resolve_variants(&project_dir).await.unwrap();
+        assert_eq!(variants, ["hello-ootb"]);
+
+        let args = BuildVariant::try_parse_from(["variant", "nope-*"]).unwrap();
+        assert!(args.resolve_variants(&project_dir).await.is_err());
+
+        let args =
+            BuildVariant::try_parse_from(["variant", "hello-ootb", "file:///cache"]).unwrap();
+        let variants = args.resolve_variants(&project_dir).await.unwrap();
+        assert_eq!(variants, ["hello-ootb"]);
     }
 }
@@ -1,24 +1,68 @@
 use crate::cargo_make::CargoMake;
-use crate::project::{self, Locked};
+use crate::common::{exec_log, fs};
+use crate::makefile::project_makefile;
+use crate::project::{self, cache::ARCHIVE_CACHE_DIR, Locked, Project, Unlocked};
 use crate::tools;
-use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use async_walkdir::WalkDir;
+use clap::{ArgGroup, Parser};
+use futures::StreamExt;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use toml::Table;
+use tracing::{debug, info};
 
+/// The suffix buildsys gives to the marker files it writes for each artifact it produces.
+const MARKER_EXTENSION: &str = ".buildsys_marker";
+
+/// Remove build outputs. With no options, everything in the build directory is removed. Options
+/// can be combined to remove only the outputs of specific packages, kits and variants.
 #[derive(Debug, Parser)]
+#[clap(group(
+    ArgGroup::new("granular")
+        .args(["packages", "kits", "variants", "external_kits"])
+        .multiple(true)
+))]
 pub(crate) struct BuildClean {
     /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
     #[clap(long = "project-path")]
     project_path: Option<PathBuf>,
+
+    /// Remove the outputs of this package. May be given more than once.
+    #[clap(long = "package")]
+    packages: Vec<String>,
+
+    /// Remove the outputs of this kit. May be given more than once.
+    #[clap(long = "kit")]
+    kits: Vec<String>,
+
+    /// Remove the images of this variant. May be given more than once.
+    #[clap(long = "variant")]
+    variants: Vec<String>,
+
+    /// Only remove outputs for this architecture. May be given more than once. Defaults to every
+    /// architecture that has been built.
+    #[clap(long = "arch", requires = "granular")]
+    arches: Vec<String>,
+
+    /// Remove the external kits fetched for the project. The OCI archive cache is kept; use
+    /// `twoliter cache prune` to clean it up.
+    #[clap(long = "external-kits")]
+    external_kits: bool,
 }
 
 impl BuildClean {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
+        if self.is_granular() {
+            return self.clean_selected(&project).await;
+        }
+
         let project = project.load_lock::<Locked>().await?;
         let toolsdir = project.project_dir().join("build/tools");
         tools::install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        let makefile_path = project_makefile(&project, &toolsdir).await?;
 
         CargoMake::new(&project.sdk_image().project_image_uri().to_string())?
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
@@ -29,4 +73,382 @@ impl BuildClean {
 
         Ok(())
     }
+
+    fn is_granular(&self) -> bool {
+        !self.packages.is_empty()
+            || !self.kits.is_empty()
+            || !self.variants.is_empty()
+            || self.external_kits
+    }
+
+    async fn clean_selected(&self, project: &Project<Unlocked>) -> Result<()> {
+        let root = project.project_dir();
+        let arches = if self.arches.is_empty() {
+            built_arches(&root).await?
+        } else {
+            self.arches.iter().cloned().collect()
+        };
+
+        let selected = [
+            (OutputKind::Package, &self.packages),
+            (OutputKind::Kit, &self.kits),
+            (OutputKind::Variant, &self.variants),
+        ];
+        for (kind, names) in selected {
+            for name in names {
+                let output = Output::find(&root, kind, name).await?;
+                for arch in &arches {
+                    output.clean(&root, arch).await?;
+                }
+            }
+        }
+
+        if self.external_kits {
+            clean_external_kits(&project.external_kits_dir()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The kinds of build output that buildsys tracks with marker files.
+#[derive(Debug, Clone, Copy)]
+enum OutputKind {
+    Package,
+    Kit,
+    Variant,
+}
+
+impl OutputKind {
+    /// The directory holding the Cargo manifests for this kind of output. It is also the directory
+    /// buildsys uses for this kind of output under `build/state/<arch>`.
+    fn dir_name(&self) -> &'static str {
+        match self {
+            OutputKind::Package => "packages",
+            OutputKind::Kit => "kits",
+            OutputKind::Variant => "variants",
+        }
+    }
+
+    /// The `package.metadata` table and key used to override the Cargo package name, if any.
+    fn name_override(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            OutputKind::Package => Some(("build-package", "package-name")),
+            OutputKind::Kit => Some(("build-kit", "kit-name")),
+            OutputKind::Variant => None,
+        }
+    }
+
+    /// The directories that buildsys copies outputs into, matching `DockerBuild` in buildsys.
+    fn output_dirs(&self, build_dir: &Path, name: &str, arch: &str) -> Vec<PathBuf> {
+        match self {
+            OutputKind::Package => vec![build_dir.join("rpms").join(name), build_dir.join("rpms")],
+            OutputKind::Kit => vec![build_dir.join("kits").join(name)],
+            OutputKind::Variant => vec![build_dir.join("images").join(format!("{arch}-{name}"))],
+        }
+    }
+}
+
+/// A package, kit or variant whose outputs should be removed.
+#[derive(Debug)]
+struct Output {
+    kind: OutputKind,
+    /// The name buildsys uses for the output
+    name: String,
+    /// The name of the Cargo package which builds the output
+    cargo_package: String,
+    /// The path to the Cargo manifest which builds the output
+    manifest_path: PathBuf,
+}
+
+impl Output {
+    /// Finds the Cargo package that builds the named output.
+    async fn find(root: &Path, kind: OutputKind, name: &str) -> Result<Self> {
+        let source_dir = root.join(kind.dir_name());
+        let mut entries = tokio::fs::read_dir(&source_dir)
+            .await
+            .context(format!("Unable to read '{}'", source_dir.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("Unable to read '{}'", source_dir.display()))?
+        {
+            let manifest_path = entry.path().join("Cargo.toml");
+            if !manifest_path.is_file() {
+                continue;
+            }
+            let manifest: Table = toml::from_str(&fs::read_to_string(&manifest_path).await?)
+                .context(format!("Unable to parse '{}'", manifest_path.display()))?;
+            let Some(cargo_package) = manifest
+                .get("package")
+                .and_then(|package| package.get("name"))
+                .and_then(|name| name.as_str())
+            else {
+                continue;
+            };
+            let buildsys_name = match kind.name_override() {
+                Some((table, key)) => manifest
+                    .get("package")
+                    .and_then(|package| package.get("metadata"))
+                    .and_then(|metadata| metadata.get(table))
+                    .and_then(|table| table.get(key))
+                    .and_then(|name| name.as_str())
+                    .unwrap_or(cargo_package)
+                    .to_string(),
+                // Variants are named after the directory that holds them.
+                None => entry.file_name().to_string_lossy().to_string(),
+            };
+
+            if buildsys_name == name || cargo_package == name {
+                return Ok(Self {
+                    kind,
+                    name: buildsys_name,
+                    cargo_package: cargo_package.to_string(),
+                    manifest_path,
+                });
+            }
+        }
+
+        bail!(
+            "Unable to find a {} named '{}' in '{}'",
+            kind.dir_name().trim_end_matches('s'),
+            name,
+            source_dir.display()
+        )
+    }
+
+    /// Removes the outputs for `arch`, then asks Cargo to forget the build so that the next
+    /// build runs buildsys again.
+    async fn clean(&self, root: &Path, arch: &str) -> Result<()> {
+        let build_dir = root.join("build");
+        let marker_dir = build_dir
+            .join("state")
+            .join(arch)
+            .join(self.kind.dir_name())
+            .join(&self.name);
+        let output_dirs = self.kind.output_dirs(&build_dir, &self.name, arch);
+
+        info!(
+            "Cleaning {} '{}' for {arch}",
+            self.kind.dir_name(),
+            self.name
+        );
+        clean_marked_outputs(&marker_dir, &output_dirs).await?;
+        if let OutputKind::Variant = self.kind {
+            remove_dangling_link(&output_dirs[0].join("latest")).await?;
+            remove_empty_dir(&output_dirs[0]).await?;
+        }
+
+        let target_dir = root.join("target").join(arch);
+        if target_dir.is_dir() {
+            exec_log(
+                Command::new("cargo")
+                    .arg("clean")
+                    .arg("--offline")
+                    .arg("--manifest-path")
+                    .arg(&self.manifest_path)
+                    .arg("--package")
+                    .arg(&self.cargo_package)
+                    .env("CARGO_TARGET_DIR", &target_dir)
+                    .env("CARGO_HOME", root.join(".cargo")),
+            )
+            .await
+            .context(format!(
+                "Unable to clean Cargo state for '{}'",
+                self.cargo_package
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the architectures that have build state in the project.
+async fn built_arches(root: &Path) -> Result<BTreeSet<String>> {
+    let state_dir = root.join("build/state");
+    let mut arches = BTreeSet::new();
+    if !state_dir.is_dir() {
+        return Ok(arches);
+    }
+    let mut entries = tokio::fs::read_dir(&state_dir)
+        .await
+        .context(format!("Unable to read '{}'", state_dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", state_dir.display()))?
+    {
+        if entry.path().is_dir() {
+            arches.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    Ok(arches)
+}
+
+/// Removes each output file that has a marker in `marker_dir` from each of `output_dirs`, along
+/// with the markers themselves. Directories left empty are removed too.
+///
+/// This mirrors the cleanup buildsys performs before rebuilding an artifact, so that outputs
+/// shared between artifacts are left alone.
+async fn clean_marked_outputs(marker_dir: &Path, output_dirs: &[PathBuf]) -> Result<()> {
+    if !marker_dir.is_dir() {
+        debug!("No build markers found in '{}'", marker_dir.display());
+        return Ok(());
+    }
+
+    let mut markers = Vec::new();
+    let mut entries = WalkDir::new(marker_dir);
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("Unable to walk '{}'", marker_dir.display()))?;
+        let path = entry.path();
+        if path.is_file() && path.to_string_lossy().ends_with(MARKER_EXTENSION) {
+            markers.push(path);
+        }
+    }
+
+    let mut dirs = BTreeSet::new();
+    for marker in markers {
+        let relative = marker
+            .strip_prefix(marker_dir)
+            .context(format!("Unexpected marker path '{}'", marker.display()))?;
+        let relative = relative.to_string_lossy();
+        let relative = relative.trim_end_matches(MARKER_EXTENSION);
+        for output_dir in output_dirs {
+            let output_file = output_dir.join(relative);
+            if output_file.exists() || output_file.is_symlink() {
+                debug!("Removing '{}'", output_file.display());
+                fs::remove_file(&output_file).await?;
+            }
+            collect_parents(&output_file, output_dir, &mut dirs);
+        }
+        fs::remove_file(&marker).await?;
+        collect_parents(&marker, marker_dir, &mut dirs);
+    }
+    dirs.insert(marker_dir.to_path_buf());
+
+    // Remove the deepest directories first, so that empty children don't keep their parents.
+    for dir in dirs.iter().rev() {
+        remove_empty_dir(dir).await?;
+    }
+
+    Ok(())
+}
+
+/// Adds the parent directories of `path`, up to and including `top`, to `dirs`.
+fn collect_parents(path: &Path, top: &Path, dirs: &mut BTreeSet<PathBuf>) {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if !dir.starts_with(top) || !dirs.insert(dir.to_path_buf()) || dir == top {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+async fn remove_empty_dir(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let is_empty = tokio::fs::read_dir(dir)
+        .await
+        .context(format!("Unable to read '{}'", dir.display()))?
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", dir.display()))?
+        .is_none();
+    if is_empty {
+        fs::remove_dir(dir).await?;
+    }
+    Ok(())
+}
+
+async fn remove_dangling_link(link: &Path) -> Result<()> {
+    if link.is_symlink() && !link.exists() {
+        fs::remove_file(link).await?;
+    }
+    Ok(())
+}
+
+/// Removes the extracted external kits and their metadata, keeping the OCI archive cache.
+async fn clean_external_kits(external_kits_dir: &Path) -> Result<()> {
+    if !external_kits_dir.is_dir() {
+        return Ok(());
+    }
+    info!(
+        "Cleaning external kits in '{}'",
+        external_kits_dir.display()
+    );
+    let mut entries = tokio::fs::read_dir(external_kits_dir)
+        .await
+        .context(format!("Unable to read '{}'", external_kits_dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", external_kits_dir.display()))?
+    {
+        let path = entry.path();
+        if entry.file_name() == ARCHIVE_CACHE_DIR {
+            continue;
+        }
+        if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    async fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(path, "").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clean_marked_outputs_keeps_unmarked_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let marker_dir = temp_dir.path().join("state/x86_64/packages/pkg-a");
+        let output_dir = temp_dir.path().join("rpms/pkg-a");
+        let shared_dir = temp_dir.path().join("rpms");
+
+        touch(&marker_dir.join(format!("pkg-a.rpm{MARKER_EXTENSION}"))).await;
+        touch(&marker_dir.join(format!("sub/pkg-a-debug.rpm{MARKER_EXTENSION}"))).await;
+        touch(&output_dir.join("pkg-a.rpm")).await;
+        touch(&output_dir.join("sub/pkg-a-debug.rpm")).await;
+        touch(&shared_dir.join("pkg-a.rpm")).await;
+        touch(&shared_dir.join("pkg-b.rpm")).await;
+
+        clean_marked_outputs(&marker_dir, &[output_dir.clone(), shared_dir.clone()])
+            .await
+            .unwrap();
+
+        assert!(!output_dir.exists());
+        assert!(!marker_dir.exists());
+        assert!(!shared_dir.join("pkg-a.rpm").exists());
+        assert!(shared_dir.join("pkg-b.rpm").exists());
+    }
+
+    #[tokio::test]
+    async fn test_find_output_by_override_name() {
+        let root = crate::test::projects_dir().join("local-kit");
+        let output = Output::find(&root, OutputKind::Package, "pkg-a-1.27")
+            .await
+            .unwrap();
+        assert_eq!(output.name, "pkg-a-1.27");
+        assert_eq!(output.cargo_package, "pkg-a-1_27");
+
+        let output = Output::find(&root, OutputKind::Variant, "hello-ootb")
+            .await
+            .unwrap();
+        assert_eq!(output.cargo_package, "hello-ootb");
+
+        assert!(Output::find(&root, OutputKind::Kit, "no-such-kit")
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,645 @@
+//! Checks that a package, kit or variant builds reproducibly, by building it twice and comparing
+//! the outputs.
+//!
+//! Each build gets its own RPM, kit, image, state and Cargo target directories under
+//! `build/reproducible`, so nothing is shared between the builds and nothing is reused from the
+//! project's usual build outputs. The builds write to different paths and run at different times,
+//! so any output that captures either is caught. The remote build cache is turned off for both.
+//!
+//! The outputs are compared file by file: the RPMs, the kit repositories and the variant images,
+//! whose partitions are written as separate files. When an RPM differs, the files inside it are
+//! compared too, using `rpm` from the SDK, so that the report names the file that made it differ.
+use crate::cargo_make::CargoMake;
+use crate::cmd::report::{ArtifactKind, ErrorCode, Report, ReportedArtifact, WithErrorCode};
+use crate::common::{exec, fs};
+use crate::makefile::project_makefile;
+use crate::project::{self, Locked};
+use crate::tools::install_tools;
+use anyhow::{anyhow, Context, Result};
+use async_walkdir::WalkDir;
+use clap::{ArgGroup, Parser};
+use futures::StreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+/// The directory, relative to the project, that holds the outputs of both builds.
+const REPRODUCIBLE_DIR: &str = "build/reproducible";
+
+/// The output directories that are compared, and what they hold.
+const OUTPUT_DIRS: [(&str, OutputLevel); 3] = [
+    ("rpms", OutputLevel::Rpm),
+    ("kits", OutputLevel::KitRepo),
+    ("images", OutputLevel::Image),
+];
+
+/// Build a package, kit or variant twice and check that both builds produce the same files.
+#[derive(Debug, Parser)]
+#[clap(group(
+    ArgGroup::new("target")
+        .args(["package", "kit", "variant"])
+        .required(true)
+))]
+pub(crate) struct BuildVerifyReproducible {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The architecture to build for.
+    #[clap(long = "arch", default_value = "x86_64")]
+    arch: String,
+
+    /// The package to build, along with the packages it depends on.
+    #[clap(long = "package")]
+    package: Option<String>,
+
+    /// The kit to build, along with the packages it depends on.
+    #[clap(long = "kit")]
+    kit: Option<String>,
+
+    /// The variant to build, along with the packages and kits it depends on.
+    #[clap(long = "variant")]
+    variant: Option<String>,
+
+    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// Mirrors may be given as a comma-separated list, and are tried in order.
+    /// Defaults to https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    lookaside_cache: Option<String>,
+
+    /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
+    /// from the upstream URL found in a package's `Cargo.toml`.
+    #[clap(long = "upstream-source-fallback")]
+    upstream_source_fallback: bool,
+
+    /// Keep the outputs of both builds even if they match. They are always kept when they differ.
+    #[clap(long = "keep")]
+    keep: bool,
+}
+
+impl BuildVerifyReproducible {
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
+        let toolsdir = project.project_dir().join("build/tools");
+        install_tools(&toolsdir)
+            .await
+            .error_code(ErrorCode::Tools)?;
+        let makefile_path = project_makefile(&project, &toolsdir)
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let sdk = project.sdk_image().project_image_uri().to_string();
+        let (kind, name, task, target_env) = self.target();
+
+        let mut optional_envs = Vec::new();
+        if let Some(lookaside_cache) = &self.lookaside_cache {
+            optional_envs.push(("BUILDSYS_LOOKASIDE_CACHE", lookaside_cache.to_string()))
+        }
+
+        let cargo_make = CargoMake::new(&sdk)?
+            .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
+            .env("BUILDSYS_ARCH", &self.arch)
+            .env(target_env, name)
+            .env("BUILDSYS_VERSION_IMAGE", project.release_version())
+            .env("GO_MODULES", project.find_go_modules().await?.join(" "))
+            .env(
+                "BUILDSYS_UPSTREAM_SOURCE_FALLBACK",
+                self.upstream_source_fallback.to_string(),
+            )
+            // A build restored from the cache would only prove that the cache works.
+            .env("BUILDSYS_REMOTE_CACHE", "")
+            .envs(optional_envs.into_iter())
+            .makefile(makefile_path)
+            .project_dir(project.project_dir());
+
+        let reproducible_dir = project.project_dir().join(REPRODUCIBLE_DIR);
+        let first = reproducible_dir.join("first");
+        let second = reproducible_dir.join("second");
+        for build_dir in [&first, &second] {
+            if build_dir.exists() {
+                fs::remove_dir_all(build_dir).await?;
+            }
+            fs::create_dir_all(build_dir).await?;
+
+            info!("Building {name} in '{}'", build_dir.display());
+            let start = Instant::now();
+            let result = cargo_make
+                .clone()
+                .env("BUILDSYS_PACKAGES_DIR", dir_env(build_dir, "rpms"))
+                .env("BUILDSYS_KITS_DIR", dir_env(build_dir, "kits"))
+                .env("BUILDSYS_IMAGES_DIR", dir_env(build_dir, "images"))
+                .env("BUILDSYS_STATE_DIR", dir_env(build_dir, "state"))
+                .env("BUILDSYS_TARGET_DIR", dir_env(build_dir, "target"))
+                .exec(task)
+                .await
+                .error_code(ErrorCode::Build);
+            report.artifact(
+                ReportedArtifact::new(kind, name)
+                    .arch(&self.arch)
+                    .path(build_dir)
+                    .duration(start.elapsed())
+                    .result(&result),
+            );
+            result?;
+        }
+
+        let reproducibility = Reproducibility::compare(&first, &second, &sdk).await?;
+        if report.human_readable() {
+            println!("{reproducibility}");
+        }
+        let differences = reproducibility.differences.len();
+        let compared = reproducibility.compared;
+        report.reproducibility(reproducibility);
+
+        if differences > 0 {
+            return Err(anyhow!(
+                "{differences} of {compared} files differ between the builds in '{}'",
+                reproducible_dir.display()
+            ))
+            .error_code(ErrorCode::NotReproducible);
+        }
+        if !self.keep {
+            fs::remove_dir_all(&reproducible_dir).await?;
+        }
+        Ok(())
+    }
+
+    /// The kind and name of what to build, the `cargo make` task that builds it, and the
+    /// environment variable that names it for that task.
+    fn target(&self) -> (ArtifactKind, &str, &'static str, &'static str) {
+        match (&self.package, &self.kit, &self.variant) {
+            (Some(package), _, _) => (ArtifactKind::Package, package, "build-package", "PACKAGE"),
+            (_, Some(kit), _) => (ArtifactKind::Kit, kit, "build-kit", "BUILDSYS_KIT"),
+            (_, _, Some(variant)) => (
+                ArtifactKind::Variant,
+                variant,
+                "build-variant",
+                "BUILDSYS_VARIANT",
+            ),
+            // Clap requires one of the three.
+            (None, None, None) => unreachable!("No package, kit or variant to build"),
+        }
+    }
+}
+
+fn dir_env(build_dir: &Path, name: &str) -> String {
+    build_dir.join(name).display().to_string()
+}
+
+/// The outcome of comparing the outputs of two builds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Reproducibility {
+    first: PathBuf,
+    second: PathBuf,
+    /// The number of files that either build produced.
+    compared: usize,
+    differences: Vec<FileDifference>,
+}
+
+/// A file that was not the same in both builds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FileDifference {
+    level: OutputLevel,
+    /// Relative to the directory of each build, e.g. `rpms/bottlerocket-glibc-....rpm`
+    path: PathBuf,
+    difference: Difference,
+    /// For RPMs, the files inside the RPM that differ. Empty if only the RPM headers differ, or if
+    /// the RPMs could not be read.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rpm_files: Vec<RpmFileDifference>,
+}
+
+/// A file inside an RPM that was not the same in both builds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RpmFileDifference {
+    path: String,
+    difference: Difference,
+    /// The attributes that differ, such as `digest` or `mtime`, when the file is in both RPMs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attributes: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OutputLevel {
+    Rpm,
+    KitRepo,
+    Image,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Difference {
+    OnlyInFirst,
+    OnlyInSecond,
+    Changed,
+}
+
+impl Reproducibility {
+    async fn compare(first: &Path, second: &Path, sdk: &str) -> Result<Self> {
+        let mut compared = 0;
+        let mut differences = Vec::new();
+        for (dir, level) in OUTPUT_DIRS {
+            let first_files = digest_tree(first, &first.join(dir)).await?;
+            let second_files = digest_tree(second, &second.join(dir)).await?;
+            compared += first_files
+                .keys()
+                .chain(second_files.keys())
+                .collect::<BTreeSet<_>>()
+                .len();
+
+            for (path, difference) in diff_trees(&first_files, &second_files) {
+                let mut rpm_files = Vec::new();
+                if difference == Difference::Changed && is_rpm(&path) {
+                    match diff_rpm(sdk, first, second, &path).await {
+                        Ok(files) => rpm_files = files,
+                        Err(e) => {
+                            warn!("Unable to compare the files in '{}': {e:?}", path.display())
+                        }
+                    }
+                }
+                differences.push(FileDifference {
+                    level,
+                    path,
+                    difference,
+                    rpm_files,
+                });
+            }
+        }
+
+        Ok(Self {
+            first: first.to_path_buf(),
+            second: second.to_path_buf(),
+            compared,
+            differences,
+        })
+    }
+}
+
+/// Hashes every file under `dir`, keyed by its path relative to `root`. Symlinks are not followed,
+/// and stand for their target instead. A build that produced nothing has no directory.
+async fn digest_tree(root: &Path, dir: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    let mut files = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut entries = WalkDir::new(dir);
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("Unable to walk '{}'", dir.display()))?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .await
+            .context(format!("Unable to read '{}'", path.display()))?;
+        let digest = if file_type.is_symlink() {
+            format!("symlink:{}", fs::read_link(&path).await?.display())
+        } else if file_type.is_file() {
+            file_digest(&path).await?
+        } else {
+            continue;
+        };
+        let relative = path
+            .strip_prefix(root)
+            .context(format!("Unexpected output path '{}'", path.display()))?;
+        files.insert(relative.to_path_buf(), digest);
+    }
+    Ok(files)
+}
+
+/// The sha256 of a file, read a piece at a time since images can be large.
+async fn file_digest(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .context(format!("Unable to open '{}'", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .context(format!("Unable to read '{}'", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Compares two sets of file digests, in path order.
+fn diff_trees<K: Ord + Clone>(
+    first: &BTreeMap<K, String>,
+    second: &BTreeMap<K, String>,
+) -> Vec<(K, Difference)> {
+    let paths = first.keys().chain(second.keys()).collect::<BTreeSet<_>>();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let difference = match (first.get(path), second.get(path)) {
+                (Some(_), None) => Difference::OnlyInFirst,
+                (None, Some(_)) => Difference::OnlyInSecond,
+                (Some(a), Some(b)) if a != b => Difference::Changed,
+                _ => return None,
+            };
+            Some((path.clone(), difference))
+        })
+        .collect()
+}
+
+fn is_rpm(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "rpm")
+}
+
+/// Lists the files inside an RPM from each build that differ, using `rpm` from the SDK.
+async fn diff_rpm(
+    sdk: &str,
+    first: &Path,
+    second: &Path,
+    path: &Path,
+) -> Result<Vec<RpmFileDifference>> {
+    let first_files = parse_rpm_dump(&rpm_dump(sdk, first, path).await?);
+    let second_files = parse_rpm_dump(&rpm_dump(sdk, second, path).await?);
+
+    let paths = first_files
+        .keys()
+        .chain(second_files.keys())
+        .collect::<BTreeSet<_>>();
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            let (difference, attributes) = match (first_files.get(path), second_files.get(path)) {
+                (Some(_), None) => (Difference::OnlyInFirst, Vec::new()),
+                (None, Some(_)) => (Difference::OnlyInSecond, Vec::new()),
+                (Some(a), Some(b)) => {
+                    let attributes = a.differing_attributes(b);
+                    if attributes.is_empty() {
+                        return None;
+                    }
+                    (Difference::Changed, attributes)
+                }
+                (None, None) => return None,
+            };
+            Some(RpmFileDifference {
+                path: path.clone(),
+                difference,
+                attributes,
+            })
+        })
+        .collect())
+}
+
+async fn rpm_dump(sdk: &str, build_dir: &Path, path: &Path) -> Result<String> {
+    let output = exec(
+        Command::new("docker")
+            .arg("run")
+            .arg("--rm")
+            .arg("--network=none")
+            .arg(format!("--volume={}:/build:ro", build_dir.display()))
+            .arg(sdk)
+            .args(["rpm", "--query", "--package", "--dump"])
+            .arg(Path::new("/build").join(path)),
+        true,
+    )
+    .await?;
+    output.context("No output from rpm")
+}
+
+/// A file in an RPM, as listed by `rpm --query --dump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RpmFile<'a> {
+    size: &'a str,
+    mtime: &'a str,
+    digest: &'a str,
+    mode: &'a str,
+    owner: &'a str,
+    group: &'a str,
+    link: &'a str,
+}
+
+impl RpmFile<'_> {
+    fn differing_attributes(&self, other: &Self) -> Vec<&'static str> {
+        [
+            ("size", self.size == other.size),
+            ("mtime", self.mtime == other.mtime),
+            ("digest", self.digest == other.digest),
+            ("mode", self.mode == other.mode),
+            ("owner", self.owner == other.owner),
+            ("group", self.group == other.group),
+            ("link", self.link == other.link),
+        ]
+        .into_iter()
+        .filter(|(_, same)| !same)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+/// Parses the output of `rpm --query --dump`, which lists each file as
+/// `path size mtime digest mode owner group isconfig isdoc rdev symlink`. Paths may contain
+/// spaces, so the line is split from the right.
+fn parse_rpm_dump(dump: &str) -> BTreeMap<String, RpmFile<'_>> {
+    dump.lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(11, ' ');
+            let link = fields.next()?;
+            let _rdev = fields.next()?;
+            let _isdoc = fields.next()?;
+            let _isconfig = fields.next()?;
+            let group = fields.next()?;
+            let owner = fields.next()?;
+            let mode = fields.next()?;
+            let digest = fields.next()?;
+            let mtime = fields.next()?;
+            let size = fields.next()?;
+            let path = fields.next()?;
+            Some((
+                path.to_string(),
+                RpmFile {
+                    size,
+                    mtime,
+                    digest,
+                    mode,
+                    owner,
+                    group,
+                    link,
+                },
+            ))
+        })
+        .collect()
+}
+
+impl Display for Reproducibility {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.differences.is_empty() {
+            return write!(f, "Both builds produced the same {} files", self.compared);
+        }
+        writeln!(
+            f,
+            "{} of {} files differ between '{}' and '{}':",
+            self.differences.len(),
+            self.compared,
+            self.first.display(),
+            self.second.display()
+        )?;
+        for difference in &self.differences {
+            writeln!(
+                f,
+                "  {} ({}): {}",
+                difference.path.display(),
+                difference.level.name(),
+                difference.difference.describe()
+            )?;
+            if difference.difference == Difference::Changed && is_rpm(&difference.path) {
+                if difference.rpm_files.is_empty() {
+                    writeln!(f, "    no files inside differ, only the RPM headers")?;
+                }
+                for file in &difference.rpm_files {
+                    write!(f, "    {}: {}", file.path, file.difference.describe())?;
+                    if !file.attributes.is_empty() {
+                        write!(f, " ({})", file.attributes.join(", "))?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OutputLevel {
+    fn name(&self) -> &'static str {
+        match self {
+            OutputLevel::Rpm => "rpm",
+            OutputLevel::KitRepo => "kit repo",
+            OutputLevel::Image => "image",
+        }
+    }
+}
+
+impl Difference {
+    fn describe(&self) -> &'static str {
+        match self {
+            Difference::OnlyInFirst => "only in the first build",
+            Difference::OnlyInSecond => "only in the second build",
+            Difference::Changed => "changed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff_trees() {
+        let first = BTreeMap::from([
+            ("rpms/a.rpm", "1".to_string()),
+            ("rpms/b.rpm", "2".to_string()),
+            ("rpms/c.rpm", "3".to_string()),
+        ]);
+        let second = BTreeMap::from([
+            ("rpms/a.rpm", "1".to_string()),
+            ("rpms/b.rpm", "4".to_string()),
+            ("rpms/d.rpm", "5".to_string()),
+        ]);
+        assert_eq!(
+            diff_trees(&first, &second),
+            [
+                ("rpms/b.rpm", Difference::Changed),
+                ("rpms/c.rpm", Difference::OnlyInFirst),
+                ("rpms/d.rpm", Difference::OnlyInSecond),
+            ]
+        );
+        assert!(diff_trees(&first, &first).is_empty());
+    }
+
+    #[test]
+    fn test_parse_rpm_dump() {
+        let first = "\
+/usr/bin/hello 1024 1700000000 aaaa 0100755 root root 0 0 0 X
+/usr/share/doc/hello world 12 1700000000 bbbb 0100644 root root 0 1 0 X
+/usr/lib/libhello.so 16 1700000000 0000 0120777 root root 0 0 0 libhello.so.1
+";
+        let second = "\
+/usr/bin/hello 1024 1700000099 cccc 0100755 root root 0 0 0 X
+/usr/share/doc/hello world 12 1700000000 bbbb 0100644 root root 0 1 0 X
+";
+        let first = parse_rpm_dump(first);
+        let second = parse_rpm_dump(second);
+        assert_eq!(first.len(), 3);
+        assert_eq!(first["/usr/share/doc/hello world"].digest, "bbbb");
+        assert_eq!(first["/usr/lib/libhello.so"].link, "libhello.so.1");
+        assert_eq!(
+            first["/usr/bin/hello"].differing_attributes(&second["/usr/bin/hello"]),
+            ["mtime", "digest"]
+        );
+        assert!(second.get("/usr/lib/libhello.so").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_digest_tree() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let images = temp_dir.path().join("images/x86_64-aws-dev/1.0.0");
+        fs::create_dir_all(&images).await.unwrap();
+        fs::write(images.join("root.ext4.lz4"), "root")
+            .await
+            .unwrap();
+        fs::symlink("1.0.0", images.with_file_name("latest"))
+            .await
+            .unwrap();
+
+        let files = digest_tree(temp_dir.path(), &temp_dir.path().join("images"))
+            .await
+            .unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                Path::new("images/x86_64-aws-dev/1.0.0/root.ext4.lz4"),
+                Path::new("images/x86_64-aws-dev/latest"),
+            ]
+        );
+        assert_eq!(
+            files[Path::new("images/x86_64-aws-dev/latest")],
+            "symlink:1.0.0"
+        );
+        assert!(digest_tree(temp_dir.path(), &temp_dir.path().join("kits"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_verify_reproducible_args() {
+        let args =
+            BuildVerifyReproducible::try_parse_from(["verify-reproducible", "--kit", "core-kit"])
+                .unwrap();
+        assert_eq!(
+            args.target(),
+            (ArtifactKind::Kit, "core-kit", "build-kit", "BUILDSYS_KIT")
+        );
+        assert!(BuildVerifyReproducible::try_parse_from(["verify-reproducible"]).is_err());
+        assert!(BuildVerifyReproducible::try_parse_from([
+            "verify-reproducible",
+            "--kit",
+            "core-kit",
+            "--variant",
+            "aws-dev",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Builds every project in a Twoliter workspace.
+//!
+//! Members are built one after another, producers of kits first. Before a member is built, its lock
+//! is verified and its external kits are fetched for each architecture; kits that an earlier member
+//! built are linked from that member's build directory rather than pulled from a registry. Since
+//! those kits have not been published, Twoliter.lock is not checked for them, but everything else
+//! in the lock still is.
+use super::build::{BuildKit, BuildVariant};
+use crate::cmd::report::{ErrorCode, Report, WithErrorCode};
+use crate::project::workspace::{self, Member};
+use crate::project::Locked;
+use anyhow::Result;
+use clap::Parser;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Build the kits and variants of every project in a Twoliter workspace, in dependency order.
+#[derive(Debug, Parser)]
+pub(crate) struct BuildWorkspace {
+    /// Path to Twoliter.workspace. Will search for Twoliter.workspace when absent.
+    #[clap(long = "workspace-path")]
+    workspace_path: Option<PathBuf>,
+
+    /// The architectures to build for. May be given more than once, or as a comma-separated list.
+    #[clap(long = "arch", default_value = "x86_64", value_delimiter = ',')]
+    arches: Vec<String>,
+
+    /// The URL to the lookaside cache where sources are stored to avoid pulling them from upstream.
+    /// Mirrors may be given as a comma-separated list, and are tried in order.
+    /// Defaults to https://cache.bottlerocket.aws
+    #[clap(long = "lookaside-cache")]
+    lookaside_cache: Option<String>,
+
+    /// If sources are not found in the lookaside cache, this flag will cause buildsys to pull them
+    /// from the upstream URL found in a package's `Cargo.toml`.
+    #[clap(long = "upstream-source-fallback")]
+    upstream_source_fallback: bool,
+
+    /// The number of variant builds to run at once within a member.
+    #[clap(long = "jobs", default_value = "1")]
+    jobs: NonZeroUsize,
+}
+
+impl BuildWorkspace {
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let workspace = workspace::load_or_find_workspace(self.workspace_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        info!(
+            "Building {} workspace members from '{}'",
+            workspace.members().len(),
+            workspace.filepath().display()
+        );
+        for member in workspace.members() {
+            self.build_member(member, report).await?;
+        }
+        Ok(())
+    }
+
+    async fn build_member(&self, member: &Member, report: &mut Report) -> Result<()> {
+        let project = member.project();
+        info!(
+            "Building workspace member '{}'",
+            project.project_dir().display()
+        );
+        // Resolve the variants before anything is built, so that a typo fails fast.
+        let variants = BuildVariant {
+            project_path: Some(project.filepath()),
+            arches: self.arches.clone(),
+            variants: member.variants().to_vec(),
+            lookaside_cache: self.lookaside_cache.clone(),
+            upstream_source_fallback: self.upstream_source_fallback,
+            infra_toml: None,
+            jobs: self.jobs,
+        };
+        let resolved_variants = if member.variants().is_empty() {
+            Vec::new()
+        } else {
+            variants
+                .resolve_variants(&project.project_dir())
+                .await
+                .error_code(ErrorCode::ProjectLoad)?
+        };
+
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
+        for arch in &self.arches {
+            project.fetch(arch).await.error_code(ErrorCode::Fetch)?;
+        }
+
+        for arch in &self.arches {
+            for kit in member.kits() {
+                let build_kit = BuildKit {
+                    project_path: Some(project.filepath()),
+                    arch: arch.clone(),
+                    kit: kit.clone(),
+                    lookaside_cache: self.lookaside_cache.clone(),
+                    legacy_lookaside_cache: None,
+                    upstream_source_fallback: self.upstream_source_fallback,
+                };
+                build_kit.build(&project, report).await?;
+            }
+        }
+        if !resolved_variants.is_empty() {
+            variants.build(&project, &resolved_variants, report).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_workspace_args() {
+        let args = BuildWorkspace::try_parse_from([
+            "workspace",
+            "--workspace-path",
+            "/src/Twoliter.workspace",
+            "--arch",
+            "x86_64,aarch64",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.workspace_path,
+            Some(PathBuf::from("/src/Twoliter.workspace"))
+        );
+        assert_eq!(args.arches, ["x86_64", "aarch64"]);
+        assert_eq!(args.jobs.get(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::project::{self, cache};
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tabled::{Table, Tabled};
+use tracing::info;
+
+const KIB: u64 = 1024;
+const UNITS: &[(&str, u64)] = &[
+    ("T", KIB * KIB * KIB * KIB),
+    ("G", KIB * KIB * KIB),
+    ("M", KIB * KIB),
+    ("K", KIB),
+];
+
+#[derive(Debug, Parser)]
+pub(crate) enum CacheCommand {
+    /// List the OCI archives cached for external kits, with their size and last use.
+    List(CacheList),
+
+    /// Remove cached OCI archives that are no longer referenced by a lock file. Archives pulled by
+    /// older versions of Twoliter don't record which lock files use them, and are only removed to
+    /// fit within `--max-size`.
+    Prune(CachePrune),
+}
+
+impl CacheCommand {
+    pub(crate) async fn run(&self) -> Result<()> {
+        match self {
+            CacheCommand::List(list) => list.run().await,
+            CacheCommand::Prune(prune) => prune.run().await,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct CacheList {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    pub(crate) project_path: Option<PathBuf>,
+}
+
+impl CacheList {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let archive_cache = project.archive_cache();
+        let archives = archive_cache.list().await?;
+
+        let total: u64 = archives.iter().map(|archive| archive.size).sum();
+        let rows = archives.iter().map(ArchiveRow::from).collect::<Vec<_>>();
+        println!("{}", Table::new(rows));
+        println!(
+            "{} archive(s) using {} in '{}'",
+            archives.len(),
+            format_size(total),
+            archive_cache.dir().display()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub(crate) struct CachePrune {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    pub(crate) project_path: Option<PathBuf>,
+
+    /// Additional lock files, or directories to search for lock files, whose images should be kept
+    /// in the cache. Archives referenced by the project's own Twoliter.lock are always kept unless
+    /// `--max-size` requires evicting them.
+    #[clap(long = "lock-path")]
+    pub(crate) lock_paths: Vec<PathBuf>,
+
+    /// Evict the least recently used archives until the cache fits within this size. Accepts a
+    /// number of bytes or a number with a K, M, G or T suffix, e.g. "20G".
+    #[clap(long = "max-size", value_parser = parse_size)]
+    pub(crate) max_size: Option<u64>,
+
+    /// Show what would be removed without removing anything.
+    #[clap(long = "dry-run")]
+    pub(crate) dry_run: bool,
+}
+
+impl CachePrune {
+    pub(super) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let lock_file = project.lock_file();
+        ensure!(
+            lock_file.exists(),
+            "Twoliter.lock does not exist, please run `twoliter update` first"
+        );
+
+        let mut lock_files = vec![lock_file];
+        for lock_path in &self.lock_paths {
+            ensure!(
+                lock_path.exists(),
+                "Lock path '{}' does not exist",
+                lock_path.display()
+            );
+            lock_files.extend(cache::find_lock_files(lock_path).await?);
+        }
+        let locked_digests = cache::locked_digests(&lock_files).await?;
+
+        let archive_cache = project.archive_cache();
+        let archives = archive_cache.list().await?;
+        let pruned = cache::select_for_pruning(&archives, &locked_digests, self.max_size);
+
+        let freed: u64 = pruned.iter().map(|archive| archive.size).sum();
+        for archive in &pruned {
+            if self.dry_run {
+                println!("Would remove {}", archive.path.display());
+            } else {
+                info!("Removing {}", archive.path.display());
+                archive_cache.remove(archive).await?;
+            }
+        }
+
+        println!(
+            "{} {} archive(s), freeing {}",
+            if self.dry_run {
+                "Would remove"
+            } else {
+                "Removed"
+            },
+            pruned.len(),
+            format_size(freed)
+        );
+        Ok(())
+    }
+}
+
+#[derive(Tabled)]
+struct ArchiveRow {
+    #[tabled(rename = "DIGEST")]
+    digest: String,
+    #[tabled(rename = "IMAGE")]
+    image: String,
+    #[tabled(rename = "SIZE")]
+    size: String,
+    #[tabled(rename = "LAST USED")]
+    last_used: String,
+}
+
+impl From<&cache::CachedArchive> for ArchiveRow {
+    fn from(archive: &cache::CachedArchive) -> Self {
+        let image = archive
+            .uri
+            .as_deref()
+            .and_then(|uri| uri.split_once('@'))
+            .map(|(image, _)| image.to_string())
+            .unwrap_or_else(|| "<unknown>".to_string());
+        Self {
+            digest: archive.digest.clone(),
+            image,
+            size: format_size(archive.size),
+            last_used: format_age(archive.last_used),
+        }
+    }
+}
+
+/// Parses a size given as a number of bytes with an optional binary unit suffix.
+fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let upper = value.to_ascii_uppercase();
+    let upper = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, multiplier) = UNITS
+        .iter()
+        .find_map(|(suffix, multiplier)| {
+            upper
+                .strip_suffix(suffix)
+                .map(|number| (number, *multiplier))
+        })
+        .unwrap_or((upper, 1));
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .context(format!("Invalid size '{value}'"))?;
+    if number.is_sign_negative() || !number.is_finite() {
+        bail!("Invalid size '{value}'");
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+fn format_size(bytes: u64) -> String {
+    UNITS
+        .iter()
+        .find(|(_, multiplier)| bytes >= *multiplier)
+        .map(|(suffix, multiplier)| format!("{:.1}{suffix}iB", bytes as f64 / *multiplier as f64))
+        .unwrap_or_else(|| format!("{bytes}B"))
+}
+
+fn format_age(time: SystemTime) -> String {
+    let age = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    match age {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} minutes ago", age / 60),
+        3600..=86399 => format!("{} hours ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("1.5M").unwrap(), 3 * 512 * 1024);
+        assert_eq!(parse_size("20G").unwrap(), 20 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("20GiB").unwrap(), 20 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1tb").unwrap(), 1024 * 1024 * 1024 * 1024);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("-1G").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(100), "100B");
+        assert_eq!(format_size(1536), "1.5KiB");
+        assert_eq!(format_size(20 * 1024 * 1024 * 1024), "20.0GiB");
+    }
+}
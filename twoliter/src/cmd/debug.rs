@@ -1,3 +1,5 @@
+use super::doctor::Doctor;
+use crate::project::{self, inspect::LockInspection};
 use crate::tools::install_tools;
 use anyhow::Result;
 use clap::Parser;
@@ -14,12 +16,16 @@ pub(crate) struct Debug {
 #[derive(Debug, Clone, Parser)]
 pub(crate) enum DebugAction {
     CheckTools(CheckToolArgs),
+    Doctor(Doctor),
+    Lock(InspectLock),
 }
 
 impl DebugAction {
     pub(crate) async fn run(&self) -> Result<()> {
         match self {
             DebugAction::CheckTools(c) => c.run().await,
+            DebugAction::Doctor(c) => c.run().await,
+            DebugAction::Lock(c) => c.run().await,
         }
     }
 }
@@ -53,3 +59,44 @@ impl CheckToolArgs {
         Ok(())
     }
 }
+
+/// Shows the verification markers that Twoliter leaves for the build system, compares them and the
+/// external kit metadata with Twoliter.lock, and explains whether the `validate-kits` task would
+/// pass. Nothing is resolved or changed.
+#[derive(Debug, Default, Clone, Parser)]
+pub(crate) struct InspectLock {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+}
+
+impl InspectLock {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone()).await?;
+        let inspection = LockInspection::load(&project.project_dir()).await;
+
+        println!("{}:", inspection.lock_file().display());
+        match inspection.locked_images() {
+            Ok(images) => {
+                for (role, image) in images {
+                    println!("  {role:<3} {image}");
+                }
+            }
+            Err(e) => println!("  {e}"),
+        }
+
+        for file in inspection.files() {
+            println!("\n{}: {}", file.path.display(), file.state);
+            for image in &file.images {
+                println!("  {image}");
+            }
+        }
+
+        let (passes, explanation) = inspection.explain_validate_kits();
+        println!(
+            "\nvalidate-kits would {}: {explanation}",
+            if passes { "pass" } else { "fail" }
+        );
+        Ok(())
+    }
+}
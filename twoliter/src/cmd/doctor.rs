@@ -0,0 +1,431 @@
+use crate::common::exec;
+use crate::project;
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// buildsys relies on `docker build` options, such as `--no-cache-filter`, that are only available
+/// when builds go through buildx, which Docker uses by default since 23.0.
+const MIN_DOCKER_VERSION: (u64, u64) = (23, 0);
+
+/// Below this much free space, a build of a variant is likely to fail partway through.
+const MIN_FREE_SPACE_GIB: u64 = 40;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Checks that the host has everything Twoliter's build and publish tools need, and explains how to
+/// fix anything that is missing.
+#[derive(Debug, Default, Clone, Parser)]
+pub(crate) struct Doctor {
+    /// Path to Twoliter.toml, used to check the free space where the project is built. Will search
+    /// for Twoliter.toml when absent, and fall back to the current directory if none is found.
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+}
+
+impl Doctor {
+    pub(crate) async fn run(&self) -> Result<()> {
+        let build_dir = self.build_dir().await?;
+        let docker_info = DockerInfo::load().await;
+
+        let mut checks = vec![
+            check_command(
+                "cargo make",
+                &["cargo", "make", "--version"],
+                CARGO_MAKE_HINT,
+            )
+            .await,
+            check_command("lz4", &["lz4", "--version"], LZ4_HINT).await,
+            check_docker_version().await,
+            check_command("buildx", &["docker", "buildx", "version"], BUILDX_HINT).await,
+        ];
+        match &docker_info {
+            Ok(info) => checks.extend([
+                info.check_host_network(),
+                info.check_snapshotter(),
+                info.check_cgroups(),
+                check_free_space("Docker storage", Path::new(&info.docker_root_dir)).await,
+            ]),
+            Err(e) => checks.push(CheckResult::fail(
+                "docker daemon",
+                format!("unable to query the docker daemon: {e:#}"),
+                DOCKER_DAEMON_HINT,
+            )),
+        }
+        checks.extend([
+            check_abstract_sockets(),
+            check_seccomp().await,
+            check_free_space("project storage", &build_dir).await,
+        ]);
+
+        for check in &checks {
+            println!("{check}");
+        }
+
+        let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+        let warned = checks.iter().filter(|c| c.status == Status::Warn).count();
+        println!(
+            "\n{} checks: {failed} failed, {warned} with warnings",
+            checks.len()
+        );
+        ensure!(failed == 0, "{failed} environment checks failed");
+        Ok(())
+    }
+
+    async fn build_dir(&self) -> Result<PathBuf> {
+        match project::load_or_find_project(self.project_path.clone()).await {
+            Ok(project) => Ok(project.project_dir()),
+            // Only a project given explicitly needs to exist.
+            Err(e) if self.project_path.is_some() => Err(e),
+            Err(_) => std::env::current_dir().context("Unable to get the current directory"),
+        }
+    }
+}
+
+const CARGO_MAKE_HINT: &str = "install cargo-make with `cargo install cargo-make`";
+const LZ4_HINT: &str = "install lz4 with your package manager, e.g. `dnf install lz4`";
+const DOCKER_HINT: &str =
+    "install Docker Engine 23.0 or newer: https://docs.docker.com/engine/install/";
+const DOCKER_DAEMON_HINT: &str =
+    "start the docker daemon and make sure your user may use it, e.g. by joining the docker group";
+const BUILDX_HINT: &str = "install the buildx plugin, e.g. `dnf install docker-buildx-plugin`";
+const HOST_NETWORK_HINT: &str =
+    "builds must run against a rootful Docker Engine on the build host \
+    itself, so that containers run with `--network host` share its network namespace";
+const SNAPSHOTTER_HINT: &str = "set `\"features\": {\"containerd-snapshotter\": true}` in \
+    /etc/docker/daemon.json and restart docker";
+const CGROUP_HINT: &str =
+    "boot the host with the unified cgroup v2 hierarchy (`systemd.unified_cgroup_hierarchy=1`)";
+const ABSTRACT_SOCKET_HINT: &str =
+    "pipesys shares build outputs over abstract Unix sockets, which \
+    are only available on Linux; build on a Linux host rather than a VM-backed Docker";
+const SECCOMP_HINT: &str = "use a kernel built with CONFIG_SECCOMP_FILTER, which unplug uses to \
+    keep builds off the network";
+const FREE_SPACE_HINT: &str =
+    "free up space, e.g. with `twoliter cache prune`, `twoliter build clean` or `docker system prune`";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+struct CheckResult {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self.status {
+            Status::Pass => "ok",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "[{status:^4}] {}: {}", self.name, self.detail)?;
+        if let Some(hint) = self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a command quietly, returning its stdout.
+async fn output(args: &[&str]) -> Result<String> {
+    let (program, args) = args.split_first().context("no command given")?;
+    let output = exec(Command::new(program).args(args), true).await?;
+    Ok(output.unwrap_or_default().trim().to_string())
+}
+
+async fn check_command(name: &'static str, args: &[&str], hint: &'static str) -> CheckResult {
+    match output(args).await {
+        Ok(version) => CheckResult::pass(name, version.lines().next().unwrap_or_default()),
+        Err(e) => CheckResult::fail(name, format!("`{}` failed: {e:#}", args.join(" ")), hint),
+    }
+}
+
+async fn check_docker_version() -> CheckResult {
+    const NAME: &str = "docker version";
+    let version = match output(&["docker", "version", "--format", "{{.Server.Version}}"]).await {
+        Ok(version) => version,
+        Err(e) => return CheckResult::fail(NAME, format!("{e:#}"), DOCKER_HINT),
+    };
+    match parse_version(&version) {
+        Some(parsed) if parsed >= MIN_DOCKER_VERSION => CheckResult::pass(NAME, version),
+        Some(_) => CheckResult::fail(
+            NAME,
+            format!(
+                "Docker {version} is older than the minimum supported version {}.{}",
+                MIN_DOCKER_VERSION.0, MIN_DOCKER_VERSION.1
+            ),
+            DOCKER_HINT,
+        ),
+        None => CheckResult::warn(
+            NAME,
+            format!("unable to parse the Docker version '{version}'"),
+            DOCKER_HINT,
+        ),
+    }
+}
+
+/// Parses the major and minor version from a Docker version such as `24.0.7` or `20.10.21+dfsg1`.
+fn parse_version(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim().trim_start_matches('v').split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+/// The parts of `docker info` that matter to builds.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerInfo {
+    #[serde(default)]
+    operating_system: String,
+    #[serde(default)]
+    security_options: Vec<String>,
+    #[serde(default)]
+    driver: String,
+    #[serde(default)]
+    driver_status: Vec<Vec<String>>,
+    #[serde(default)]
+    cgroup_version: String,
+    #[serde(default)]
+    docker_root_dir: String,
+}
+
+impl DockerInfo {
+    async fn load() -> Result<Self> {
+        let info = output(&["docker", "info", "--format", "{{json .}}"]).await?;
+        serde_json::from_str(&info).context("Unable to parse the output of `docker info`")
+    }
+
+    fn check_host_network(&self) -> CheckResult {
+        const NAME: &str = "host networking";
+        if self
+            .security_options
+            .iter()
+            .any(|option| option.contains("name=rootless"))
+        {
+            CheckResult::fail(
+                NAME,
+                "Docker is running rootless, so `--network host` does not share the host's network \
+                namespace with builds",
+                HOST_NETWORK_HINT,
+            )
+        } else if self.operating_system.contains("Docker Desktop") {
+            CheckResult::fail(
+                NAME,
+                "Docker Desktop runs containers in a VM, so `--network host` does not share the \
+                host's network namespace with builds",
+                HOST_NETWORK_HINT,
+            )
+        } else {
+            CheckResult::pass(NAME, "containers share the host's network namespace")
+        }
+    }
+
+    fn check_snapshotter(&self) -> CheckResult {
+        const NAME: &str = "containerd snapshotter";
+        let enabled = self.driver.contains("overlayfs")
+            || self
+                .driver_status
+                .iter()
+                .flatten()
+                .any(|value| value.contains("io.containerd.snapshotter"));
+        if enabled {
+            CheckResult::pass(NAME, format!("enabled ({})", self.driver))
+        } else {
+            CheckResult::warn(
+                NAME,
+                format!(
+                    "storage driver '{}' cannot store OCI image indexes, which is needed when \
+                    docker is used to handle kit images",
+                    self.driver
+                ),
+                SNAPSHOTTER_HINT,
+            )
+        }
+    }
+
+    fn check_cgroups(&self) -> CheckResult {
+        const NAME: &str = "cgroups";
+        match self.cgroup_version.as_str() {
+            "2" => CheckResult::pass(NAME, "cgroup v2"),
+            "" => CheckResult::warn(NAME, "Docker did not report a cgroup version", CGROUP_HINT),
+            version => CheckResult::warn(
+                NAME,
+                format!(
+                    "cgroup v{version} is in use; parallel builds are more likely to be killed \
+                    for running out of memory"
+                ),
+                CGROUP_HINT,
+            ),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_abstract_sockets() -> CheckResult {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+    const NAME: &str = "abstract Unix sockets";
+
+    let name = format!("twoliter-doctor-{}", uuid::Uuid::new_v4());
+    let result = SocketAddr::from_abstract_name(name.as_bytes())
+        .and_then(|addr| {
+            let listener = UnixListener::bind_addr(&addr)?;
+            UnixStream::connect_addr(&addr)?;
+            listener.accept()
+        })
+        .map(|_| ());
+    match result {
+        Ok(()) => CheckResult::pass(NAME, "supported"),
+        Err(e) => CheckResult::fail(NAME, e.to_string(), ABSTRACT_SOCKET_HINT),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_abstract_sockets() -> CheckResult {
+    CheckResult::fail(
+        "abstract Unix sockets",
+        "not supported on this operating system",
+        ABSTRACT_SOCKET_HINT,
+    )
+}
+
+async fn check_seccomp() -> CheckResult {
+    const NAME: &str = "seccomp";
+    match tokio::fs::read_to_string("/proc/sys/kernel/seccomp/actions_avail").await {
+        Ok(actions) if actions.split_whitespace().any(|action| action == "errno") => {
+            CheckResult::pass(NAME, "seccomp filters are supported")
+        }
+        Ok(actions) => CheckResult::fail(
+            NAME,
+            format!("the kernel cannot return errors from filters (available: {actions})"),
+            SECCOMP_HINT,
+        ),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("unable to read the kernel's seccomp support: {e}"),
+            SECCOMP_HINT,
+        ),
+    }
+}
+
+async fn check_free_space(name: &'static str, path: &Path) -> CheckResult {
+    let path_arg = path.display().to_string();
+    let available = match output(&["df", "-Pk", &path_arg]).await {
+        Ok(df) => parse_df_available(&df),
+        Err(e) => return CheckResult::warn(name, format!("{e:#}"), FREE_SPACE_HINT),
+    };
+    match available {
+        Some(bytes) if bytes >= MIN_FREE_SPACE_GIB * GIB => CheckResult::pass(
+            name,
+            format!("{} GiB free at '{}'", bytes / GIB, path.display()),
+        ),
+        Some(bytes) => CheckResult::fail(
+            name,
+            format!(
+                "only {} GiB free at '{}', at least {MIN_FREE_SPACE_GIB} GiB is recommended",
+                bytes / GIB,
+                path.display()
+            ),
+            FREE_SPACE_HINT,
+        ),
+        None => CheckResult::warn(
+            name,
+            format!("unable to determine the free space at '{}'", path.display()),
+            FREE_SPACE_HINT,
+        ),
+    }
+}
+
+/// Parses the available bytes from the output of `df -Pk`.
+fn parse_df_available(df: &str) -> Option<u64> {
+    let kib: u64 = df.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("24.0.7"), Some((24, 0)));
+        assert_eq!(parse_version("20.10.21+dfsg1"), Some((20, 10)));
+        assert_eq!(parse_version("27.3.1-ce"), Some((27, 3)));
+        assert_eq!(parse_version("dev"), None);
+        assert!(parse_version("20.10.21").unwrap() < MIN_DOCKER_VERSION);
+    }
+
+    #[test]
+    fn test_parse_df_available() {
+        let df = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+                  /dev/nvme0n1p1   104845292  52422646  52422646      50% /";
+        assert_eq!(parse_df_available(df), Some(52422646 * 1024));
+        assert_eq!(parse_df_available("garbage"), None);
+    }
+
+    #[test]
+    fn test_host_network() {
+        let info = DockerInfo {
+            security_options: vec![
+                "name=seccomp,profile=builtin".into(),
+                "name=rootless".into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(info.check_host_network().status, Status::Fail);
+
+        let info = DockerInfo {
+            operating_system: "Amazon Linux 2023".into(),
+            security_options: vec!["name=seccomp,profile=builtin".into()],
+            ..Default::default()
+        };
+        assert_eq!(info.check_host_network().status, Status::Pass);
+    }
+}
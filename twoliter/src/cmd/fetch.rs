@@ -1,3 +1,4 @@
+use crate::cmd::report::{ArtifactKind, ErrorCode, Report, ReportedArtifact, WithErrorCode};
 use crate::project::{self, Locked};
 use anyhow::Result;
 use clap::Parser;
@@ -15,10 +16,24 @@ pub(crate) struct Fetch {
 }
 
 impl Fetch {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
-        project.fetch(self.arch.as_str()).await?;
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
+        project
+            .fetch(self.arch.as_str())
+            .await
+            .error_code(ErrorCode::Fetch)?;
+        report.artifact(
+            ReportedArtifact::new(ArtifactKind::ExternalKits, "external-kits")
+                .arch(&self.arch)
+                .path(project.external_kits_dir()),
+        );
         Ok(())
     }
 }
@@ -1,27 +1,11 @@
 use crate::cargo_make::CargoMake;
+use crate::makefile::{project_makefile, task_verification, Verification};
 use crate::project::{self, Locked, SDKLocked, Unlocked};
 use crate::tools::install_tools;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
-
-// Most subcommands do not require kits and thus do not need to resolve and verify them against the
-// lockfile.
-//
-// Avoiding that resolution can be useful in CI situations where images are already built and we
-// need to perform additional operations using the SDK.
-//
-// Only twoliter make targets in the following list *require* kit validation; however, kit
-// validation will occur in other targets which require the SDK if no SDK is explicitly listed in
-// Twoliter.toml.
-const MUST_VALIDATE_KITS_TARGETS: &[&str] = &[
-    "build-package",
-    "build-kit",
-    "build-variant",
-    "build-all",
-    "build",
-    "default",
-];
+use tracing::debug;
 
 /// Run a cargo make command in Twoliter's build environment. Known Makefile.toml environment
 /// variables will be passed-through to the cargo make invocation.
@@ -43,7 +27,8 @@ pub(crate) struct Make {
     #[clap(long, env = "BUILDSYS_ARCH")]
     arch: String,
 
-    /// Cargo make task. E.g. the word "build" if we want to execute `cargo make build`.
+    /// Cargo make task. E.g. the word "build" if we want to execute `cargo make build`. This may
+    /// also be a task from the project's makefile extension.
     makefile_task: String,
 
     /// Uninspected arguments to be passed to cargo make after the target name. For example, --foo
@@ -54,10 +39,11 @@ pub(crate) struct Make {
 impl Make {
     pub(super) async fn run(&self) -> Result<()> {
         let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let sdk_source = self.locked_sdk(&project).await?;
         let toolsdir = project.project_dir().join("build/tools");
         install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        let makefile_path = project_makefile(&project, &toolsdir).await?;
+        let declared = task_verification(&makefile_path, &self.makefile_task).await?;
+        let sdk_source = self.locked_sdk(&project, declared).await?;
         CargoMake::new(&sdk_source)?
             .env("CARGO_HOME", self.cargo_home.display().to_string())
             .env("TWOLITER_TOOLS_DIR", toolsdir.display().to_string())
@@ -68,23 +54,39 @@ impl Make {
             .await
     }
 
-    fn can_skip_kit_verification(&self, project: &project::Project<Unlocked>) -> bool {
-        let target_allows_kit_verification_skip =
-            !MUST_VALIDATE_KITS_TARGETS.contains(&self.makefile_task.as_str());
-        let project_has_explicit_sdk_dep = project.direct_sdk_image_dep().is_some();
-
-        target_allows_kit_verification_skip && project_has_explicit_sdk_dep
+    /// The verification to do before running the task, given what the task declares in the
+    /// makefile. Most tasks do not require kits, and avoiding their resolution can be useful in CI
+    /// situations where images are already built and we need to perform additional operations
+    /// using the SDK. So a task that declares nothing only requires the SDK.
+    ///
+    /// If Twoliter.toml does not name an SDK, the SDK comes from a kit, and the kits must be
+    /// verified to find it.
+    fn verification(
+        project: &project::Project<Unlocked>,
+        declared: Option<Verification>,
+    ) -> Verification {
+        if project.direct_sdk_image_dep().is_none() {
+            return Verification::Kits;
+        }
+        declared.unwrap_or(Verification::Sdk)
     }
 
-    /// Returns the locked SDK image for the project.
-    async fn locked_sdk(&self, project: &project::Project<Unlocked>) -> Result<String> {
-        Ok(if self.can_skip_kit_verification(project) {
-            project.load_lock::<SDKLocked>().await?.sdk_image()
-        } else {
-            project.load_lock::<Locked>().await?.sdk_image()
-        }
-        .project_image_uri()
-        .to_string())
+    /// Returns the SDK image for the project, after verifying as much of the lock as the task
+    /// needs.
+    async fn locked_sdk(
+        &self,
+        project: &project::Project<Unlocked>,
+        declared: Option<Verification>,
+    ) -> Result<String> {
+        let sdk = match Self::verification(project, declared) {
+            Verification::None => project
+                .direct_sdk_image_dep()
+                .context("Twoliter.toml does not name an SDK")??,
+            Verification::Sdk => project.load_lock::<SDKLocked>().await?.sdk_image(),
+            Verification::Kits => project.load_lock::<Locked>().await?.sdk_image(),
+        };
+        debug!("Running task '{}' with SDK '{sdk}'", self.makefile_task);
+        Ok(sdk.project_image_uri().to_string())
     }
 }
 
@@ -92,6 +94,7 @@ impl Make {
 mod test {
     use std::path::Path;
 
+    use crate::cmd::report::{OutputFormat, Report};
     use crate::cmd::update::Update;
     use crate::project::VerificationTagger;
 
@@ -198,7 +201,8 @@ mod test {
         let command = Update {
             project_path: Some(project_path.to_path_buf()),
         };
-        command.run().await.unwrap();
+        let mut report = Report::new("update", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
     }
 
     async fn run_makefile_target(
@@ -246,14 +250,31 @@ mod test {
             .await
             .unwrap();
 
-        let make = Make {
-            project_path: Some(project_path),
-            cargo_home: project_dir.to_owned(),
-            arch: "x86_64".to_string(),
-            makefile_task: target_name.to_string(),
-            additional_args: Vec::new(),
-        };
-        make.can_skip_kit_verification(&project)
+        let toolsdir = project_dir.join("build/tools");
+        install_tools(&toolsdir).await.unwrap();
+        let makefile_path = project_makefile(&project, &toolsdir).await.unwrap();
+        let declared = task_verification(&makefile_path, target_name)
+            .await
+            .unwrap();
+        Make::verification(&project, declared) != Verification::Kits
+    }
+
+    #[tokio::test]
+    async fn test_undeclared_task_can_skip_kit_verification() {
+        assert!(target_can_skip_kit_verification("no-such-task").await);
+    }
+
+    #[tokio::test]
+    async fn test_build_cannot_skip_kit_verification() {
+        for target in [
+            "build-package",
+            "build-kit",
+            "build-all",
+            "build",
+            "default",
+        ] {
+            assert!(!target_can_skip_kit_verification(target).await, "{target}");
+        }
     }
 
     #[tokio::test]
@@ -1,18 +1,27 @@
 mod build;
 mod build_clean;
+mod build_reproducible;
+mod build_workspace;
+mod cache;
 mod debug;
+mod doctor;
 mod fetch;
 mod make;
 mod publish_kit;
+mod publish_variant;
+mod report;
 mod update;
 
 use self::build::BuildCommand;
+use crate::cmd::cache::CacheCommand;
 use crate::cmd::debug::DebugAction;
 use crate::cmd::fetch::Fetch;
 use crate::cmd::make::Make;
 use crate::cmd::publish_kit::PublishCommand;
+use crate::cmd::report::{OutputFormat, Report};
 use crate::cmd::update::Update;
-use anyhow::Result;
+use crate::common;
+use anyhow::{ensure, Result};
 use clap::Parser;
 use env_logger::Builder;
 use log::LevelFilter;
@@ -29,6 +38,13 @@ pub(crate) struct Args {
     #[clap(long = "log-level")]
     pub(crate) log_level: Option<LevelFilter>,
 
+    /// The format of the output printed when the command finishes. With `json`, a single JSON
+    /// document describing the resolved images, artifacts, durations and any error is printed to
+    /// stdout, and the output of the build system is sent to stderr. Only the `build`, `fetch`,
+    /// `update` and `publish` commands can be reported as JSON.
+    #[clap(long = "output", value_enum, default_value_t = OutputFormat::Text, global = true)]
+    pub(crate) output: OutputFormat,
+
     #[clap(subcommand)]
     pub(crate) subcommand: Subcommand,
 }
@@ -46,10 +62,14 @@ pub(crate) enum Subcommand {
     /// Update Twoliter.lock
     Update(Update),
 
-    /// Publish something, such as a Kit
+    /// Publish something, such as a kit, a repo or an AMI
     #[clap(subcommand)]
     Publish(PublishCommand),
 
+    /// Inspect and clean up the cache of OCI archives pulled for external kits.
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+
     /// Commands that are used for checking and troubleshooting Twoliter's internals.
     #[clap(subcommand)]
     Debug(DebugAction),
@@ -57,13 +77,56 @@ pub(crate) enum Subcommand {
 
 /// Entrypoint for the `twoliter` command line program.
 pub(super) async fn run(args: Args) -> Result<()> {
-    match args.subcommand {
-        Subcommand::Build(build_command) => build_command.run().await,
-        Subcommand::Fetch(fetch_args) => fetch_args.run().await,
+    ensure!(
+        args.output == OutputFormat::Text || args.subcommand.reports(),
+        "'twoliter {}' does not support '--output json'",
+        args.subcommand.name()
+    );
+    if args.output == OutputFormat::Json {
+        common::reserve_stdout();
+    }
+    let mut report = Report::new(args.subcommand.name(), args.output);
+    let result = match args.subcommand {
+        Subcommand::Build(build_command) => build_command.run(&mut report).await,
+        Subcommand::Fetch(fetch_args) => fetch_args.run(&mut report).await,
         Subcommand::Make(make_args) => make_args.run().await,
-        Subcommand::Update(update_args) => update_args.run().await,
-        Subcommand::Publish(publish_command) => publish_command.run().await,
+        Subcommand::Update(update_args) => update_args.run(&mut report).await,
+        Subcommand::Publish(publish_command) => publish_command.run(&mut report).await,
+        Subcommand::Cache(cache_command) => cache_command.run().await,
         Subcommand::Debug(debug_action) => debug_action.run().await,
+    };
+
+    report.finish(&result);
+    if !report.human_readable() {
+        println!("{}", report.to_json()?);
+    }
+    result
+}
+
+impl Subcommand {
+    /// The name of the command as it is given on the command line, e.g. `build variant`.
+    fn name(&self) -> String {
+        match self {
+            Subcommand::Build(build_command) => format!("build {}", build_command.name()),
+            Subcommand::Fetch(_) => "fetch".to_string(),
+            Subcommand::Make(_) => "make".to_string(),
+            Subcommand::Update(_) => "update".to_string(),
+            Subcommand::Publish(publish_command) => format!("publish {}", publish_command.name()),
+            Subcommand::Cache(_) => "cache".to_string(),
+            Subcommand::Debug(_) => "debug".to_string(),
+        }
+    }
+
+    /// Whether the command fills in a [`Report`]. Other commands print their own text to stdout,
+    /// which would be mixed into the JSON document.
+    fn reports(&self) -> bool {
+        match self {
+            Subcommand::Build(_)
+            | Subcommand::Fetch(_)
+            | Subcommand::Update(_)
+            | Subcommand::Publish(_) => true,
+            Subcommand::Make(_) | Subcommand::Cache(_) | Subcommand::Debug(_) => false,
+        }
     }
 }
 
@@ -133,7 +196,8 @@ mod test {
         let command = Update {
             project_path: Some(project_path.to_path_buf()),
         };
-        command.run().await.unwrap();
+        let mut report = Report::new("update", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
     }
 
     async fn twoliter_fetch(project_path: &Path, arch: &str) {
@@ -141,7 +205,8 @@ mod test {
             project_path: Some(project_path.to_path_buf()),
             arch: arch.into(),
         };
-        command.run().await.unwrap()
+        let mut report = Report::new("fetch", OutputFormat::Text);
+        command.run(&mut report).await.unwrap()
     }
 
     #[tokio::test]
@@ -160,10 +225,12 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
+            legacy_lookaside_cache: None,
             upstream_source_fallback: false,
         };
 
-        command.run().await.unwrap();
+        let mut report = Report::new("build kit", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
     }
 
@@ -183,10 +250,12 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
+            legacy_lookaside_cache: None,
             upstream_source_fallback: false,
         };
 
-        command.run().await.unwrap();
+        let mut report = Report::new("build kit", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-1-kit", arch, &["pkg-b", "pkg-d"]).await;
     }
@@ -207,10 +276,12 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
+            legacy_lookaside_cache: None,
             upstream_source_fallback: false,
         };
 
-        command.run().await.unwrap();
+        let mut report = Report::new("build kit", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-2-kit", arch, &["pkg-c"]).await;
     }
@@ -231,10 +302,12 @@ mod test {
             arch: arch.to_string(),
             kit: kit_name.to_string(),
             lookaside_cache: None,
+            legacy_lookaside_cache: None,
             upstream_source_fallback: false,
         };
 
-        command.run().await.unwrap();
+        let mut report = Report::new("build kit", OutputFormat::Text);
+        command.run(&mut report).await.unwrap();
         expect_kit(&project_dir, "core-kit", arch, &["pkg-a"]).await;
         expect_kit(&project_dir, "extra-1-kit", arch, &["pkg-b", "pkg-d"]).await;
         expect_kit(&project_dir, "extra-2-kit", arch, &["pkg-c"]).await;
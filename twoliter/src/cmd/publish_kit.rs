@@ -1,4 +1,9 @@
 use crate::cargo_make::CargoMake;
+use crate::cmd::publish_variant::{
+    PublishAmi, PublishOva, PublishPromoteSsm, PublishRepo, PublishSsm,
+};
+use crate::cmd::report::{ArtifactKind, ErrorCode, Report, ReportedArtifact, WithErrorCode};
+use crate::makefile::project_makefile;
 use crate::project::{self, Locked};
 use crate::tools::install_tools;
 use anyhow::Result;
@@ -9,12 +14,33 @@ use std::path::PathBuf;
 #[derive(Debug, Parser)]
 pub(crate) enum PublishCommand {
     Kit(PublishKit),
+    Repo(PublishRepo),
+    Ami(PublishAmi),
+    Ssm(PublishSsm),
+    PromoteSsm(PublishPromoteSsm),
+    Ova(PublishOva),
 }
 
 impl PublishCommand {
-    pub(crate) async fn run(self) -> Result<()> {
+    pub(crate) async fn run(self, report: &mut Report) -> Result<()> {
         match self {
-            PublishCommand::Kit(command) => command.run().await,
+            PublishCommand::Kit(command) => command.run(report).await,
+            PublishCommand::Repo(command) => command.run().await,
+            PublishCommand::Ami(command) => command.run().await,
+            PublishCommand::Ssm(command) => command.run().await,
+            PublishCommand::PromoteSsm(command) => command.run().await,
+            PublishCommand::Ova(command) => command.run().await,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            PublishCommand::Kit(_) => "kit",
+            PublishCommand::Repo(_) => "repo",
+            PublishCommand::Ami(_) => "ami",
+            PublishCommand::Ssm(_) => "ssm",
+            PublishCommand::PromoteSsm(_) => "promote-ssm",
+            PublishCommand::Ova(_) => "ova",
         }
     }
 }
@@ -37,12 +63,22 @@ pub(crate) struct PublishKit {
 }
 
 impl PublishKit {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        let project = project.load_lock::<Locked>().await?;
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let project = project
+            .load_lock::<Locked>()
+            .await
+            .error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
         let toolsdir = project.project_dir().join("build/tools");
-        install_tools(&toolsdir).await?;
-        let makefile_path = toolsdir.join("Makefile.toml");
+        install_tools(&toolsdir)
+            .await
+            .error_code(ErrorCode::Tools)?;
+        let makefile_path = project_makefile(&project, &toolsdir)
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
 
         let publish_kit_repo = match &self.kit_repo {
             Some(kit_repo) => kit_repo,
@@ -58,5 +94,22 @@ impl PublishKit {
             .project_dir(project.project_dir())
             .exec("publish-kit")
             .await
+            .error_code(ErrorCode::Publish)?;
+
+        report.artifact(
+            ReportedArtifact::new(ArtifactKind::PublishedKit, &self.kit_name)
+                .path(
+                    project
+                        .project_dir()
+                        .join("build/kits")
+                        .join(&self.kit_name),
+                )
+                .image(format!(
+                    "{}/{publish_kit_repo}:v{}",
+                    self.vendor,
+                    project.release_version()
+                )),
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,800 @@
+use crate::common::exec_log;
+use crate::common::fs::{create_dir_all, read_link, remove_file};
+use crate::makefile::{env_value, project_makefile};
+use crate::project::{self, Project, Unlocked};
+use crate::tools::install_tools;
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// The variable that names the images of a project, and so the prefix of every image artifact.
+const IMAGE_NAME_VAR: &str = "BUILDSYS_NAME";
+/// The image name that buildsys uses when neither the environment nor the makefile sets one.
+const DEFAULT_IMAGE_NAME: &str = "bottlerocket";
+const AMI_DATA_FILE_SUFFIX: &str = "amis.json";
+const SSM_DATA_FILE_SUFFIX: &str = "ssm-params.json";
+const DEFAULT_REPO: &str = "default";
+
+/// Arguments shared by every command that publishes a variant which has already been built.
+#[derive(Debug, Clone, Parser)]
+pub(crate) struct VariantPublishArgs {
+    /// Path to Twoliter.toml. Will search for Twoliter.toml when absent
+    #[clap(long = "project-path")]
+    project_path: Option<PathBuf>,
+
+    /// The variant to publish
+    #[clap(long = "variant")]
+    variant: String,
+
+    /// The architecture of the variant build to publish
+    #[clap(long = "arch", default_value = "x86_64", value_parser = ["x86_64", "aarch64"])]
+    arch: String,
+
+    /// The full version of the build to publish, e.g. "1.2.0-abcdef01". Defaults to the most
+    /// recent build of the variant for this architecture.
+    #[clap(long = "build")]
+    build: Option<String>,
+
+    /// Path to Infra.toml. Defaults to Infra.toml in the project directory
+    #[clap(long = "infra-config-path")]
+    infra_config_path: Option<PathBuf>,
+
+    /// The log level passed to pubsys
+    #[clap(long = "publish-log-level", default_value = "info")]
+    publish_log_level: String,
+}
+
+/// Publish a local TUF repository for a variant build
+#[derive(Debug, Parser)]
+pub(crate) struct PublishRepo {
+    #[clap(flatten)]
+    common: VariantPublishArgs,
+
+    /// The repo from Infra.toml to publish to
+    #[clap(long = "repo", default_value = DEFAULT_REPO)]
+    repo: String,
+
+    /// When the waves of the release should start, e.g. "2024-01-01T00:00:00Z". Defaults to now
+    #[clap(long = "release-start-time")]
+    release_start_time: Option<String>,
+
+    /// Path to the repo expiration policy. Defaults to the 2w-2w-1w policy in the project
+    #[clap(long = "repo-expiration-policy-path")]
+    repo_expiration_policy_path: Option<PathBuf>,
+
+    /// Path to the wave policy. Defaults to the policy installed with Twoliter's tools
+    #[clap(long = "wave-policy-path")]
+    wave_policy_path: Option<PathBuf>,
+}
+
+/// Register an AMI from a variant build
+#[derive(Debug, Parser)]
+pub(crate) struct PublishAmi {
+    #[clap(flatten)]
+    common: VariantPublishArgs,
+
+    /// The name of the AMI. Defaults to one derived from the variant, arch and version
+    #[clap(long = "name")]
+    name: Option<String>,
+
+    /// The description of the AMI. Defaults to its name
+    #[clap(long = "description")]
+    description: Option<String>,
+
+    /// Regions to register the AMI in, overriding those in Infra.toml
+    #[clap(long = "regions", value_delimiter = ',')]
+    regions: Vec<String>,
+
+    /// The Secure Boot keys profile whose UEFI variables are attached to the AMI
+    #[clap(
+        long = "sbkeys-profile",
+        env = "BUILDSYS_SBKEYS_PROFILE",
+        default_value = "local"
+    )]
+    sbkeys_profile: String,
+
+    /// Don't show progress while uploading snapshots
+    #[clap(long = "no-progress")]
+    no_progress: bool,
+}
+
+/// Set SSM parameters for the AMIs registered from a variant build
+#[derive(Debug, Parser)]
+pub(crate) struct PublishSsm {
+    #[clap(flatten)]
+    common: VariantPublishArgs,
+
+    /// Regions to set parameters in, overriding those in Infra.toml
+    #[clap(long = "regions", value_delimiter = ',')]
+    regions: Vec<String>,
+
+    /// Path to the SSM parameter templates. Defaults to the templates in the project
+    #[clap(long = "template-path")]
+    template_path: Option<PathBuf>,
+
+    /// Allow existing parameters to be overwritten
+    #[clap(long = "allow-clobber")]
+    allow_clobber: bool,
+}
+
+/// Promote the SSM parameters of a variant build from one version or pointer to another
+#[derive(Debug, Parser)]
+pub(crate) struct PublishPromoteSsm {
+    #[clap(flatten)]
+    common: VariantPublishArgs,
+
+    /// The version or pointer to promote to, e.g. "latest"
+    #[clap(long = "target")]
+    target: String,
+
+    /// The version to promote. Defaults to the full version of the build
+    #[clap(long = "source")]
+    source: Option<String>,
+
+    /// Regions to promote parameters in, overriding those in Infra.toml
+    #[clap(long = "regions", value_delimiter = ',')]
+    regions: Vec<String>,
+
+    /// Path to the SSM parameter templates. Defaults to the templates in the project
+    #[clap(long = "template-path")]
+    template_path: Option<PathBuf>,
+}
+
+/// Upload the OVA of a variant build to VMware
+#[derive(Debug, Parser)]
+pub(crate) struct PublishOva {
+    #[clap(flatten)]
+    common: VariantPublishArgs,
+
+    /// The name of the uploaded VM. Defaults to one derived from the variant, arch and version
+    #[clap(long = "name")]
+    name: Option<String>,
+
+    /// Datacenters to upload to, overriding those in Infra.toml
+    #[clap(long = "datacenters", value_delimiter = ',')]
+    datacenters: Vec<String>,
+
+    /// Path to the import spec template. Defaults to the template in the project
+    #[clap(long = "import-spec-path")]
+    import_spec_path: Option<PathBuf>,
+
+    /// Mark the uploaded VM as a template
+    #[clap(long = "mark-as-template")]
+    mark_as_template: bool,
+}
+
+impl PublishRepo {
+    pub(super) async fn run(&self) -> Result<()> {
+        let publisher = Publisher::new(&self.common).await?;
+        let artifacts = &publisher.artifacts;
+        let project_dir = publisher.project.project_dir();
+        let root_role = project_dir.join(format!("roles/{}.root.json", self.repo));
+        let default_key = project_dir.join(format!("keys/{}.pem", self.repo));
+        let expiration_policy = self.repo_expiration_policy_path.clone().unwrap_or_else(|| {
+            project_dir.join("tools/pubsys/policies/repo-expiration/2w-2w-1w.toml")
+        });
+        let wave_policy = self
+            .wave_policy_path
+            .clone()
+            .unwrap_or_else(|| publisher.toolsdir.join("waves/default-waves.toml"));
+        let release_config = project_dir.join("Release.toml");
+        require_file(&expiration_policy, "repo expiration policy")?;
+        require_file(&wave_policy, "wave policy")?;
+        require_file(&release_config, "release config")?;
+
+        let boot_image = artifacts.require("-boot.ext4.lz4")?;
+        let root_image = artifacts.require("-root.ext4.lz4")?;
+        let hash_image = artifacts.require("-root.verity.lz4")?;
+        let migrations = artifacts.require("-migrations.tar")?;
+
+        let mut link_targets = vec![artifacts.require_path(artifacts.kmod_kit())?];
+        for suffix in [".img.lz4", "-data.img.lz4"] {
+            if artifacts.file(suffix).is_file() {
+                link_targets.push(artifacts.file(suffix));
+                link_targets.push(artifacts.friendly_file(suffix));
+            }
+        }
+        let ovf_template = project_dir.join(format!("variants/{}/template.ovf", artifacts.variant));
+        if ovf_template.is_file() {
+            link_targets.push(
+                artifacts
+                    .require_path(artifacts.ova())
+                    .context("An OVA is required to publish a repo for this variant")?,
+            );
+        }
+
+        // Migrations are shipped as individual targets, so unpack them somewhere temporary.
+        let migrations_dir = TempDir::new().context("Unable to create a temporary directory")?;
+        let migrations = std::fs::File::open(&migrations)
+            .context(format!("Unable to open '{}'", migrations.display()))?;
+        tar::Archive::new(migrations)
+            .unpack(migrations_dir.path())
+            .context("Unable to unpack migrations")?;
+        let mut copy_targets = std::fs::read_dir(migrations_dir.path())
+            .context("Unable to read unpacked migrations")?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .context("Unable to read unpacked migrations")?;
+        copy_targets.sort();
+
+        exec_log(
+            Command::new(publisher.toolsdir.join("pubsys-setup"))
+                .args(publisher.global_args())
+                .arg("--root-role-path")
+                .arg(&root_role)
+                .arg("--default-key-path")
+                .arg(&default_key)
+                .args(["--repo", &self.repo]),
+        )
+        .await?;
+
+        let repo_base_dir = project_dir.join("build/repos").join(&self.repo);
+        let outdir = repo_base_dir.join(format!("{}-{}", artifacts.name, artifacts.version_full));
+        let mut args = publisher.global_args();
+        args.extend(os_args([
+            "repo",
+            "--repo",
+            &self.repo,
+            "--arch",
+            &artifacts.arch,
+            "--version",
+            &artifacts.version_image,
+            "--variant",
+            &artifacts.variant,
+        ]));
+        args.extend(path_arg("--boot-image", &boot_image));
+        args.extend(path_arg("--root-image", &root_image));
+        args.extend(path_arg("--hash-image", &hash_image));
+        for target in &link_targets {
+            args.extend(path_arg("--link-target", target));
+        }
+        for target in &copy_targets {
+            args.extend(path_arg("--copy-target", target));
+        }
+        args.extend(path_arg(
+            "--repo-expiration-policy-path",
+            &expiration_policy,
+        ));
+        args.extend(path_arg("--release-config-path", &release_config));
+        args.extend(path_arg("--wave-policy-path", &wave_policy));
+        if let Some(release_start_time) = &self.release_start_time {
+            args.extend(os_args(["--release-start-time", release_start_time]));
+        }
+        args.extend(path_arg("--root-role-path", &root_role));
+        args.extend(path_arg("--default-key-path", &default_key));
+        args.extend(path_arg("--outdir", &outdir));
+        publisher.pubsys(args).await?;
+
+        symlink_latest(&repo_base_dir, &outdir).await?;
+        info!("Published repo to '{}'", outdir.display());
+        Ok(())
+    }
+}
+
+impl PublishAmi {
+    pub(super) async fn run(&self) -> Result<()> {
+        let publisher = Publisher::new(&self.common).await?;
+        let artifacts = &publisher.artifacts;
+        let project_dir = publisher.project.project_dir();
+        let os_image = artifacts.require(".img.lz4")?;
+        let data_image = Some(artifacts.file("-data.img.lz4")).filter(|path| path.is_file());
+        let variant_manifest =
+            project_dir.join(format!("variants/{}/Cargo.toml", artifacts.variant));
+        let uefi_data = project_dir.join(format!("sbkeys/{}/efi-vars.aws", self.sbkeys_profile));
+        require_file(&variant_manifest, "variant manifest")?;
+        require_file(
+            &uefi_data,
+            "UEFI variables for the Secure Boot keys profile",
+        )?;
+
+        // pubsys registers raw images, so decompress them alongside their artifacts. The temporary
+        // directory removes them again when we're done.
+        let scratch = TempDir::new_in(&artifacts.dir)
+            .context("Unable to create a directory to decompress images in")?;
+        let os_image = decompress(&os_image, scratch.path()).await?;
+        let data_image = match data_image {
+            Some(data_image) => Some(decompress(&data_image, scratch.path()).await?),
+            None => None,
+        };
+
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| artifacts.default_name());
+        let ami_output = artifacts.file(&format!("-{AMI_DATA_FILE_SUFFIX}"));
+        let mut args = publisher.global_args();
+        args.push("ami".into());
+        args.extend(path_arg("--os-image", &os_image));
+        if let Some(data_image) = &data_image {
+            args.extend(path_arg("--data-image", data_image));
+        }
+        args.extend(path_arg("--variant-manifest", &variant_manifest));
+        args.extend(path_arg("--uefi-data", &uefi_data));
+        args.extend(os_args([
+            "--arch",
+            &artifacts.arch,
+            "--name",
+            &name,
+            "--description",
+            self.description.as_deref().unwrap_or(&name),
+        ]));
+        args.extend(path_arg("--ami-output", &ami_output));
+        if self.no_progress {
+            args.push("--no-progress".into());
+        }
+        args.extend(list_arg("--regions", &self.regions));
+        publisher.pubsys(args).await?;
+
+        symlink_file(&ami_output, &artifacts.variant_file(AMI_DATA_FILE_SUFFIX)).await?;
+        info!("Wrote AMI details to '{}'", ami_output.display());
+        Ok(())
+    }
+}
+
+impl PublishSsm {
+    pub(super) async fn run(&self) -> Result<()> {
+        let publisher = Publisher::new(&self.common).await?;
+        let artifacts = &publisher.artifacts;
+        let ami_input = artifacts
+            .require(&format!("-{AMI_DATA_FILE_SUFFIX}"))
+            .context("Please run `twoliter publish ami` first")?;
+        let template_path = publisher.ssm_template_path(self.template_path.as_ref())?;
+
+        let mut args = publisher.global_args();
+        args.push("ssm".into());
+        args.extend(path_arg("--ami-input", &ami_input));
+        args.extend(os_args([
+            "--arch",
+            &artifacts.arch,
+            "--variant",
+            &artifacts.variant,
+            "--version",
+            &artifacts.version_full,
+        ]));
+        args.extend(path_arg("--template-path", &template_path));
+        args.extend(path_arg(
+            "--ssm-parameter-output",
+            &artifacts.file(&format!("-{SSM_DATA_FILE_SUFFIX}")),
+        ));
+        args.extend(list_arg("--regions", &self.regions));
+        if self.allow_clobber {
+            args.push("--allow-clobber".into());
+        }
+        publisher.pubsys(args).await
+    }
+}
+
+impl PublishPromoteSsm {
+    pub(super) async fn run(&self) -> Result<()> {
+        ensure!(
+            !self.target.trim().is_empty(),
+            "The promotion target must not be empty"
+        );
+        let publisher = Publisher::new(&self.common).await?;
+        let artifacts = &publisher.artifacts;
+        let template_path = publisher.ssm_template_path(self.template_path.as_ref())?;
+        let source = self.source.as_deref().unwrap_or(&artifacts.version_full);
+
+        let mut args = publisher.global_args();
+        args.extend(os_args([
+            "promote-ssm",
+            "--arch",
+            &artifacts.arch,
+            "--variant",
+            &artifacts.variant,
+            "--source",
+            source,
+            "--target",
+            &self.target,
+        ]));
+        args.extend(path_arg("--template-path", &template_path));
+        args.extend(path_arg(
+            "--ssm-parameter-output",
+            &artifacts.file(&format!("-{SSM_DATA_FILE_SUFFIX}")),
+        ));
+        args.extend(list_arg("--regions", &self.regions));
+        publisher.pubsys(args).await
+    }
+}
+
+impl PublishOva {
+    pub(super) async fn run(&self) -> Result<()> {
+        let publisher = Publisher::new(&self.common).await?;
+        let artifacts = &publisher.artifacts;
+        let ova = artifacts.require(".ova")?;
+        let spec = self.import_spec_path.clone().unwrap_or_else(|| {
+            publisher
+                .project
+                .project_dir()
+                .join("tools/pubsys/support/vmware/import_spec.template")
+        });
+        require_file(&spec, "VMware import spec")?;
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| artifacts.default_name());
+
+        let mut args = publisher.global_args();
+        args.push("upload-ova".into());
+        args.extend(path_arg("--ova", &ova));
+        args.extend(path_arg("--spec", &spec));
+        args.extend(os_args(["--name", &name]));
+        if self.mark_as_template {
+            args.push("--mark-as-template".into());
+        }
+        args.extend(list_arg("--datacenters", &self.datacenters));
+        publisher.pubsys(args).await
+    }
+}
+
+/// Everything needed to run pubsys against the artifacts of a variant build.
+struct Publisher {
+    project: Project<Unlocked>,
+    toolsdir: PathBuf,
+    infra_config_path: PathBuf,
+    log_level: String,
+    artifacts: VariantArtifacts,
+}
+
+impl Publisher {
+    async fn new(args: &VariantPublishArgs) -> Result<Self> {
+        let project = project::load_or_find_project(args.project_path.clone()).await?;
+        let project_dir = project.project_dir();
+        let infra_config_path = args
+            .infra_config_path
+            .clone()
+            .unwrap_or_else(|| project_dir.join("Infra.toml"));
+        require_file(&infra_config_path, "infra config")?;
+        ensure!(
+            project_dir.join("variants").join(&args.variant).is_dir(),
+            "Variant '{}' does not exist in '{}'",
+            args.variant,
+            project_dir.join("variants").display()
+        );
+
+        let toolsdir = project_dir.join("build/tools");
+        install_tools(&toolsdir).await?;
+        let makefile_path = project_makefile(&project, &toolsdir).await?;
+
+        let artifacts = VariantArtifacts::find(
+            &project_dir,
+            &image_name(&makefile_path).await?,
+            &args.variant,
+            &args.arch,
+            project.release_version(),
+            args.build.as_deref(),
+        )
+        .await?;
+        debug!("Publishing artifacts from '{}'", artifacts.dir.display());
+
+        Ok(Self {
+            project,
+            toolsdir,
+            infra_config_path,
+            log_level: args.publish_log_level.clone(),
+            artifacts,
+        })
+    }
+
+    /// The arguments that pubsys and pubsys-setup expect before their subcommand.
+    fn global_args(&self) -> Vec<OsString> {
+        let mut args = os_args(["--log-level", &self.log_level]);
+        args.extend(path_arg("--infra-config-path", &self.infra_config_path));
+        args
+    }
+
+    fn ssm_template_path(&self, template_path: Option<&PathBuf>) -> Result<PathBuf> {
+        let template_path = template_path.cloned().unwrap_or_else(|| {
+            self.project
+                .project_dir()
+                .join("tools/pubsys/policies/ssm/defaults.toml")
+        });
+        require_file(&template_path, "SSM parameter templates")?;
+        Ok(template_path)
+    }
+
+    async fn pubsys(&self, args: Vec<OsString>) -> Result<()> {
+        exec_log(Command::new(self.toolsdir.join("pubsys")).args(args)).await
+    }
+}
+
+/// The name that the build gives to the project's images. As when building, `BUILDSYS_NAME` in the
+/// environment wins over the project's makefile, since Twoliter passes it to cargo-make.
+async fn image_name(makefile_path: &Path) -> Result<String> {
+    if let Ok(name) = std::env::var(IMAGE_NAME_VAR) {
+        return Ok(name);
+    }
+    Ok(env_value(makefile_path, IMAGE_NAME_VAR)
+        .await?
+        .unwrap_or_else(|| DEFAULT_IMAGE_NAME.to_string()))
+}
+
+/// The location and naming of the artifacts produced by a variant build, mirroring the names that
+/// the build tasks give them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VariantArtifacts {
+    /// The image name, `BUILDSYS_NAME`, that prefixes every artifact.
+    pub(crate) name: String,
+    pub(crate) variant: String,
+    pub(crate) arch: String,
+    pub(crate) version_image: String,
+    pub(crate) version_full: String,
+    pub(crate) dir: PathBuf,
+}
+
+impl VariantArtifacts {
+    /// Finds the artifacts of a variant build under `build/images`. When `version_full` is absent
+    /// the build pointed to by the `latest` link is used.
+    pub(crate) async fn find(
+        project_dir: &Path,
+        name: &str,
+        variant: &str,
+        arch: &str,
+        version_image: &str,
+        version_full: Option<&str>,
+    ) -> Result<Self> {
+        let output_dir = project_dir.join(format!("build/images/{arch}-{variant}"));
+        let version_full = match version_full {
+            Some(version_full) => version_full.to_string(),
+            None => {
+                let latest = output_dir.join("latest");
+                ensure!(
+                    latest.exists(),
+                    "No build of variant '{variant}' for '{arch}' was found in '{}', please run \
+                    `twoliter build variant {variant} --arch {arch}` first",
+                    output_dir.display()
+                );
+                read_link(&latest)
+                    .await?
+                    .file_name()
+                    .context(format!("Invalid link '{}'", latest.display()))?
+                    .to_string_lossy()
+                    .to_string()
+            }
+        };
+        ensure!(
+            version_full.starts_with(&format!("{version_image}-")),
+            "Build '{version_full}' of variant '{variant}' does not match the release version \
+            '{version_image}' in Twoliter.toml"
+        );
+
+        let dir = output_dir.join(&version_full);
+        ensure!(
+            dir.is_dir(),
+            "Build '{version_full}' of variant '{variant}' for '{arch}' does not exist at '{}'",
+            dir.display()
+        );
+        Ok(Self {
+            name: name.to_string(),
+            variant: variant.to_string(),
+            arch: arch.to_string(),
+            version_image: version_image.to_string(),
+            version_full,
+            dir,
+        })
+    }
+
+    /// The name shared by all artifacts of the variant, regardless of version.
+    pub(crate) fn name_variant(&self) -> String {
+        format!("{}-{}-{}", self.name, self.variant, self.arch)
+    }
+
+    /// The name given to artifacts of this specific build.
+    pub(crate) fn name_full(&self) -> String {
+        format!("{}-{}", self.name_variant(), self.version_full)
+    }
+
+    /// The name used for friendlier links to artifacts, without the build id.
+    pub(crate) fn name_friendly(&self) -> String {
+        format!("{}-v{}", self.name_variant(), self.version_image)
+    }
+
+    /// The default name for images registered in the cloud.
+    fn default_name(&self) -> String {
+        let build_id = self
+            .version_full
+            .strip_prefix(&format!("{}-", self.version_image))
+            .unwrap_or(&self.version_full);
+        format!("{}-v{}-{build_id}", self.name_variant(), self.version_image)
+    }
+
+    pub(crate) fn file(&self, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{suffix}", self.name_full()))
+    }
+
+    fn friendly_file(&self, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{suffix}", self.name_friendly()))
+    }
+
+    fn variant_file(&self, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}-{suffix}", self.name_variant()))
+    }
+
+    fn kmod_kit(&self) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}-kmod-kit-v{}.tar.xz",
+            self.variant, self.arch, self.version_image
+        ))
+    }
+
+    fn ova(&self) -> PathBuf {
+        self.dir.join(format!("{}.ova", self.name_friendly()))
+    }
+
+    fn require(&self, suffix: &str) -> Result<PathBuf> {
+        self.require_path(self.file(suffix))
+    }
+
+    fn require_path(&self, path: PathBuf) -> Result<PathBuf> {
+        ensure!(
+            path.metadata().map(|m| m.len() > 0).unwrap_or(false),
+            "Artifact '{}' does not exist for build '{}' of variant '{}'",
+            path.display(),
+            self.version_full,
+            self.variant
+        );
+        Ok(path)
+    }
+}
+
+fn require_file(path: &Path, description: &str) -> Result<()> {
+    ensure!(
+        path.is_file(),
+        "The {description} was not found at '{}'",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Decompresses an lz4 image into `dir`, returning the path of the raw image.
+async fn decompress(lz4: &Path, dir: &Path) -> Result<PathBuf> {
+    let name = lz4
+        .file_stem()
+        .context(format!("Invalid image path '{}'", lz4.display()))?;
+    let image = dir.join(name);
+    create_dir_all(dir).await?;
+    exec_log(Command::new("lz4").arg("-df").arg(lz4).arg(&image))
+        .await
+        .context(format!("Unable to decompress '{}'", lz4.display()))?;
+    Ok(image)
+}
+
+/// Points the `latest` link in `parent` at `target`.
+async fn symlink_latest(parent: &Path, target: &Path) -> Result<()> {
+    symlink_file(target, &parent.join("latest")).await
+}
+
+/// Creates or replaces a relative symlink at `link` pointing at `target`, which is a sibling.
+async fn symlink_file(target: &Path, link: &Path) -> Result<()> {
+    let name = target
+        .file_name()
+        .context(format!("Invalid path '{}'", target.display()))?;
+    if link.symlink_metadata().is_ok() {
+        remove_file(link).await?;
+    }
+    tokio::fs::symlink(name, link).await.context(format!(
+        "Unable to link '{}' to '{}'",
+        link.display(),
+        target.display()
+    ))
+}
+
+fn os_args<'a>(args: impl IntoIterator<Item = &'a str>) -> Vec<OsString> {
+    args.into_iter().map(OsString::from).collect()
+}
+
+fn path_arg(flag: &str, path: &Path) -> [OsString; 2] {
+    [flag.into(), path.into()]
+}
+
+fn list_arg(flag: &str, values: &[String]) -> Vec<OsString> {
+    if values.is_empty() {
+        Vec::new()
+    } else {
+        os_args([flag, &values.join(",")])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_publish_args() {
+        let ssm = PublishSsm::try_parse_from([
+            "ssm",
+            "--variant",
+            "aws-dev",
+            "--regions",
+            "us-west-2,us-east-1",
+            "--allow-clobber",
+        ])
+        .unwrap();
+        assert_eq!(ssm.common.arch, "x86_64");
+        assert_eq!(ssm.regions, vec!["us-west-2", "us-east-1"]);
+        assert!(ssm.allow_clobber);
+
+        assert!(
+            PublishRepo::try_parse_from(["repo", "--variant", "aws-dev", "--arch", "arm"]).is_err()
+        );
+        assert!(
+            PublishPromoteSsm::try_parse_from(["promote-ssm", "--variant", "aws-dev"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_variant_artifacts() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("build/images/aarch64-aws-dev");
+        create_dir_all(output_dir.join("1.2.0-abcdef01"))
+            .await
+            .unwrap();
+        tokio::fs::symlink("1.2.0-abcdef01", output_dir.join("latest"))
+            .await
+            .unwrap();
+
+        let artifacts = VariantArtifacts::find(
+            temp_dir.path(),
+            "bottlerocket",
+            "aws-dev",
+            "aarch64",
+            "1.2.0",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifacts.version_full, "1.2.0-abcdef01");
+        assert_eq!(
+            artifacts.file(".img.lz4"),
+            output_dir.join("1.2.0-abcdef01/bottlerocket-aws-dev-aarch64-1.2.0-abcdef01.img.lz4")
+        );
+        assert_eq!(
+            artifacts.default_name(),
+            "bottlerocket-aws-dev-aarch64-v1.2.0-abcdef01"
+        );
+        assert!(artifacts.require(".img.lz4").is_err());
+
+        // Projects that set their own image name find artifacts with that name.
+        let artifacts = VariantArtifacts::find(
+            temp_dir.path(),
+            "my-os",
+            "aws-dev",
+            "aarch64",
+            "1.2.0",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            artifacts.name_full(),
+            "my-os-aws-dev-aarch64-1.2.0-abcdef01"
+        );
+
+        // A build from a different release version is never published by accident.
+        assert!(VariantArtifacts::find(
+            temp_dir.path(),
+            "bottlerocket",
+            "aws-dev",
+            "aarch64",
+            "1.3.0",
+            None
+        )
+        .await
+        .is_err());
+        assert!(VariantArtifacts::find(
+            temp_dir.path(),
+            "bottlerocket",
+            "aws-dev",
+            "aarch64",
+            "1.2.0",
+            Some("1.2.0-00000000")
+        )
+        .await
+        .is_err());
+    }
+}
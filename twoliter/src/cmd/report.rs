@@ -0,0 +1,340 @@
+//! Machine-readable reports of what a command did, printed when Twoliter is run with
+//! `--output json`.
+//!
+//! A [`Report`] is filled in by a command as it runs and is printed as a single JSON document when
+//! the command finishes, whether it succeeded or not. Failures carry an [`ErrorCode`] so that CI
+//! pipelines can tell them apart without matching on error messages, which may change at any time.
+use super::build_reproducible::Reproducibility;
+use crate::build_events::BuildStep;
+use crate::build_timing::BuildTiming;
+use crate::project::{Locked, LockedImage, Project};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The format of the output that Twoliter prints when a command finishes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Human readable text and logs
+    #[default]
+    Text,
+    /// A single JSON document describing the outcome of the command
+    Json,
+}
+
+/// Identifies the kind of failure in a report. The serialized names are stable and must not be
+/// changed once released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ErrorCode {
+    /// Twoliter.toml could not be found, read or validated
+    ProjectLoad,
+    /// Twoliter.lock could not be resolved, or did not match Twoliter.toml
+    Lock,
+    /// External kits could not be fetched
+    Fetch,
+    /// Twoliter's tools could not be installed
+    Tools,
+    /// A build failed
+    Build,
+    /// Two builds of the same thing produced different outputs
+    NotReproducible,
+    /// Publishing failed
+    Publish,
+    /// The failure was not classified
+    Unknown,
+}
+
+/// Wraps an error to record its [`ErrorCode`] without changing how the error is displayed.
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    inner: anyhow::Error,
+}
+
+impl Display for CodedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.inner, f)
+    }
+}
+
+impl std::error::Error for CodedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+/// Attaches an [`ErrorCode`] to the error of a `Result`. The first code attached wins, so the code
+/// reflects the most specific step that failed.
+pub(crate) trait WithErrorCode<T> {
+    fn error_code(self, code: ErrorCode) -> Result<T>;
+}
+
+impl<T> WithErrorCode<T> for Result<T> {
+    fn error_code(self, code: ErrorCode) -> Result<T> {
+        self.map_err(|inner| {
+            if inner.is::<CodedError>() {
+                inner
+            } else {
+                CodedError { code, inner }.into()
+            }
+        })
+    }
+}
+
+impl ErrorCode {
+    fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<CodedError>()
+            .map(|e| e.code)
+            .unwrap_or(ErrorCode::Unknown)
+    }
+}
+
+/// The final report of a command.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Report {
+    command: String,
+    success: bool,
+    duration_secs: f64,
+    images: Vec<ReportedImage>,
+    artifacts: Vec<ReportedArtifact>,
+    /// The event log that buildsys wrote to, if anything was built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_events: Option<PathBuf>,
+    /// The package, kit and variant builds recorded in `build_events`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    build_steps: Vec<BuildStep>,
+    /// Where the time in `build_steps` went
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_timing: Option<BuildTiming>,
+    /// The comparison of two builds, for `build verify-reproducible`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reproducibility: Option<Reproducibility>,
+    error: Option<ReportedError>,
+    #[serde(skip)]
+    format: OutputFormat,
+    #[serde(skip)]
+    start: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ReportedImage {
+    /// Either "sdk" or "kit"
+    role: &'static str,
+    name: String,
+    version: String,
+    vendor: String,
+    source: String,
+    digest: String,
+}
+
+/// Something a command produced, such as a kit or variant build or a published image.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ReportedArtifact {
+    kind: ArtifactKind,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    /// For published artifacts, the vendor, repository and tag they were published as, e.g.
+    /// `my-vendor/core-kit:v1.0.0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<f64>,
+    /// Set when producing this artifact failed but the command carried on with others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ReportedError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ArtifactKind {
+    ExternalKits,
+    Package,
+    Kit,
+    Variant,
+    PublishedKit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ReportedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl ReportedError {
+    pub(crate) fn from_error(error: &anyhow::Error) -> Self {
+        Self {
+            code: ErrorCode::of(error),
+            message: format!("{error:#}"),
+        }
+    }
+}
+
+impl ReportedArtifact {
+    pub(crate) fn new(kind: ArtifactKind, name: impl Into<String>) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+            arch: None,
+            path: None,
+            image: None,
+            duration_secs: None,
+            error: None,
+        }
+    }
+
+    pub(crate) fn arch(mut self, arch: impl Into<String>) -> Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
+    pub(crate) fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub(crate) fn image(mut self, image: impl Into<String>) -> Self {
+        self.image = Some(image.into());
+        self
+    }
+
+    pub(crate) fn duration(mut self, duration: Duration) -> Self {
+        self.duration_secs = Some(duration.as_secs_f64());
+        self
+    }
+
+    pub(crate) fn result(mut self, result: &Result<()>) -> Self {
+        self.error = result.as_ref().err().map(ReportedError::from_error);
+        self
+    }
+}
+
+impl From<(&'static str, &LockedImage)> for ReportedImage {
+    fn from((role, image): (&'static str, &LockedImage)) -> Self {
+        Self {
+            role,
+            name: image.name.to_string(),
+            version: image.version.to_string(),
+            vendor: image.vendor.to_string(),
+            source: image.source.clone(),
+            digest: image.digest.clone(),
+        }
+    }
+}
+
+impl Report {
+    pub(crate) fn new(command: impl Into<String>, format: OutputFormat) -> Self {
+        Self {
+            command: command.into(),
+            success: false,
+            duration_secs: 0.0,
+            images: Vec::new(),
+            artifacts: Vec::new(),
+            build_events: None,
+            build_steps: Vec::new(),
+            build_timing: None,
+            reproducibility: None,
+            error: None,
+            format,
+            start: Instant::now(),
+        }
+    }
+
+    /// Whether the command should print its own human readable summaries. When the report is
+    /// printed instead, stdout must hold nothing else.
+    pub(crate) fn human_readable(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// Records the SDK and kits that a project resolved to. A command that locks several projects
+    /// records each image once.
+    pub(crate) fn locked_images(&mut self, project: &Project<Locked>) {
+        let images = std::iter::once(("sdk", project.locked_sdk()))
+            .chain(project.locked_kits().iter().map(|kit| ("kit", kit)))
+            .map(ReportedImage::from);
+        for image in images {
+            if !self.images.contains(&image) {
+                self.images.push(image);
+            }
+        }
+    }
+
+    pub(crate) fn artifact(&mut self, artifact: ReportedArtifact) {
+        self.artifacts.push(artifact);
+    }
+
+    /// Records the builds that buildsys logged while running the command.
+    pub(crate) fn build_steps(&mut self, events: &Path, steps: Vec<BuildStep>) {
+        self.build_events = Some(events.to_path_buf());
+        self.build_timing = BuildTiming::new(&steps);
+        self.build_steps = steps;
+    }
+
+    pub(crate) fn build_timing(&self) -> Option<&BuildTiming> {
+        self.build_timing.as_ref()
+    }
+
+    pub(crate) fn reproducibility(&mut self, reproducibility: Reproducibility) {
+        self.reproducibility = Some(reproducibility);
+    }
+
+    /// Records the outcome of the command.
+    pub(crate) fn finish(&mut self, result: &Result<()>) {
+        self.success = result.is_ok();
+        self.duration_secs = self.start.elapsed().as_secs_f64();
+        self.error = result.as_ref().err().map(ReportedError::from_error);
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_error_code_keeps_message() {
+        let result: Result<()> = Err(anyhow!("root cause"))
+            .context("while building")
+            .error_code(ErrorCode::Build)
+            .error_code(ErrorCode::Unknown);
+        let error = result.unwrap_err();
+        assert_eq!(ErrorCode::of(&error), ErrorCode::Build);
+        assert_eq!(format!("{error:#}"), "while building: root cause");
+        assert_eq!(ErrorCode::of(&anyhow!("uncoded")), ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut report = Report::new("build kit", OutputFormat::Json);
+        report.artifact(
+            ReportedArtifact::new(ArtifactKind::Kit, "core-kit")
+                .arch("x86_64")
+                .path("/project/build/kits/core-kit"),
+        );
+        report.finish(&Err(anyhow!("boom")).error_code(ErrorCode::Tools));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["command"], "build kit");
+        assert_eq!(json["success"], false);
+        assert_eq!(json["artifacts"][0]["kind"], "kit");
+        assert_eq!(json["artifacts"][0]["path"], "/project/build/kits/core-kit");
+        assert!(json["artifacts"][0].get("image").is_none());
+        assert_eq!(json["error"]["code"], "tools");
+        assert_eq!(json["error"]["message"], "boom");
+        assert_eq!(json["images"], serde_json::json!([]));
+    }
+}
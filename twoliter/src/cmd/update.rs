@@ -1,3 +1,4 @@
+use crate::cmd::report::{ErrorCode, Report, WithErrorCode};
 use crate::project;
 use anyhow::Result;
 use clap::Parser;
@@ -11,9 +12,12 @@ pub(crate) struct Update {
 }
 
 impl Update {
-    pub(super) async fn run(&self) -> Result<()> {
-        let project = project::load_or_find_project(self.project_path.clone()).await?;
-        project.create_lock().await?;
+    pub(super) async fn run(&self, report: &mut Report) -> Result<()> {
+        let project = project::load_or_find_project(self.project_path.clone())
+            .await
+            .error_code(ErrorCode::ProjectLoad)?;
+        let project = project.create_lock().await.error_code(ErrorCode::Lock)?;
+        report.locked_images(&project);
         Ok(())
     }
 }
@@ -1,5 +1,6 @@
 use anyhow::{ensure, Context, Result};
 use log::{self, LevelFilter};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::process::Command;
 use tracing::{debug, instrument};
 
@@ -9,6 +10,15 @@ use tracing::{debug, instrument};
 /// Twoliter.
 pub(crate) const BUILDSYS_OUTPUT_GENERATION_ID: u32 = 1;
 
+/// Whether stdout is reserved for Twoliter's own machine-readable output.
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Reserves stdout for Twoliter's own output, such as a JSON report. Commands run afterwards will
+/// have their stdout sent to stderr so that they cannot interleave with it.
+pub(crate) fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::SeqCst);
+}
+
 /// Run a `tokio::process::Command` and return a `Result` letting us know whether or not it worked.
 /// Pipes stdout/stderr when logging `LevelFilter` is more verbose than `Warn`.
 #[instrument(level = "trace", skip(cmd))]
@@ -47,6 +57,9 @@ pub(crate) async fn exec(cmd: &mut Command, quiet: bool) -> Result<Option<String
         )
     } else {
         // For less quiet log levels we stream to stdout and stderr.
+        if STDOUT_RESERVED.load(Ordering::SeqCst) {
+            cmd.stdout(std::io::stderr());
+        }
         let status = cmd
             .status()
             .await
@@ -136,6 +149,14 @@ pub(crate) mod fs {
             .context(format!("Unable to read from '{}'", path.as_ref().display()))
     }
 
+    #[instrument(level = "trace", skip(path), fields(path = %path.as_ref().display()))]
+    pub(crate) async fn read_link(path: impl AsRef<Path>) -> Result<PathBuf> {
+        fs::read_link(path.as_ref()).await.context(format!(
+            "Unable to read the link '{}'",
+            path.as_ref().display()
+        ))
+    }
+
     #[instrument(level = "trace", skip(path), fields(path = %path.as_ref().display()))]
     pub(crate) async fn read_to_string(path: impl AsRef<Path>) -> Result<String> {
         fs::read_to_string(path.as_ref()).await.context(format!(
@@ -192,6 +213,21 @@ pub(crate) mod fs {
         ))
     }
 
+    #[instrument(
+        level = "trace",
+        skip_all,
+        fields(original = %original.as_ref().display(), link = %link.as_ref().display())
+    )]
+    pub(crate) async fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+        let original = original.as_ref();
+        let link = link.as_ref();
+        fs::symlink(original, link).await.context(format!(
+            "Unable to create a link from '{}' to '{}'",
+            link.display(),
+            original.display()
+        ))
+    }
+
     #[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()))]
     pub(crate) async fn write<P, C>(path: P, contents: C) -> Result<()>
     where
@@ -2,11 +2,14 @@ use crate::cmd::{init_logger, Args};
 use anyhow::Result;
 use clap::Parser;
 
+mod build_events;
+mod build_timing;
 mod cargo_make;
 mod cmd;
 mod common;
 mod compatibility;
 mod docker;
+mod makefile;
 mod project;
 mod schema_version;
 /// Test code that should only be compiled when running tests.
@@ -0,0 +1,511 @@
+//! Projects can add tasks to Twoliter's embedded `Makefile.toml`, or replace some of its tasks and
+//! environment variables, with an extension makefile named in `Twoliter.toml`:
+//!
+//! ```toml
+//! [makefile-extension]
+//! path = "Makefile.extension.toml"
+//! override-tasks = ["check-licenses"]
+//! override-env = ["BUILDSYS_NAME"]
+//! ```
+//!
+//! The extension is copied to `build/Makefile.project.toml` with cargo-make's `extend` pointed at
+//! the embedded makefile, and that is the makefile that `cargo make` runs. It is copied as written,
+//! since cargo-make evaluates environment variables in the order they appear. cargo-make merges a
+//! task that is defined in both makefiles field by field, with the extension's fields winning.
+//!
+//! Replacing a task or environment variable by accident would quietly change how the project is
+//! built, so anything that the extension redefines must be listed in `override-tasks` or
+//! `override-env`. Anything else that it redefines is reported as a conflict.
+//!
+//! Tasks declare how much of `Twoliter.lock` must be verified before they can run with a
+//! `twoliter-verify` key of `none`, `sdk` or `kits`. A task needs the most verification that it or
+//! any task it depends on declares, so a task that depends on `validate-kits` needs `kits`.
+use crate::common::fs;
+use crate::project::{Project, ProjectLock};
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use tracing::{debug, info, warn};
+
+/// The name of the embedded makefile in the tools directory.
+const EMBEDDED_MAKEFILE: &str = "Makefile.toml";
+
+/// Where the extension, pointed at the embedded makefile, is written in the project.
+const PROJECT_MAKEFILE: &str = "build/Makefile.project.toml";
+
+/// The key in a task that declares the verification it needs.
+const VERIFY_KEY: &str = "twoliter-verify";
+
+/// How much of `Twoliter.lock` must be verified before a task can run. Ordered from the least
+/// verification to the most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Verification {
+    /// Nothing is verified. The task must not use the SDK or any kit.
+    None,
+    /// Only the SDK is verified.
+    Sdk,
+    /// The SDK and every kit are verified.
+    Kits,
+}
+
+/// The `makefile-extension` table of `Twoliter.toml`.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MakefileExtension {
+    /// The extension makefile, relative to the project directory.
+    pub(crate) path: PathBuf,
+    /// Tasks of the embedded makefile that the extension is allowed to redefine.
+    #[serde(default)]
+    pub(crate) override_tasks: BTreeSet<String>,
+    /// Environment variables of the embedded makefile that the extension is allowed to redefine.
+    #[serde(default)]
+    pub(crate) override_env: BTreeSet<String>,
+}
+
+/// Returns the makefile to run for the project. This is the embedded makefile installed in
+/// `tools_dir`, unless the project has an extension, in which case it is the extension merged with
+/// the embedded makefile.
+pub(crate) async fn project_makefile<L: ProjectLock>(
+    project: &Project<L>,
+    tools_dir: &Path,
+) -> Result<PathBuf> {
+    let embedded_path = tools_dir.join(EMBEDDED_MAKEFILE);
+    let Some(extension) = project.makefile_extension() else {
+        return Ok(embedded_path);
+    };
+    let extension_path = project.project_dir().join(&extension.path);
+    let embedded = fs::read_to_string(&embedded_path).await?;
+    let embedded: Table = toml::from_str(&embedded).context(format!(
+        "Unable to parse makefile '{}'",
+        embedded_path.display()
+    ))?;
+    let extension_makefile = fs::read_to_string(&extension_path).await?;
+
+    let merged = extension
+        .merge(&embedded, &extension_makefile, &embedded_path)
+        .context(format!(
+            "Unable to extend Twoliter's Makefile.toml with '{}'",
+            extension_path.display()
+        ))?;
+
+    let merged_path = project.project_dir().join(PROJECT_MAKEFILE);
+    if let Some(parent) = merged_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&merged_path, merged).await?;
+    debug!(
+        "Extended Twoliter's Makefile.toml with '{}' in '{}'",
+        extension_path.display(),
+        merged_path.display()
+    );
+    Ok(merged_path)
+}
+
+impl MakefileExtension {
+    /// Checks the extension against the embedded makefile, and points it at the embedded makefile
+    /// with cargo-make's `extend`.
+    fn merge(&self, embedded: &Table, contents: &str, embedded_path: &Path) -> Result<String> {
+        let extension: Table = toml::from_str(contents).context("Unable to parse the extension")?;
+        let extends = extension.contains_key("extend")
+            || extension
+                .get("config")
+                .and_then(Value::as_table)
+                .is_some_and(|config| config.contains_key("extend"));
+        ensure!(
+            !extends,
+            "The extension must not set 'extend', since Twoliter extends its own Makefile.toml with it"
+        );
+
+        let conflicts = Conflicts::find(embedded, &extension);
+        let unexpected = conflicts.excluding(&self.override_tasks, &self.override_env);
+        ensure!(
+            unexpected.is_empty(),
+            "The extension redefines parts of Twoliter's Makefile.toml that are not listed in the \
+             'override-tasks' or 'override-env' of the 'makefile-extension' in Twoliter.toml:\n\
+             {unexpected}"
+        );
+        for task in &conflicts.tasks {
+            info!("The makefile extension overrides task '{task}'");
+        }
+        for env in &conflicts.env {
+            info!("The makefile extension overrides environment variable '{env}'");
+        }
+        let unused = Conflicts {
+            tasks: self.override_tasks.clone(),
+            env: self.override_env.clone(),
+        }
+        .excluding(&conflicts.tasks, &conflicts.env);
+        if !unused.is_empty() {
+            warn!("The makefile extension is allowed to override these, but does not:\n{unused}");
+        }
+
+        // Keys at the top level must come before any table, so the extension can follow `extend`.
+        let extend = Value::String(embedded_path.display().to_string());
+        Ok(format!(
+            "# Generated by Twoliter from the makefile extension in Twoliter.toml. Do not edit.\n\
+             extend = {extend}\n\n{contents}"
+        ))
+    }
+}
+
+/// The tasks and environment variables that two makefiles both define.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Conflicts {
+    tasks: BTreeSet<String>,
+    env: BTreeSet<String>,
+}
+
+impl Conflicts {
+    fn find(embedded: &Table, extension: &Table) -> Self {
+        Self {
+            tasks: common_keys(embedded, extension, "tasks"),
+            env: common_keys(embedded, extension, "env"),
+        }
+    }
+
+    fn excluding(&self, tasks: &BTreeSet<String>, env: &BTreeSet<String>) -> Self {
+        Self {
+            tasks: self.tasks.difference(tasks).cloned().collect(),
+            env: self.env.difference(env).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty() && self.env.is_empty()
+    }
+}
+
+fn common_keys(a: &Table, b: &Table, table: &str) -> BTreeSet<String> {
+    let keys = |makefile: &Table| {
+        makefile
+            .get(table)
+            .and_then(Value::as_table)
+            .map(|table| table.keys().cloned().collect::<BTreeSet<_>>())
+            .unwrap_or_default()
+    };
+    keys(a).intersection(&keys(b)).cloned().collect()
+}
+
+impl Display for Conflicts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .tasks
+            .iter()
+            .map(|task| format!("  task '{task}'"))
+            .chain(self.env.iter().map(|env| format!("  env '{env}'")))
+            .collect::<Vec<_>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Returns the verification that `task` in `makefile` needs, or `None` if neither the task nor
+/// anything it depends on declares any.
+pub(crate) async fn task_verification(makefile: &Path, task: &str) -> Result<Option<Verification>> {
+    Makefiles::load(makefile).await?.verification(task)
+}
+
+/// Returns the value that `makefile`, or a makefile it extends, gives the environment variable
+/// `name`, or `None` if none of them set it. Only a plain string can be known without running
+/// cargo-make, so a value that refers to other variables or is computed by a script is an error.
+pub(crate) async fn env_value(makefile: &Path, name: &str) -> Result<Option<String>> {
+    let makefiles = Makefiles::load(makefile).await?;
+    let Some(value) = makefiles.env_field(name) else {
+        return Ok(None);
+    };
+    match value.as_str() {
+        Some(value) if !value.contains("${") => Ok(Some(value.to_string())),
+        _ => bail!(
+            "The value of '{name}' in '{}' can only be known by running cargo-make, please set \
+             '{name}' in the environment instead",
+            makefile.display()
+        ),
+    }
+}
+
+/// A makefile and the makefiles it extends, in the order that cargo-make gives them precedence.
+struct Makefiles(Vec<Table>);
+
+impl Makefiles {
+    async fn load(path: &Path) -> Result<Self> {
+        let mut makefiles = Vec::new();
+        let mut next = Some(path.to_path_buf());
+        while let Some(path) = next.take() {
+            let contents = fs::read_to_string(&path).await?;
+            let makefile: Table = toml::from_str(&contents)
+                .context(format!("Unable to parse makefile '{}'", path.display()))?;
+            // Only Twoliter's own use of `extend`, a single path, is followed.
+            if let Some(extend) = makefile.get("extend").and_then(Value::as_str) {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                next = Some(dir.join(extend));
+            }
+            makefiles.push(makefile);
+        }
+        Ok(Self(makefiles))
+    }
+
+    /// A field of a task, from the first makefile that sets it.
+    fn task_field(&self, task: &str, field: &str) -> Option<&Value> {
+        self.0.iter().find_map(|makefile| {
+            makefile
+                .get("tasks")
+                .and_then(|tasks| tasks.get(task))
+                .and_then(|task| task.get(field))
+        })
+    }
+
+    /// An environment variable, from the first makefile that sets it.
+    fn env_field(&self, name: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find_map(|makefile| makefile.get("env").and_then(|env| env.get(name)))
+    }
+
+    fn verification(&self, task: &str) -> Result<Option<Verification>> {
+        let mut visited = HashSet::new();
+        self.visit(task, &mut visited)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        task: &'a str,
+        visited: &mut HashSet<&'a str>,
+    ) -> Result<Option<Verification>> {
+        if !visited.insert(task) {
+            return Ok(None);
+        }
+        let mut verification = match self.task_field(task, VERIFY_KEY) {
+            Some(value) => Some(value.clone().try_into::<Verification>().context(format!(
+                "Task '{task}' has an invalid '{VERIFY_KEY}', expected 'none', 'sdk' or 'kits'"
+            ))?),
+            None => None,
+        };
+
+        let alias = self.task_field(task, "alias").and_then(Value::as_str);
+        for dependency in alias.into_iter().chain(self.dependencies(task)?) {
+            verification = verification.max(self.visit(dependency, visited)?);
+        }
+        Ok(verification)
+    }
+
+    /// The names of the tasks that `task` depends on. cargo-make allows each to be a task name or
+    /// a table naming the task.
+    fn dependencies(&self, task: &str) -> Result<Vec<&str>> {
+        let Some(dependencies) = self.task_field(task, "dependencies") else {
+            return Ok(Vec::new());
+        };
+        let Some(dependencies) = dependencies.as_array() else {
+            bail!("The dependencies of task '{task}' are not an array");
+        };
+        dependencies
+            .iter()
+            .map(|dependency| {
+                dependency
+                    .as_str()
+                    .or_else(|| dependency.get("name").and_then(Value::as_str))
+                    .context(format!("Task '{task}' has a dependency without a name"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EMBEDDED: &str = r#"
+[env]
+BUILDSYS_NAME = "bottlerocket"
+BUILDSYS_ARCH = "x86_64"
+
+[tasks.build]
+dependencies = ["build-variant"]
+
+[tasks.check-licenses]
+script = ["cargo deny check"]
+"#;
+
+    fn extension(overrides: &[&str]) -> MakefileExtension {
+        MakefileExtension {
+            path: PathBuf::from("Makefile.extension.toml"),
+            override_tasks: overrides.iter().map(|s| s.to_string()).collect(),
+            override_env: ["BUILDSYS_NAME".to_string()].into(),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let embedded: Table = toml::from_str(EMBEDDED).unwrap();
+        let makefile = r#"
+[env]
+MY_VAR = "1"
+BUILDSYS_NAME = "my-os-${MY_VAR}"
+
+[tasks.check-licenses]
+script = ["true"]
+
+[tasks.lint]
+script = ["shellcheck tools/*"]
+"#;
+
+        let merged = extension(&["check-licenses"])
+            .merge(
+                &embedded,
+                makefile,
+                Path::new("/project/build/tools/Makefile.toml"),
+            )
+            .unwrap();
+        assert!(merged.ends_with(makefile));
+        let merged: Table = toml::from_str(&merged).unwrap();
+        assert_eq!(
+            merged["extend"].as_str(),
+            Some("/project/build/tools/Makefile.toml")
+        );
+        assert!(merged["tasks"].get("lint").is_some());
+
+        let err = extension(&[])
+            .merge(&embedded, makefile, Path::new("Makefile.toml"))
+            .unwrap_err();
+        assert!(err.to_string().contains("task 'check-licenses'"));
+        assert!(!err.to_string().contains("BUILDSYS_NAME"));
+    }
+
+    #[test]
+    fn test_merge_rejects_extend() {
+        let embedded: Table = toml::from_str(EMBEDDED).unwrap();
+        for makefile in [
+            "[config]\nextend = \"other.toml\"\n",
+            "extend = \"other.toml\"\n",
+        ] {
+            assert!(extension(&[])
+                .merge(&embedded, makefile, Path::new("Makefile.toml"))
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_task_verification() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let embedded = temp_dir.path().join("tools/Makefile.toml");
+        fs::create_dir_all(embedded.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(
+            &embedded,
+            r#"
+[tasks.fetch-sdk]
+twoliter-verify = "sdk"
+
+[tasks.validate-kits]
+twoliter-verify = "kits"
+
+[tasks.build-variant]
+dependencies = ["fetch-sdk", { name = "validate-kits" }]
+
+[tasks.build]
+dependencies = ["build-variant"]
+
+[tasks.default]
+alias = "build"
+
+[tasks.repack-variant]
+dependencies = ["fetch-sdk"]
+
+[tasks.loop-a]
+dependencies = ["loop-b"]
+
+[tasks.loop-b]
+dependencies = ["loop-a"]
+"#,
+        )
+        .await
+        .unwrap();
+        let project = temp_dir.path().join("Makefile.project.toml");
+        fs::write(
+            &project,
+            r#"extend = "tools/Makefile.toml"
+
+[tasks.repack-variant]
+twoliter-verify = "none"
+
+[tasks.my-build]
+dependencies = ["build"]
+
+[tasks.my-lint]
+twoliter-verify = "none"
+"#,
+        )
+        .await
+        .unwrap();
+
+        let verification = |task: &'static str| {
+            let project = project.clone();
+            async move { task_verification(&project, task).await.unwrap() }
+        };
+        assert_eq!(verification("build").await, Some(Verification::Kits));
+        assert_eq!(verification("default").await, Some(Verification::Kits));
+        assert_eq!(verification("my-build").await, Some(Verification::Kits));
+        // The extension's declaration does not hide what the task's dependencies need.
+        assert_eq!(
+            verification("repack-variant").await,
+            Some(Verification::Sdk)
+        );
+        assert_eq!(verification("my-lint").await, Some(Verification::None));
+        assert_eq!(verification("loop-a").await, None);
+        assert_eq!(verification("no-such-task").await, None);
+
+        fs::write(&project, "[tasks.bad]\ntwoliter-verify = \"all\"\n")
+            .await
+            .unwrap();
+        assert!(task_verification(&project, "bad").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_env_value() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let embedded = temp_dir.path().join("Makefile.toml");
+        fs::write(&embedded, EMBEDDED).await.unwrap();
+        let project = temp_dir.path().join("Makefile.project.toml");
+        fs::write(
+            &project,
+            "extend = \"Makefile.toml\"\n[env]\nBUILDSYS_NAME = \"my-os\"\nMY_VAR = \"${BUILDSYS_NAME}-1\"\n",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            env_value(&embedded, "BUILDSYS_NAME").await.unwrap(),
+            Some("bottlerocket".to_string())
+        );
+        assert_eq!(
+            env_value(&project, "BUILDSYS_NAME").await.unwrap(),
+            Some("my-os".to_string())
+        );
+        assert_eq!(
+            env_value(&project, "BUILDSYS_ARCH").await.unwrap(),
+            Some("x86_64".to_string())
+        );
+        assert_eq!(env_value(&project, "NO_SUCH_VAR").await.unwrap(), None);
+        assert!(env_value(&project, "MY_VAR").await.is_err());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let embedded: Table = toml::from_str(EMBEDDED).unwrap();
+        let makefile: Table =
+            toml::from_str("[env]\nBUILDSYS_ARCH = \"aarch64\"\n[tasks.build]\n").unwrap();
+        let conflicts = Conflicts::find(&embedded, &makefile);
+        assert_eq!(conflicts.tasks, ["build".to_string()].into());
+        assert_eq!(conflicts.env, ["BUILDSYS_ARCH".to_string()].into());
+        assert_eq!(
+            conflicts.to_string(),
+            "  task 'build'\n  env 'BUILDSYS_ARCH'"
+        );
+        assert!(conflicts
+            .excluding(&conflicts.tasks, &conflicts.env)
+            .is_empty());
+    }
+}
@@ -2,16 +2,42 @@ use super::views::{IndexView, ManifestLayoutView};
 use crate::common::fs::{create_dir_all, read, read_to_string, remove_dir_all, write};
 use anyhow::{Context, Result};
 use oci_cli_wrapper::ImageTool;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use tar::Archive as TarArchive;
 use tracing::{debug, instrument, trace};
 
+/// A record written alongside each archive in the cache.
+///
+/// The record notes which locked images the archive was pulled for, so that the cache can be pruned
+/// against lock files. It is rewritten whenever the archive is used, so its modification time
+/// tracks when the archive was last needed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ArchiveRecord {
+    /// The digest-qualified uri the archive was pulled from
+    pub uri: String,
+    /// The digests of the locked images that referred to this archive. Several lock files may
+    /// refer to the same archive, through different locked images.
+    #[serde(default)]
+    pub locked_digests: BTreeSet<String>,
+}
+
+impl ArchiveRecord {
+    /// Returns the path of the record for the archive stored at `archive_path`.
+    pub(crate) fn path_for(archive_path: &Path) -> PathBuf {
+        archive_path.with_extension("json")
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct OCIArchive {
     registry: String,
     repository: String,
     digest: String,
+    locked_digest: Option<String>,
     cache_dir: PathBuf,
 }
 
@@ -24,10 +50,18 @@ impl OCIArchive {
             registry: registry.into(),
             repository: repository.into(),
             digest: digest.into(),
+            locked_digest: None,
             cache_dir: cache_dir.as_ref().to_path_buf(),
         })
     }
 
+    /// Notes the digest of the locked image this archive belongs to, so that the cache can tell
+    /// which lock files still refer to it.
+    pub fn locked_by(mut self, locked_digest: &str) -> Self {
+        self.locked_digest = Some(locked_digest.into());
+        self
+    }
+
     pub fn archive_path(&self) -> PathBuf {
         self.cache_dir.join(self.digest.replace(':', "-"))
     }
@@ -52,7 +86,32 @@ impl OCIArchive {
                 digest_uri
             );
         }
-        Ok(())
+        self.record_use().await
+    }
+
+    /// Writes the archive's record to the cache, which also marks the archive as recently used.
+    /// The locked digests of any earlier record are kept, since those locks may still refer to
+    /// the archive.
+    async fn record_use(&self) -> Result<()> {
+        let record_path = ArchiveRecord::path_for(&self.archive_path());
+        let mut locked_digests = if record_path.is_file() {
+            serde_json::from_slice::<ArchiveRecord>(&read(&record_path).await?)
+                .map(|record| record.locked_digests)
+                .unwrap_or_default()
+        } else {
+            BTreeSet::new()
+        };
+        locked_digests.extend(self.locked_digest.clone());
+        let record = ArchiveRecord {
+            uri: self.uri(),
+            locked_digests,
+        };
+        let record_json =
+            serde_json::to_vec(&record).context("failed to serialize oci archive record")?;
+        write(&record_path, record_json).await.context(format!(
+            "failed to record use of oci archive at {}",
+            record_path.display()
+        ))
     }
 
     #[instrument(
@@ -124,3 +183,28 @@ impl OCIArchive {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_keeps_every_locked_digest() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let archive = |locked_digest: &str| {
+            OCIArchive::new("example.com", "kit", "sha256:abc", cache_dir.path())
+                .unwrap()
+                .locked_by(locked_digest)
+        };
+        archive("lock-a").record_use().await.unwrap();
+        archive("lock-b").record_use().await.unwrap();
+
+        let record_path = ArchiveRecord::path_for(&archive("lock-a").archive_path());
+        let record: ArchiveRecord =
+            serde_json::from_slice(&read(&record_path).await.unwrap()).unwrap();
+        assert_eq!(
+            record.locked_digests,
+            BTreeSet::from(["lock-a".to_string(), "lock-b".to_string()])
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use super::archive::ArchiveRecord;
+use super::{Lock, TWOLITER_LOCK};
+use crate::common::fs::{metadata, read, remove_dir_all, remove_file};
+use anyhow::{Context, Result};
+use async_walkdir::{Filtering, WalkDir};
+use futures::StreamExt;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, instrument, warn};
+
+/// The name of the directory, within the external kits directory, where OCI archives are cached.
+pub(crate) const ARCHIVE_CACHE_DIR: &str = "cache";
+
+/// Directories which are never searched when looking for lock files.
+const SKIPPED_LOCK_SEARCH_DIRS: &[&str] = &["build", "target", ".cargo", ".gomodcache", ".git"];
+
+/// An OCI archive found in the cache.
+#[derive(Debug, Clone)]
+pub(crate) struct CachedArchive {
+    /// The directory holding the archive
+    pub path: PathBuf,
+    /// The digest of the image manifest the archive was pulled from
+    pub digest: String,
+    /// The uri the archive was pulled from, if it is known
+    pub uri: Option<String>,
+    /// The digests of the locked images that referred to this archive, or `None` if the archive
+    /// has no record of them, such as when it was pulled by an older version of Twoliter
+    pub locked_digests: Option<BTreeSet<String>>,
+    /// The size of the archive on disk, in bytes
+    pub size: u64,
+    /// When the archive was last pulled or reused
+    pub last_used: SystemTime,
+}
+
+impl CachedArchive {
+    /// Whether any of the given locked image digests refer to this archive. An archive without a
+    /// record may be referenced by any of them, since its directory is named after the digest of
+    /// the manifest for one architecture rather than the locked digest.
+    pub(crate) fn may_be_referenced_by(&self, locked_digests: &HashSet<String>) -> bool {
+        self.locked_digests.as_ref().map_or(true, |digests| {
+            digests.iter().any(|digest| locked_digests.contains(digest))
+        })
+    }
+}
+
+/// The cache of OCI archives which `twoliter fetch` pulls external kits into.
+#[derive(Debug, Clone)]
+pub(crate) struct ArchiveCache {
+    dir: PathBuf,
+}
+
+impl ArchiveCache {
+    pub(crate) fn new<P: AsRef<Path>>(external_kits_dir: P) -> Self {
+        Self {
+            dir: external_kits_dir.as_ref().join(ARCHIVE_CACHE_DIR),
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Lists the archives in the cache, most recently used first.
+    #[instrument(level = "trace", skip(self), fields(dir = %self.dir.display()))]
+    pub(crate) async fn list(&self) -> Result<Vec<CachedArchive>> {
+        let mut archives = Vec::new();
+        if !self.dir.is_dir() {
+            debug!("No archive cache found at '{}'", self.dir.display());
+            return Ok(archives);
+        }
+
+        let mut entries = tokio::fs::read_dir(&self.dir).await.context(format!(
+            "failed to read archive cache at '{}'",
+            self.dir.display()
+        ))?;
+        while let Some(entry) = entries.next_entry().await.context(format!(
+            "failed to read archive cache at '{}'",
+            self.dir.display()
+        ))? {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            archives.push(Self::load_archive(path).await?);
+        }
+
+        archives.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        Ok(archives)
+    }
+
+    /// Removes an archive and its record from the cache.
+    #[instrument(level = "trace", skip_all, fields(path = %archive.path.display()))]
+    pub(crate) async fn remove(&self, archive: &CachedArchive) -> Result<()> {
+        debug!("Removing cached archive '{}'", archive.path.display());
+        remove_dir_all(&archive.path).await?;
+        let record_path = ArchiveRecord::path_for(&archive.path);
+        if record_path.exists() {
+            remove_file(&record_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn load_archive(path: PathBuf) -> Result<CachedArchive> {
+        let dir_name = path
+            .file_name()
+            .context(format!("invalid cached archive path '{}'", path.display()))?
+            .to_string_lossy()
+            .to_string();
+        // Archive directories are named after their digest with ':' replaced by '-'
+        let digest = dir_name.replacen('-', ":", 1);
+
+        let record_path = ArchiveRecord::path_for(&path);
+        let (record, last_used) = if record_path.is_file() {
+            let record = match serde_json::from_slice::<ArchiveRecord>(&read(&record_path).await?) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    warn!(
+                        "Ignoring unreadable archive record '{}': {e}",
+                        record_path.display()
+                    );
+                    None
+                }
+            };
+            (record, modified(&record_path).await?)
+        } else {
+            // Archives pulled by older versions of Twoliter have no record.
+            (None, modified(&path).await?)
+        };
+
+        Ok(CachedArchive {
+            size: dir_size(&path).await?,
+            path,
+            digest,
+            uri: record.as_ref().map(|record| record.uri.clone()),
+            locked_digests: record.map(|record| record.locked_digests),
+            last_used,
+        })
+    }
+}
+
+/// Chooses the archives to remove from the cache.
+///
+/// Archives which are not referenced by any of `locked_digests` are always removed. Archives with
+/// no record of the locked digests that referred to them are kept, since they may still be in use.
+/// If `max_size` is given, the least recently used of the remaining archives are also removed until
+/// the cache fits within `max_size` bytes.
+pub(crate) fn select_for_pruning<'a>(
+    archives: &'a [CachedArchive],
+    locked_digests: &HashSet<String>,
+    max_size: Option<u64>,
+) -> Vec<&'a CachedArchive> {
+    let (mut kept, mut pruned): (Vec<_>, Vec<_>) = archives
+        .iter()
+        .partition(|archive| archive.may_be_referenced_by(locked_digests));
+
+    if let Some(max_size) = max_size {
+        kept.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        let mut total: u64 = kept.iter().map(|archive| archive.size).sum();
+        while total > max_size {
+            match kept.pop() {
+                Some(archive) => {
+                    total -= archive.size;
+                    pruned.push(archive);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pruned
+}
+
+/// Collects the digests of every image referred to by the given lock files.
+pub(crate) async fn locked_digests(lock_files: &[PathBuf]) -> Result<HashSet<String>> {
+    let mut digests = HashSet::new();
+    for lock_file in lock_files {
+        let lock = Lock::read_from(lock_file)
+            .await
+            .context(format!("failed to load '{}'", lock_file.display()))?;
+        digests.insert(lock.sdk.digest);
+        digests.extend(lock.kit.into_iter().map(|kit| kit.digest));
+    }
+    Ok(digests)
+}
+
+/// Finds the lock files at or beneath `path`, skipping build output directories.
+pub(crate) async fn find_lock_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut lock_files = Vec::new();
+    let mut entries = WalkDir::new(path).filter(|entry| async move {
+        let skipped = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| SKIPPED_LOCK_SEARCH_DIRS.contains(&name));
+        if skipped {
+            Filtering::IgnoreDir
+        } else {
+            Filtering::Continue
+        }
+    });
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!(
+            "failed to search for lock files in '{}'",
+            path.display()
+        ))?;
+        if entry.file_name() == TWOLITER_LOCK {
+            lock_files.push(entry.path());
+        }
+    }
+    Ok(lock_files)
+}
+
+async fn modified(path: &Path) -> Result<SystemTime> {
+    metadata(path)
+        .await?
+        .modified()
+        .context(format!("unable to read mtime of '{}'", path.display()))
+}
+
+async fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    let mut entries = WalkDir::new(path);
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context(format!("failed to walk '{}'", path.display()))?;
+        let metadata = entry
+            .metadata()
+            .await
+            .context(format!("failed to stat '{}'", entry.path().display()))?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn archive(name: &str, locked_digests: &[&str], size: u64, age: u64) -> CachedArchive {
+        CachedArchive {
+            path: PathBuf::from(format!("/cache/sha256-{name}")),
+            digest: format!("sha256:{name}"),
+            uri: None,
+            locked_digests: Some(locked_digests.iter().map(|d| d.to_string()).collect()),
+            size,
+            last_used: SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 - age),
+        }
+    }
+
+    fn names(pruned: Vec<&CachedArchive>) -> HashSet<String> {
+        pruned.into_iter().map(|a| a.digest.clone()).collect()
+    }
+
+    #[test]
+    fn test_prune_removes_unreferenced_archives() {
+        let archives = vec![
+            archive("a", &["lock-a"], 10, 0),
+            archive("b", &["lock-b"], 10, 0),
+            archive("c", &[], 10, 0),
+            // Still referenced through one of the locks that pulled it.
+            archive("d", &["lock-a", "lock-d"], 10, 0),
+        ];
+        let locked = HashSet::from(["lock-a".to_string()]);
+
+        let pruned = names(select_for_pruning(&archives, &locked, None));
+        assert_eq!(
+            pruned,
+            HashSet::from(["sha256:b".to_string(), "sha256:c".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_prune_evicts_least_recently_used_over_max_size() {
+        let archives = vec![
+            archive("old", &["lock-old"], 40, 300),
+            archive("mid", &["lock-mid"], 40, 200),
+            archive("new", &["lock-new"], 40, 100),
+        ];
+        let locked = HashSet::from([
+            "lock-old".to_string(),
+            "lock-mid".to_string(),
+            "lock-new".to_string(),
+        ]);
+
+        let pruned = names(select_for_pruning(&archives, &locked, Some(80)));
+        assert_eq!(pruned, HashSet::from(["sha256:old".to_string()]));
+
+        let pruned = names(select_for_pruning(&archives, &locked, Some(0)));
+        assert_eq!(pruned.len(), 3);
+    }
+
+    #[test]
+    fn test_prune_keeps_archives_without_a_record() {
+        let mut unrecorded = archive("unrecorded", &[], 40, 300);
+        unrecorded.locked_digests = None;
+        let archives = vec![unrecorded, archive("new", &["lock-new"], 40, 100)];
+        let locked = HashSet::from(["lock-new".to_string()]);
+
+        // It can't be told apart from an archive that the lock still uses.
+        let pruned = names(select_for_pruning(&archives, &locked, None));
+        assert!(pruned.is_empty());
+
+        // But it is evicted like any other to fit within the maximum size.
+        let pruned = names(select_for_pruning(&archives, &locked, Some(40)));
+        assert_eq!(pruned, HashSet::from(["sha256:unrecorded".to_string()]));
+    }
+}
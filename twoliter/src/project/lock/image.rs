@@ -1,4 +1,5 @@
 use super::archive::OCIArchive;
+use super::cache::ARCHIVE_CACHE_DIR;
 use super::views::ManifestListView;
 use crate::common::fs::create_dir_all;
 use crate::compatibility::SUPPORTED_KIT_METADATA_VERSION;
@@ -104,7 +105,7 @@ impl EncodedKitMetadata {
     async fn try_from_image(image_uri: &str, image_tool: &ImageTool) -> Result<Self> {
         tracing::trace!(image_uri, "Extracting kit metadata from OCI image config");
         let config = image_tool.get_config(image_uri).await?;
-        let kit_metadata = EncodedKitMetadata(Self::extract_encoded_kit_metadata(&config)?);
+        let kit_metadata = Self::from_config(&config)?;
 
         tracing::trace!(
             image_uri,
@@ -116,6 +117,11 @@ impl EncodedKitMetadata {
         Ok(kit_metadata)
     }
 
+    /// Reads the kit metadata embedded in an OCI image config.
+    pub(super) fn from_config(oci_config: &ConfigView) -> Result<Self> {
+        Self::extract_encoded_kit_metadata(oci_config).map(Self)
+    }
+
     fn extract_encoded_kit_metadata(oci_config: &ConfigView) -> Result<String> {
         let encoded_metadata = oci_config
             .labels
@@ -309,7 +315,13 @@ impl ImageResolver {
         level = "trace",
         fields(uri = %self.image.project_image_uri(), path = %path.as_ref().display())
     )]
-    pub(crate) async fn extract<P>(&self, image_tool: &ImageTool, path: P, arch: &str) -> Result<()>
+    pub(crate) async fn extract<P>(
+        &self,
+        image_tool: &ImageTool,
+        path: P,
+        arch: &str,
+        locked_digest: &str,
+    ) -> Result<()>
     where
         P: AsRef<Path>,
     {
@@ -323,7 +335,7 @@ impl ImageResolver {
             self.image.vendor_name(),
             self.image.name()
         ));
-        let cache_path = path.as_ref().join("cache");
+        let cache_path = path.as_ref().join(ARCHIVE_CACHE_DIR);
         create_dir_all(&target_path).await?;
         create_dir_all(&cache_path).await?;
 
@@ -347,7 +359,8 @@ impl ImageResolver {
             uri.repo.as_str(),
             manifest.digest.as_str(),
             &cache_path,
-        )?;
+        )?
+        .locked_by(locked_digest);
 
         // Checks for the saved image locally, or else pulls and saves it
         oci_archive.pull_image(image_tool).await?;
@@ -0,0 +1,359 @@
+//! Reads a project's Twoliter.lock, verification markers and external kit metadata as they are on
+//! disk and compares them with each other. Nothing is resolved against a registry, so this works
+//! offline and never changes the files it inspects.
+use super::image::LockedImage;
+use super::verification::{
+    VerificationManifest, KITS_VERIFIED_MARKER_FILE, SDK_VERIFIED_MARKER_FILE,
+};
+use super::{ExternalKitMetadata, Lock, TWOLITER_LOCK};
+use buildsys_config::{EXTERNAL_KIT_DIRECTORY, EXTERNAL_KIT_METADATA};
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// How a file that Twoliter derives from the lock compares with Twoliter.lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FileState {
+    /// The file does not exist
+    Missing,
+    /// The file exists but could not be parsed
+    Unreadable(String),
+    /// There is no readable Twoliter.lock to compare the file with
+    Unchecked,
+    /// The file lists exactly the images in Twoliter.lock
+    Matches,
+    /// The file lists different images than Twoliter.lock
+    Differs {
+        /// Images in Twoliter.lock that the file does not list
+        missing: Vec<String>,
+        /// Images the file lists that are not in Twoliter.lock
+        unexpected: Vec<String>,
+    },
+}
+
+impl Display for FileState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileState::Missing => write!(f, "missing"),
+            FileState::Unreadable(e) => write!(f, "unreadable: {e}"),
+            FileState::Unchecked => write!(f, "present, but there is no lock to compare it with"),
+            FileState::Matches => write!(f, "matches {TWOLITER_LOCK}"),
+            FileState::Differs {
+                missing,
+                unexpected,
+            } => {
+                write!(f, "differs from {TWOLITER_LOCK}")?;
+                for image in missing {
+                    write!(f, "\n    not listed: {image}")?;
+                }
+                for image in unexpected {
+                    write!(f, "\n    not locked: {image}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A file which was compared with Twoliter.lock.
+#[derive(Debug, Clone)]
+pub(crate) struct InspectedFile {
+    pub(crate) path: PathBuf,
+    /// The size of the file, if it exists
+    pub(crate) size: Option<u64>,
+    /// The images the file lists, as they are written in it
+    pub(crate) images: Vec<String>,
+    pub(crate) state: FileState,
+}
+
+impl InspectedFile {
+    pub(crate) fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn missing(path: PathBuf) -> Self {
+        Self {
+            path,
+            size: None,
+            images: Vec::new(),
+            state: FileState::Missing,
+        }
+    }
+
+    fn unreadable(path: PathBuf, error: impl Display) -> Self {
+        Self {
+            size: file_size(&path),
+            path,
+            images: Vec::new(),
+            state: FileState::Unreadable(format!("{error:#}")),
+        }
+    }
+
+    fn compared(
+        path: PathBuf,
+        images: BTreeSet<String>,
+        expected: Option<BTreeSet<String>>,
+    ) -> Self {
+        let state = match expected {
+            None => FileState::Unchecked,
+            Some(expected) if expected == images => FileState::Matches,
+            Some(expected) => FileState::Differs {
+                missing: expected.difference(&images).cloned().collect(),
+                unexpected: images.difference(&expected).cloned().collect(),
+            },
+        };
+        Self {
+            size: file_size(&path),
+            path,
+            images: images.into_iter().collect(),
+            state,
+        }
+    }
+}
+
+/// The state of a project's lock and the files Twoliter derives from it.
+#[derive(Debug)]
+pub(crate) struct LockInspection {
+    lock_file: PathBuf,
+    lock: Result<Lock, String>,
+    sdk_marker: InspectedFile,
+    kits_marker: InspectedFile,
+    metadata: InspectedFile,
+}
+
+impl LockInspection {
+    pub(crate) async fn load(project_dir: &Path) -> Self {
+        let lock_file = project_dir.join(TWOLITER_LOCK);
+        let lock = if lock_file.exists() {
+            Lock::read_from(&lock_file)
+                .await
+                .map_err(|e| format!("{e:#}"))
+        } else {
+            Err(format!(
+                "{TWOLITER_LOCK} does not exist, please run `twoliter update`"
+            ))
+        };
+        let external_kits_dir = project_dir.join(EXTERNAL_KIT_DIRECTORY);
+        let locked = lock.as_ref().ok();
+
+        let sdk_marker = inspect_marker(
+            external_kits_dir.join(SDK_VERIFIED_MARKER_FILE),
+            locked.map(|lock| VerificationManifest::from(&lock.sdk)),
+        )
+        .await;
+        let kits_marker = inspect_marker(
+            external_kits_dir.join(KITS_VERIFIED_MARKER_FILE),
+            locked.map(|lock| {
+                VerificationManifest::from(lock.kit.iter().collect::<Vec<_>>().as_slice())
+            }),
+        )
+        .await;
+        let metadata = inspect_metadata(project_dir.join(EXTERNAL_KIT_METADATA), locked).await;
+
+        Self {
+            lock_file,
+            lock,
+            sdk_marker,
+            kits_marker,
+            metadata,
+        }
+    }
+
+    pub(crate) fn lock_file(&self) -> &Path {
+        &self.lock_file
+    }
+
+    /// The images in Twoliter.lock, each with its role, or why the lock could not be read.
+    pub(crate) fn locked_images(&self) -> Result<Vec<(&'static str, String)>, &str> {
+        let lock = self.lock.as_ref().map_err(String::as_str)?;
+        Ok(std::iter::once(("sdk", &lock.sdk))
+            .chain(lock.kit.iter().map(|kit| ("kit", kit)))
+            .map(|(role, image)| (role, with_digest(image)))
+            .collect())
+    }
+
+    pub(crate) fn files(&self) -> [&InspectedFile; 3] {
+        [&self.sdk_marker, &self.kits_marker, &self.metadata]
+    }
+
+    /// Explains whether the `validate-kits` task would pass. The task only checks that the kits
+    /// marker exists and is not empty, so a stale marker passes.
+    pub(crate) fn explain_validate_kits(&self) -> (bool, String) {
+        let marker = &self.kits_marker;
+        let name = marker.name();
+        if marker.size.unwrap_or_default() == 0 {
+            let reason = if marker.size.is_none() {
+                format!("{name} does not exist")
+            } else {
+                format!("{name} is empty")
+            };
+            return (
+                false,
+                format!(
+                    "{reason}. Twoliter writes it only after resolving every kit in \
+                    {TWOLITER_LOCK} against its registry, which `twoliter fetch`, `twoliter build` \
+                    and `twoliter make` with a build target all do. Commands which only need the \
+                    SDK remove it, so running cargo make directly after one of them fails."
+                ),
+            );
+        }
+
+        let explanation = match &marker.state {
+            FileState::Matches => format!("{name} matches {TWOLITER_LOCK}."),
+            FileState::Differs { .. } => format!(
+                "{name} exists, but it does not match {TWOLITER_LOCK}, so the lock has changed \
+                since Twoliter last verified it. Twoliter rewrites the markers each time it runs, \
+                so this only matters when cargo make is run directly; run `twoliter fetch` to \
+                verify the kits again."
+            ),
+            FileState::Unreadable(_) => format!(
+                "{name} exists, although it cannot be parsed; the task only checks that it is \
+                not empty."
+            ),
+            FileState::Unchecked | FileState::Missing => format!(
+                "{name} exists, but without a readable {TWOLITER_LOCK} it cannot be compared."
+            ),
+        };
+        (true, explanation)
+    }
+}
+
+async fn inspect_marker(path: PathBuf, expected: Option<VerificationManifest>) -> InspectedFile {
+    match VerificationManifest::read_marker(&path).await {
+        Ok(None) => InspectedFile::missing(path),
+        Ok(Some(manifest)) => InspectedFile::compared(
+            path,
+            manifest.images().clone(),
+            expected.map(|expected| expected.images().clone()),
+        ),
+        Err(e) => InspectedFile::unreadable(path, e),
+    }
+}
+
+async fn inspect_metadata(path: PathBuf, lock: Option<&Lock>) -> InspectedFile {
+    if !path.exists() {
+        return InspectedFile::missing(path);
+    }
+    let metadata = match tokio::fs::read(&path).await {
+        Ok(bytes) => serde_json::from_slice::<ExternalKitMetadata>(&bytes),
+        Err(e) => return InspectedFile::unreadable(path, e),
+    };
+    match metadata {
+        Ok(metadata) => InspectedFile::compared(
+            path,
+            metadata_images(&metadata.sdk, &metadata.kits),
+            lock.map(|lock| metadata_images(&lock.sdk, &lock.kit)),
+        ),
+        Err(e) => InspectedFile::unreadable(path, e),
+    }
+}
+
+/// External kit metadata records digests, unlike the verification markers, so they are compared
+/// too.
+fn metadata_images(sdk: &LockedImage, kits: &[LockedImage]) -> BTreeSet<String> {
+    std::iter::once(sdk).chain(kits).map(with_digest).collect()
+}
+
+fn with_digest(image: &LockedImage) -> String {
+    format!("{image} {}", image.digest)
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    path.metadata().ok().map(|metadata| metadata.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOCK: &str = r#"
+schema-version = 1
+
+[sdk]
+name = "bottlerocket-sdk"
+version = "0.50.0"
+vendor = "bottlerocket"
+source = "public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0"
+digest = "c2RrLWRpZ2VzdA=="
+
+[[kit]]
+name = "core-kit"
+version = "2.0.0"
+vendor = "bottlerocket"
+source = "public.ecr.aws/bottlerocket/core-kit:v2.0.0"
+digest = "a2l0LWRpZ2VzdA=="
+"#;
+
+    const SDK: &str =
+        "bottlerocket-sdk-0.50.0@bottlerocket (public.ecr.aws/bottlerocket/bottlerocket-sdk:v0.50.0)";
+    const KIT: &str = "core-kit-2.0.0@bottlerocket (public.ecr.aws/bottlerocket/core-kit:v2.0.0)";
+
+    async fn project() -> tempfile::TempDir {
+        let project_dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(project_dir.path().join(TWOLITER_LOCK), LOCK)
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(project_dir.path().join(EXTERNAL_KIT_DIRECTORY))
+            .await
+            .unwrap();
+        project_dir
+    }
+
+    async fn write_marker(project_dir: &Path, marker: &str, contents: &str) {
+        let path = project_dir.join(EXTERNAL_KIT_DIRECTORY).join(marker);
+        tokio::fs::write(path, contents).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missing_kits_marker_fails_validation() {
+        let project_dir = project().await;
+        write_marker(
+            project_dir.path(),
+            SDK_VERIFIED_MARKER_FILE,
+            &format!(r#"["{SDK}"]"#),
+        )
+        .await;
+
+        let inspection = LockInspection::load(project_dir.path()).await;
+        assert_eq!(inspection.sdk_marker.state, FileState::Matches);
+        assert_eq!(inspection.kits_marker.state, FileState::Missing);
+        assert_eq!(inspection.metadata.state, FileState::Missing);
+        let (passes, explanation) = inspection.explain_validate_kits();
+        assert!(!passes);
+        assert!(explanation.starts_with(".kits-verified does not exist"));
+    }
+
+    #[tokio::test]
+    async fn test_stale_kits_marker_passes_validation() {
+        let project_dir = project().await;
+        let stale_kit = "core-kit-1.0.0@bottlerocket (public.ecr.aws/bottlerocket/core-kit:v1.0.0)";
+        write_marker(
+            project_dir.path(),
+            KITS_VERIFIED_MARKER_FILE,
+            &format!(r#"["{stale_kit}"]"#),
+        )
+        .await;
+
+        let inspection = LockInspection::load(project_dir.path()).await;
+        assert_eq!(
+            inspection.kits_marker.state,
+            FileState::Differs {
+                missing: vec![KIT.to_string()],
+                unexpected: vec![stale_kit.to_string()],
+            }
+        );
+        let (passes, _) = inspection.explain_validate_kits();
+        assert!(passes);
+    }
+
+    #[tokio::test]
+    async fn test_missing_lock() {
+        let project_dir = tempfile::tempdir().unwrap();
+        let inspection = LockInspection::load(project_dir.path()).await;
+        assert!(inspection.locked_images().is_err());
+        assert!(!inspection.explain_validate_kits().0);
+    }
+}
@@ -0,0 +1,402 @@
+use super::image::{EncodedKitMetadata, ImageMetadata, LockedImage};
+use super::views::{IndexView, ManifestConfigView};
+use crate::common::fs::{create_dir_all, remove_dir_all};
+use crate::project::{ProjectImage, ValidIdentifier, VendedArtifact};
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use oci_cli_wrapper::ConfigView;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tar::Archive as TarArchive;
+use tracing::{debug, info, instrument};
+
+/// Blobs larger than this are image layers, which are not needed to read the kit metadata.
+const MAX_METADATA_BLOB_SIZE: u64 = 1024 * 1024;
+
+/// A kit built by another project in the same Twoliter workspace. Projects that depend on it use
+/// the kit from the other project's build directory instead of pulling it from a registry.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) struct LocalKit {
+    name: ValidIdentifier,
+    version: Version,
+    /// The directory the producing project builds the kit into, `build/kits/<name>`.
+    dir: PathBuf,
+}
+
+impl LocalKit {
+    pub(crate) fn new(name: ValidIdentifier, version: Version, project_dir: &Path) -> Self {
+        let dir = project_dir.join("build/kits").join(name.as_ref());
+        Self { name, version, dir }
+    }
+
+    pub(crate) fn name(&self) -> &ValidIdentifier {
+        &self.name
+    }
+
+    pub(crate) fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Whether a dependency on `image` is satisfied by this kit. The vendor is not compared, since
+    /// the vendor only says which registry the kit would otherwise be pulled from.
+    pub(crate) fn provides(&self, image: &impl VendedArtifact) -> bool {
+        image.artifact_name() == &self.name && image.version() == &self.version
+    }
+
+    /// Locks the kit as it was last built, reading its metadata from the OCI archives that the
+    /// build left in the kit's directory. The digest covers the archive of every architecture.
+    #[instrument(level = "trace", skip(self), fields(kit = %self.name, dir = %self.dir.display()))]
+    pub(super) async fn resolve(
+        &self,
+        image: &ProjectImage,
+    ) -> Result<(LockedImage, ImageMetadata)> {
+        info!(
+            "Resolving dependency image '{}' from the workspace kit in '{}'.",
+            image,
+            self.dir.display()
+        );
+        let archives = self.archives().await?;
+        let mut hasher = Sha256::new();
+        let mut canonical_metadata = None;
+        for (arch, path) in archives {
+            debug!(
+                "Reading kit metadata for '{arch}' from '{}'",
+                path.display()
+            );
+            let (index, config) = tokio::task::spawn_blocking(move || read_archive(&path))
+                .await
+                .context("Unable to run and join async task for reading kit archive")??;
+            hasher.update(&index);
+            let metadata = EncodedKitMetadata::from_config(&config)?;
+            match &canonical_metadata {
+                None => canonical_metadata = Some(metadata),
+                Some(canonical) => ensure!(
+                    canonical == &metadata,
+                    "The archives for kit '{}' in '{}' have different metadata, rebuild the kit \
+                    for every architecture",
+                    self.name,
+                    self.dir.display()
+                ),
+            }
+        }
+        let metadata = canonical_metadata
+            .context(format!("could not find metadata for kit {}", self.name))?
+            .try_into()
+            .context("Failed to decode and parse kit metadata")?;
+
+        let locked_image = LockedImage {
+            name: image.name().to_owned(),
+            version: image.version().to_owned(),
+            vendor: image.vendor_name().to_owned(),
+            source: self.dir.display().to_string(),
+            digest: base64::engine::general_purpose::STANDARD.encode(hasher.finalize()),
+        };
+        Ok((locked_image, metadata))
+    }
+
+    /// Places the kit's repository for `arch` where buildsys expects to find an external kit,
+    /// linking the files rather than copying them where possible.
+    #[instrument(level = "trace", skip(self), fields(kit = %self.name, dir = %self.dir.display()))]
+    pub(super) async fn extract(
+        &self,
+        vendor: &ValidIdentifier,
+        path: &Path,
+        arch: &str,
+    ) -> Result<()> {
+        let source = self.dir.join(arch);
+        ensure!(
+            source.is_dir(),
+            "Kit '{}' has not been built for '{arch}' in '{}'",
+            self.name,
+            self.dir.display()
+        );
+        let target = path.join(format!("{vendor}/{}/{arch}", self.name));
+        info!(
+            "Linking workspace kit '{}' from '{}' to '{}'",
+            self.name,
+            source.display(),
+            target.display()
+        );
+        remove_dir_all(&target).await?;
+        create_dir_all(&target).await?;
+        tokio::task::spawn_blocking(move || link_tree(&source, &target))
+            .await
+            .context("Unable to run and join async task for linking kit")?
+    }
+
+    /// The newest OCI archive that has been built for each architecture, by architecture. Archives
+    /// are named `<name>-v<version>-<build id>-<arch>.tar`.
+    async fn archives(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let prefix = format!("{}-v{}-", self.name, self.version);
+        let mut newest: BTreeMap<String, (SystemTime, PathBuf)> = BTreeMap::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await.context(format!(
+            "Kit '{}' has not been built in '{}', build the project that produces it first",
+            self.name,
+            self.dir.display()
+        ))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context(format!("Unable to read '{}'", self.dir.display()))?
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(arch) = file_name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".tar"))
+                .and_then(|rest| rest.rsplit_once('-'))
+                .map(|(_build_id, arch)| arch.to_string())
+            else {
+                continue;
+            };
+            let modified = entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+                .context(format!("Unable to get metadata for '{file_name}'"))?;
+            if newest.get(&arch).map_or(true, |(time, _)| modified > *time) {
+                newest.insert(arch, (modified, entry.path()));
+            }
+        }
+        if newest.is_empty() {
+            bail!(
+                "Kit '{}' version {} has not been built in '{}', build the project that produces \
+                it first",
+                self.name,
+                self.version,
+                self.dir.display()
+            );
+        }
+        Ok(newest
+            .into_iter()
+            .map(|(arch, (_, path))| (arch, path))
+            .collect())
+    }
+}
+
+/// The part of an OCI image config that holds the kit metadata.
+#[derive(Deserialize, Debug)]
+struct ImageConfigView {
+    config: ConfigView,
+}
+
+/// Reads an OCI archive built by `rpm2kit`, returning its index and its image config.
+fn read_archive(path: &Path) -> Result<(Vec<u8>, ConfigView)> {
+    let file = File::open(path).context(format!("Unable to open '{}'", path.display()))?;
+    let mut archive = TarArchive::new(file);
+    let mut blobs = HashMap::new();
+    let entries = archive
+        .entries()
+        .context(format!("Unable to read '{}'", path.display()))?;
+    for entry in entries {
+        let mut entry = entry.context(format!("Unable to read '{}'", path.display()))?;
+        if !entry.header().entry_type().is_file() || entry.size() > MAX_METADATA_BLOB_SIZE {
+            continue;
+        }
+        // Entries are archived relative to `.`, so drop it to look them up by their OCI paths.
+        let name = entry
+            .path()
+            .context(format!("Unable to read a path in '{}'", path.display()))?
+            .components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .collect::<PathBuf>();
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .context(format!("Unable to read '{}'", name.display()))?;
+        blobs.insert(name, data);
+    }
+
+    let blob = |digest: &str| {
+        blobs
+            .get(&Path::new("blobs").join(digest.replace(':', "/")))
+            .context(format!("'{}' is missing blob '{digest}'", path.display()))
+    };
+    let index = blobs
+        .get(Path::new("index.json"))
+        .context(format!("'{}' is missing 'index.json'", path.display()))?;
+    let index_view: IndexView =
+        serde_json::from_slice(index).context("failed to deserialize oci image index")?;
+    let manifest_digest = &index_view
+        .manifests
+        .first()
+        .context("empty oci image")?
+        .digest;
+    let manifest: ManifestConfigView = serde_json::from_slice(blob(manifest_digest)?)
+        .context("failed to deserialize oci manifest")?;
+    let config: ImageConfigView =
+        serde_json::from_slice(blob(&manifest.config.digest.to_string())?)
+            .context("failed to deserialize oci image config")?;
+    Ok((index.clone(), config.config))
+}
+
+/// Recreates the tree at `from` under `to`, hard linking each file, or copying it if it cannot be
+/// linked.
+fn link_tree(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to).context(format!("Unable to create '{}'", to.display()))?;
+    let entries =
+        std::fs::read_dir(from).context(format!("Unable to read '{}'", from.display()))?;
+    for entry in entries {
+        let entry = entry.context(format!("Unable to read '{}'", from.display()))?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .context(format!("Unable to get file type of '{}'", source.display()))?;
+        if file_type.is_dir() {
+            link_tree(&source, &target)?;
+        } else if std::fs::hard_link(&source, &target).is_err() {
+            std::fs::copy(&source, &target).context(format!(
+                "Unable to copy '{}' to '{}'",
+                source.display(),
+                target.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn image(name: &str, version: &str) -> crate::project::Image {
+        crate::project::Image {
+            name: ValidIdentifier(name.into()),
+            version: version.parse().unwrap(),
+            vendor: ValidIdentifier("anyone".into()),
+        }
+    }
+
+    /// Writes an OCI archive the way `rpm2kit` lays it out, with the given kit metadata.
+    fn write_archive(path: &Path, metadata: &str) {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(metadata);
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "config": { "Labels": { "dev.bottlerocket.kit.v2": encoded } },
+        })
+        .to_string();
+        let config_digest = format!("{:x}", Sha256::digest(&config));
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "config": { "digest": format!("sha256:{config_digest}") },
+            "layers": [],
+        })
+        .to_string();
+        let manifest_digest = format!("{:x}", Sha256::digest(&manifest));
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{ "digest": format!("sha256:{manifest_digest}") }],
+        })
+        .to_string();
+
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, data) in [
+            ("./index.json".to_string(), index),
+            (format!("./blobs/sha256/{manifest_digest}"), manifest),
+            (format!("./blobs/sha256/{config_digest}"), config),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, data.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().flush().unwrap();
+    }
+
+    const METADATA: &str = r#"{"kit":[],"name":"core-kit","sdk":{"name":"bottlerocket-sdk","vendor":"bottlerocket","version":"0.41.0"},"version":"1.0.0"}"#;
+
+    #[test]
+    fn test_provides() {
+        let kit = LocalKit::new(
+            ValidIdentifier("core-kit".into()),
+            Version::new(1, 0, 0),
+            Path::new("/project"),
+        );
+        assert_eq!(kit.dir, Path::new("/project/build/kits/core-kit"));
+        assert!(kit.provides(&image("core-kit", "1.0.0")));
+        assert!(!kit.provides(&image("core-kit", "1.0.1")));
+        assert!(!kit.provides(&image("extra-kit", "1.0.0")));
+    }
+
+    #[tokio::test]
+    async fn test_archives_picks_newest_per_arch() {
+        let temp_dir = TempDir::new().unwrap();
+        let kit = LocalKit::new(
+            ValidIdentifier("core-kit".into()),
+            Version::new(1, 0, 0),
+            temp_dir.path(),
+        );
+        assert!(kit.archives().await.is_err());
+
+        std::fs::create_dir_all(&kit.dir).unwrap();
+        for name in [
+            "core-kit-v1.0.0-aaaaaaa-x86_64.tar",
+            "core-kit-v1.0.0-bbbbbbb-x86_64.tar",
+            "core-kit-v1.0.0-aaaaaaa-aarch64.tar",
+            "core-kit-v0.9.0-aaaaaaa-x86_64.tar",
+        ] {
+            write_archive(&kit.dir.join(name), METADATA);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let archives = kit.archives().await.unwrap();
+        assert_eq!(
+            archives,
+            BTreeMap::from([
+                (
+                    "aarch64".to_string(),
+                    kit.dir.join("core-kit-v1.0.0-aaaaaaa-aarch64.tar")
+                ),
+                (
+                    "x86_64".to_string(),
+                    kit.dir.join("core-kit-v1.0.0-bbbbbbb-x86_64.tar")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_read_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("kit.tar");
+        write_archive(&path, METADATA);
+        let (_, config) = read_archive(&path).unwrap();
+        let metadata: ImageMetadata = EncodedKitMetadata::from_config(&config)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(metadata.sdk.name.as_ref(), "bottlerocket-sdk");
+        assert!(metadata.kits.is_empty());
+    }
+
+    #[test]
+    fn test_link_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("from");
+        std::fs::create_dir_all(from.join("Packages/hello")).unwrap();
+        std::fs::write(from.join("Packages/hello/hello.rpm"), "rpm").unwrap();
+        std::fs::create_dir_all(from.join("repodata")).unwrap();
+        std::fs::write(from.join("repodata/repomd.xml"), "xml").unwrap();
+
+        let to = temp_dir.path().join("to");
+        link_tree(&from, &to).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(to.join("Packages/hello/hello.rpm")).unwrap(),
+            "rpm"
+        );
+        assert_eq!(
+            std::fs::read_to_string(to.join("repodata/repomd.xml")).unwrap(),
+            "xml"
+        );
+    }
+}
@@ -5,20 +5,28 @@
 
 /// Contains operations for working with an OCI Archive
 mod archive;
+/// Lists and prunes the cache of OCI archives pulled for external kits
+pub(crate) mod cache;
 /// Covers resolution and validation of a single image dependency in a lock file
 mod image;
+/// Inspects the lock file and verification markers without resolving anything
+pub(crate) mod inspect;
+/// Covers resolution and extraction of kits built by another project in the workspace
+mod local;
 /// Provides tools for marking artifacts as having been verified against the Twoliter lockfile
 mod verification;
 /// Implements view models of common OCI manifest and configuration types
 mod views;
 
+pub(crate) use self::image::LockedImage;
+pub(crate) use self::local::LocalKit;
 pub(crate) use self::verification::VerificationTagger;
 
 use crate::common::fs::{create_dir_all, read, write};
 use crate::project::{Project, ValidIdentifier};
 use crate::schema_version::SchemaVersion;
 use anyhow::{bail, ensure, Context, Result};
-use image::{ImageResolver, LockedImage};
+use image::ImageResolver;
 use oci_cli_wrapper::ImageTool;
 use olpc_cjson::CanonicalFormatter as CanonicalJsonFormatter;
 use semver::Version;
@@ -27,14 +35,15 @@ use std::cmp::PartialEq;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::mem::take;
+use std::path::Path;
 use tokio::fs::read_to_string;
 use tracing::{debug, error, info, instrument};
 
 use super::{Locked, ProjectLock, Unlocked};
 
-const TWOLITER_LOCK: &str = "Twoliter.lock";
+pub(crate) const TWOLITER_LOCK: &str = "Twoliter.lock";
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct ExternalKitMetadata {
     sdk: LockedImage,
     #[serde(rename = "kit")]
@@ -165,7 +174,8 @@ impl Lock {
             resolved_lock=?resolved_lock,
             "Comparing resolved lock to current lock state"
         );
-        if current_lock != resolved_lock {
+        // Kits built in the workspace are not published yet, so Twoliter.lock cannot pin them.
+        if current_lock.without_local_kits(project) != resolved_lock.without_local_kits(project) {
             error!(
                 current_lock=?current_lock,
                 resolved_lock=?resolved_lock,
@@ -184,8 +194,13 @@ impl Lock {
             lock_file_path.exists(),
             "Twoliter.lock does not exist, please run `twoliter update` first"
         );
+        Self::read_from(&lock_file_path).await
+    }
+
+    /// Reads a lockfile from disk without checking it against the state of the world.
+    pub(crate) async fn read_from(lock_file_path: &Path) -> Result<Self> {
         debug!("Loading existing lockfile '{}'", lock_file_path.display());
-        let lock_str = read_to_string(&lock_file_path)
+        let lock_str = read_to_string(lock_file_path)
             .await
             .context("failed to read lockfile")?;
         let lock: Self =
@@ -193,6 +208,14 @@ impl Lock {
         Ok(lock)
     }
 
+    /// Returns a copy of the lock without the kits that are built in the project's workspace.
+    fn without_local_kits<L: ProjectLock>(&self, project: &Project<L>) -> Self {
+        let mut lock = self.clone();
+        lock.kit
+            .retain(|locked| project.local_kit_for(locked).is_none());
+        lock
+    }
+
     fn external_kit_metadata(&self) -> ExternalKitMetadata {
         ExternalKitMetadata {
             sdk: self.sdk.clone(),
@@ -214,11 +237,22 @@ impl Lock {
             dependencies = ?self.kit.iter().map(ToString::to_string).collect::<Vec<_>>(),
             "Extracting kit dependencies."
         );
-        for image in self.kit.iter() {
-            let image = project.as_project_image(image)?;
+        for locked in self.kit.iter() {
+            if let Some(local) = project.local_kit_for(locked) {
+                local
+                    .extract(&locked.vendor, &project.external_kits_dir(), arch)
+                    .await?;
+                continue;
+            }
+            let image = project.as_project_image(locked)?;
             let resolver = ImageResolver::from_image(&image)?;
             resolver
-                .extract(&image_tool, &project.external_kits_dir(), arch)
+                .extract(
+                    &image_tool,
+                    &project.external_kits_dir(),
+                    arch,
+                    &locked.digest,
+                )
                 .await?;
         }
 
@@ -291,12 +325,18 @@ impl Lock {
                     (image.name().clone(), image.vendor_name().clone()),
                     image.version().clone(),
                 );
-                let image_resolver = ImageResolver::from_image(image)?;
-                let (locked_image, metadata) = image_resolver.resolve(&image_tool).await?;
-                let metadata = metadata.context(format!(
-                    "failed to validate kit image with name {} from vendor {}",
-                    locked_image.name, locked_image.vendor
-                ))?;
+                let (locked_image, metadata) = match project.local_kit_for(image) {
+                    Some(local) => local.resolve(image).await?,
+                    None => {
+                        let image_resolver = ImageResolver::from_image(image)?;
+                        let (locked_image, metadata) = image_resolver.resolve(&image_tool).await?;
+                        let metadata = metadata.context(format!(
+                            "failed to validate kit image with name {} from vendor {}",
+                            locked_image.name, locked_image.vendor
+                        ))?;
+                        (locked_image, metadata)
+                    }
+                };
                 locked.push(locked_image);
                 sdk_set.insert(project.as_project_image(&metadata.sdk)?);
                 for dep in metadata.kits {
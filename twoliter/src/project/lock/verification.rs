@@ -20,8 +20,8 @@ use std::path::Path;
 use strum::{EnumIter, IntoEnumIterator};
 use tracing::{debug, instrument};
 
-const SDK_VERIFIED_MARKER_FILE: &str = ".sdk-verified";
-const KITS_VERIFIED_MARKER_FILE: &str = ".kits-verified";
+pub(crate) const SDK_VERIFIED_MARKER_FILE: &str = ".sdk-verified";
+pub(crate) const KITS_VERIFIED_MARKER_FILE: &str = ".kits-verified";
 
 /// A tag indicating that Twoliter artifacts have been resolved and verified against the lockfile
 #[derive(Debug, PartialEq, Eq, Ord, PartialOrd, EnumIter)]
@@ -55,6 +55,28 @@ pub(crate) struct VerificationManifest {
 }
 
 impl VerificationManifest {
+    /// Reads the manifest written to a marker file, or `None` if there is no marker.
+    pub(crate) async fn read_marker(marker_file: &Path) -> Result<Option<Self>> {
+        if !marker_file.exists() {
+            return Ok(None);
+        }
+        let contents = tokio::fs::read(marker_file).await.context(format!(
+            "failed to read verification tag file: '{}'",
+            marker_file.display()
+        ))?;
+        serde_json::from_slice(&contents)
+            .context(format!(
+                "failed to parse verification tag file: '{}'",
+                marker_file.display()
+            ))
+            .map(Some)
+    }
+
+    /// The verified images, as they are written in the manifest.
+    pub(crate) fn images(&self) -> &BTreeSet<String> {
+        &self.verified_images
+    }
+
     fn as_canonical_json(&self) -> Result<Vec<u8>> {
         let mut manifest = Vec::new();
         let mut ser =
@@ -29,6 +29,12 @@ pub(crate) struct ManifestLayoutView {
     pub layers: Vec<Layer>,
 }
 
+/// The descriptor of the config blob in an image manifest.
+#[derive(Deserialize, Debug)]
+pub(crate) struct ManifestConfigView {
+    pub config: Layer,
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct Layer {
     pub digest: ContainerDigest,
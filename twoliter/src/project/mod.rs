@@ -1,13 +1,20 @@
 mod lock;
 pub(crate) mod vendor;
+pub(crate) mod workspace;
 
 pub(crate) use self::vendor::ArtifactVendor;
+pub(crate) use lock::cache;
+pub(crate) use lock::inspect;
+pub(crate) use lock::LocalKit;
+pub(crate) use lock::LockedImage;
 pub(crate) use lock::VerificationTagger;
 
-use self::lock::{Lock, LockedSDK, Override};
+use self::lock::cache::ArchiveCache;
+use self::lock::{Lock, LockedSDK, Override, TWOLITER_LOCK};
 use crate::common::fs::{self, read_to_string};
 use crate::compatibility::SUPPORTED_TWOLITER_PROJECT_SCHEMA_VERSION;
 use crate::docker::ImageUri;
+use crate::makefile::MakefileExtension;
 use crate::schema_version::SchemaVersion;
 use anyhow::{ensure, Context, Result};
 use async_recursion::async_recursion;
@@ -68,6 +75,13 @@ pub(crate) struct Project<L: ProjectLock> {
 
     overrides: BTreeMap<String, BTreeMap<String, Override>>,
 
+    /// A makefile that adds to, or overrides parts of, Twoliter's embedded `Makefile.toml`.
+    makefile_extension: Option<MakefileExtension>,
+
+    /// Kits built by other projects in the same workspace, which are used in place of the images
+    /// in their registries.
+    local_kits: Vec<LocalKit>,
+
     /// The resolved and locked dependencies of the project.
     lock: L,
 }
@@ -123,6 +137,13 @@ impl Project<Unlocked> {
         Self::find_and_load(parent).await
     }
 
+    /// Uses the given kits, built by other projects in the workspace, wherever this project depends
+    /// on them.
+    pub(crate) fn with_local_kits(mut self, local_kits: Vec<LocalKit>) -> Self {
+        self.local_kits = local_kits;
+        self
+    }
+
     pub(crate) async fn create_lock(self) -> Result<Project<Locked>> {
         let lock = Lock::create(&self).await?;
         Ok(self.with_new_lock(lock))
@@ -154,6 +175,8 @@ impl<L: ProjectLock> Project<L> {
             vendor: self.vendor.clone(),
             kit: self.kit.clone(),
             overrides: self.overrides.clone(),
+            makefile_extension: self.makefile_extension.clone(),
+            local_kits: self.local_kits.clone(),
             lock: new_lock.into(),
         }
     }
@@ -174,6 +197,15 @@ impl<L: ProjectLock> Project<L> {
         self.project_dir.join(EXTERNAL_KIT_METADATA)
     }
 
+    pub(crate) fn lock_file(&self) -> PathBuf {
+        self.project_dir.join(TWOLITER_LOCK)
+    }
+
+    /// The cache of OCI archives which external kits are pulled into.
+    pub(crate) fn archive_cache(&self) -> ArchiveCache {
+        ArchiveCache::new(self.external_kits_dir())
+    }
+
     pub(crate) fn schema_version(&self) -> SchemaVersion<1> {
         self.schema_version
     }
@@ -182,6 +214,15 @@ impl<L: ProjectLock> Project<L> {
         self.release_version.as_str()
     }
 
+    pub(crate) fn makefile_extension(&self) -> Option<&MakefileExtension> {
+        self.makefile_extension.as_ref()
+    }
+
+    /// The kit built in the workspace that satisfies a dependency on `image`, if there is one.
+    pub(crate) fn local_kit_for(&self, image: &impl VendedArtifact) -> Option<&LocalKit> {
+        self.local_kits.iter().find(|kit| kit.provides(image))
+    }
+
     pub(crate) fn direct_kit_deps(&self) -> Result<Vec<ProjectImage>> {
         self.kit
             .iter()
@@ -301,6 +342,18 @@ impl Project<Locked> {
         self.as_project_image(&lock.sdk)
             .expect("Could not find SDK vendor despite lock resolution succeeding?")
     }
+
+    /// The SDK image as it was resolved in Twoliter.lock.
+    pub(crate) fn locked_sdk(&self) -> &LockedImage {
+        let Locked(lock) = &self.lock;
+        &lock.sdk
+    }
+
+    /// The kit images as they were resolved in Twoliter.lock.
+    pub(crate) fn locked_kits(&self) -> &[LockedImage] {
+        let Locked(lock) = &self.lock;
+        &lock.kit
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -367,6 +420,20 @@ impl ProjectImage {
     }
 }
 
+impl VendedArtifact for ProjectImage {
+    fn artifact_name(&self) -> &ValidIdentifier {
+        self.name()
+    }
+
+    fn vendor_name(&self) -> &ValidIdentifier {
+        self.vendor.vendor_name()
+    }
+
+    fn version(&self) -> &Version {
+        self.image.version()
+    }
+}
+
 /// An artifact/vendor name combination used to identify an artifact resolved by Twoliter.
 ///
 /// This is intended for use in [`Project::vendor_for`] lookups.
@@ -500,6 +567,7 @@ struct UnvalidatedProject {
     sdk: Option<Image>,
     vendor: Option<BTreeMap<ValidIdentifier, Vendor>>,
     kit: Option<Vec<Image>>,
+    makefile_extension: Option<MakefileExtension>,
 }
 
 impl UnvalidatedProject {
@@ -516,6 +584,7 @@ impl UnvalidatedProject {
 
         self.check_vendor_availability().await?;
         self.check_release_toml(&project_dir).await?;
+        self.check_makefile_extension(&project_dir)?;
         let overrides = self.check_and_load_overrides(&project_dir).await?;
 
         Ok(Project {
@@ -527,6 +596,8 @@ impl UnvalidatedProject {
             vendor: self.vendor.unwrap_or_default(),
             kit: self.kit.unwrap_or_default(),
             overrides,
+            makefile_extension: self.makefile_extension,
+            local_kits: Vec::new(),
             lock: Unlocked,
         })
     }
@@ -571,6 +642,19 @@ impl UnvalidatedProject {
         Ok(())
     }
 
+    /// Errors if the project names a makefile extension that does not exist.
+    fn check_makefile_extension(&self, project_dir: &Path) -> Result<()> {
+        if let Some(extension) = &self.makefile_extension {
+            let path = project_dir.join(&extension.path);
+            ensure!(
+                path.is_file(),
+                "The makefile extension '{}' named in Twoliter.toml does not exist",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
     /// Issues a warning if `Release.toml` is found and, if so, ensures that it contains the same
     /// version (i.e. `release-version`) as the `Twoliter.toml` project file.
     async fn check_release_toml(&self, project_dir: &Path) -> Result<()> {
@@ -846,6 +930,7 @@ mod test {
                 version: Version::new(1, 20, 0),
                 vendor: ValidIdentifier("not-bottlerocket".into()),
             }]),
+            makefile_extension: None,
         };
         assert!(project.check_vendor_availability().await.is_err());
     }
@@ -870,6 +955,30 @@ mod test {
         Project::find_and_load(p).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_makefile_extension_must_exist() {
+        let tempdir = TempDir::new().unwrap();
+        let p = tempdir.path();
+        let twoliter_toml = fs::read_to_string(data_dir().join("Twoliter-1.toml"))
+            .await
+            .unwrap();
+        let twoliter_toml =
+            format!("{twoliter_toml}\n[makefile-extension]\npath = \"Makefile.extension.toml\"\n");
+        fs::write(p.join("Twoliter.toml"), twoliter_toml)
+            .await
+            .unwrap();
+        assert!(Project::find_and_load(p).await.is_err());
+
+        fs::write(p.join("Makefile.extension.toml"), "[tasks.lint]\n")
+            .await
+            .unwrap();
+        let project = Project::find_and_load(p).await.unwrap();
+        assert_eq!(
+            project.makefile_extension().unwrap().path,
+            Path::new("Makefile.extension.toml")
+        );
+    }
+
     #[tokio::test]
     async fn find_go_modules() {
         let twoliter_toml_path = projects_dir().join("project1").join("Twoliter.toml");
@@ -0,0 +1,376 @@
+//! A Twoliter workspace groups projects that depend on each other's kits, such as a core kit
+//! project, a vendor kit project and a variants project in one repository.
+//!
+//! The workspace is described by a `Twoliter.workspace` file which lists its member projects.
+//! Members are built in dependency order, and each member uses the kits that earlier members built
+//! in place of the images in their registries. See [`LocalKit`].
+use super::{LocalKit, Project, Unlocked};
+use crate::common::fs;
+use crate::schema_version::SchemaVersion;
+use anyhow::{bail, ensure, Context, Result};
+use async_recursion::async_recursion;
+use semver::Version;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::{debug, trace};
+
+pub(crate) const TWOLITER_WORKSPACE: &str = "Twoliter.workspace";
+
+/// If the user gave a path to the `Twoliter.workspace` file, we use it, otherwise we search for the
+/// file.
+pub(crate) async fn load_or_find_workspace(user_path: Option<PathBuf>) -> Result<Workspace> {
+    let workspace = match user_path {
+        None => Workspace::find_and_load(".").await?,
+        Some(p) => Workspace::load(&p).await?,
+    };
+    debug!(
+        "Workspace file loaded from '{}'",
+        workspace.filepath.display()
+    );
+    Ok(workspace)
+}
+
+/// The projects of a workspace, in the order in which they need to be built.
+#[derive(Debug)]
+pub(crate) struct Workspace {
+    filepath: PathBuf,
+    members: Vec<Member>,
+}
+
+/// A project in a workspace, and what to build in it.
+#[derive(Debug)]
+pub(crate) struct Member {
+    project: Project<Unlocked>,
+    kits: Vec<String>,
+    variants: Vec<String>,
+}
+
+impl Member {
+    /// The member's project, which uses the kits built by the other members.
+    pub(crate) fn project(&self) -> &Project<Unlocked> {
+        &self.project
+    }
+
+    /// The kits to build in the member.
+    pub(crate) fn kits(&self) -> &[String] {
+        &self.kits
+    }
+
+    /// The variants to build in the member. Each may be a glob pattern.
+    pub(crate) fn variants(&self) -> &[String] {
+        &self.variants
+    }
+
+    /// The kits that the member builds, at the member's release version.
+    fn local_kits(&self) -> Result<Vec<LocalKit>> {
+        let version: Version = self.project.release_version().parse().context(format!(
+            "The release-version of '{}' must be a semantic version to build its kits in a \
+            workspace",
+            self.project.filepath().display()
+        ))?;
+        self.kits
+            .iter()
+            .map(|kit| {
+                Ok(LocalKit::new(
+                    kit.parse()?,
+                    version.clone(),
+                    &self.project.project_dir(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Whether the member depends directly on any of `kits`.
+    fn depends_on(&self, kits: &[LocalKit]) -> bool {
+        self.project
+            .kit
+            .iter()
+            .any(|image| kits.iter().any(|kit| kit.provides(image)))
+    }
+}
+
+impl Workspace {
+    /// Load a `Twoliter.workspace` file and the projects it names.
+    pub(crate) async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let filepath = fs::canonicalize(path).await?;
+        let data = fs::read_to_string(&filepath).await.context(format!(
+            "Unable to read workspace file '{}'",
+            filepath.display()
+        ))?;
+        let unvalidated: UnvalidatedWorkspace = toml::from_str(&data).context(format!(
+            "Unable to deserialize workspace file '{}'",
+            filepath.display()
+        ))?;
+        let workspace_dir = filepath
+            .parent()
+            .context(format!(
+                "Unable to find the parent directory of '{}'",
+                filepath.display(),
+            ))?
+            .to_path_buf();
+
+        let mut members = Vec::new();
+        for member in unvalidated.member {
+            let project_path = workspace_dir.join(&member.path).join("Twoliter.toml");
+            let project = Project::load(&project_path).await.context(format!(
+                "Unable to load workspace member '{}'",
+                member.path.display()
+            ))?;
+            let kits = match member.kits {
+                Some(kits) => kits,
+                None => find_kits(&project.project_dir()).await?,
+            };
+            members.push(Member {
+                project,
+                kits,
+                variants: member.variants,
+            });
+        }
+        ensure!(
+            !members.is_empty(),
+            "The workspace file '{}' does not list any members",
+            filepath.display()
+        );
+
+        let members = Self::build_order(members)?;
+        Ok(Self { filepath, members })
+    }
+
+    /// Recursively search for a file named `Twoliter.workspace` starting in `dir`. If it is not
+    /// found, move up (i.e. `cd ..`) until it is found. Return an error if there is no parent
+    /// directory.
+    #[async_recursion]
+    pub(crate) async fn find_and_load<P>(dir: P) -> Result<Self>
+    where
+        P: Send + AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        trace!("Looking for {TWOLITER_WORKSPACE} in '{}'", dir.display());
+        ensure!(
+            dir.is_dir(),
+            "Unable to locate {TWOLITER_WORKSPACE} in '{}': not a directory",
+            dir.display()
+        );
+        let dir = dir
+            .canonicalize()
+            .context(format!("Unable to canonicalize '{}'", dir.display()))?;
+        let filepath = dir.join(TWOLITER_WORKSPACE);
+        if filepath.is_file() {
+            return Self::load(&filepath).await;
+        }
+        // Move up a level and recurse.
+        let parent = dir
+            .parent()
+            .context(format!("Unable to find {TWOLITER_WORKSPACE} file"))?
+            .to_owned();
+        Self::find_and_load(parent).await
+    }
+
+    pub(crate) fn filepath(&self) -> &Path {
+        &self.filepath
+    }
+
+    /// The members of the workspace, producers of kits before the members that use them.
+    pub(crate) fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    /// Sorts the members so that each comes after the members whose kits it depends on, keeping
+    /// the order of the workspace file otherwise. Gives each member the kits of all the others.
+    fn build_order(members: Vec<Member>) -> Result<Vec<Member>> {
+        let local_kits = members
+            .iter()
+            .map(Member::local_kits)
+            .collect::<Result<Vec<_>>>()?;
+        let all_kits = local_kits.iter().flatten().collect::<Vec<_>>();
+        for (i, kit) in all_kits.iter().enumerate() {
+            ensure!(
+                !all_kits[..i]
+                    .iter()
+                    .any(|other| other.name() == kit.name() && other.version() == kit.version()),
+                "More than one workspace member builds kit '{}' version {}",
+                kit.name(),
+                kit.version()
+            );
+        }
+
+        let mut remaining = members.into_iter().zip(local_kits).collect::<Vec<_>>();
+        let mut ordered: Vec<(Member, Vec<LocalKit>)> = Vec::new();
+        while !remaining.is_empty() {
+            // The first member that depends on no member which is still waiting to be ordered.
+            let ready = remaining.iter().position(|(member, _)| {
+                !remaining
+                    .iter()
+                    .any(|(_, kits)| member.depends_on(kits.as_slice()))
+            });
+            let Some(ready) = ready else {
+                let cycle = remaining
+                    .iter()
+                    .map(|(member, _)| member.project.project_dir().display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                bail!("The kits of these workspace members depend on each other: {cycle}");
+            };
+            ordered.push(remaining.remove(ready));
+        }
+
+        let all_kits = ordered
+            .iter()
+            .flat_map(|(_, kits)| kits.iter().cloned())
+            .collect::<Vec<_>>();
+        Ok(ordered
+            .into_iter()
+            .map(|(member, own_kits)| {
+                let others = all_kits
+                    .iter()
+                    .filter(|kit| !own_kits.contains(kit))
+                    .cloned()
+                    .collect();
+                Member {
+                    project: member.project.with_local_kits(others),
+                    ..member
+                }
+            })
+            .collect())
+    }
+}
+
+/// Returns the names of the kits in a project's `kits` directory.
+async fn find_kits(project_dir: &Path) -> Result<Vec<String>> {
+    let kits_dir = project_dir.join("kits");
+    let mut kits = Vec::new();
+    if !kits_dir.is_dir() {
+        return Ok(kits);
+    }
+    let mut entries = tokio::fs::read_dir(&kits_dir)
+        .await
+        .context(format!("Unable to read '{}'", kits_dir.display()))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context(format!("Unable to read '{}'", kits_dir.display()))?
+    {
+        if entry.path().join("Cargo.toml").is_file() {
+            kits.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    // Provide a predictable ordering.
+    kits.sort();
+    Ok(kits)
+}
+
+/// This is used to `Deserialize` a workspace before its members are loaded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct UnvalidatedWorkspace {
+    /// Checked by deserializing, and not needed after that.
+    #[expect(dead_code)]
+    schema_version: SchemaVersion<1>,
+    #[serde(default)]
+    member: Vec<UnvalidatedMember>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct UnvalidatedMember {
+    /// The directory holding the member's `Twoliter.toml`, relative to the workspace file.
+    path: PathBuf,
+    /// The kits to build. Defaults to every kit in the member's `kits` directory.
+    kits: Option<Vec<String>>,
+    /// The variants to build, which may be glob patterns.
+    #[serde(default)]
+    variants: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Copies the `local-kit` project, which builds `core-kit`, and the `external-kit` project,
+    /// which depends on it, into a workspace with the given workspace file.
+    async fn workspace(workspace_toml: &str) -> TempDir {
+        let temp_dir = TempDir::new().unwrap();
+        for project in ["local-kit", "external-kit"] {
+            let copy = crate::test::copy_project_to_temp_dir(project);
+            std::fs::rename(copy.into_path(), temp_dir.path().join(project)).unwrap();
+        }
+        fs::write(temp_dir.path().join(TWOLITER_WORKSPACE), workspace_toml)
+            .await
+            .unwrap();
+        temp_dir
+    }
+
+    fn kit_names(project: &Project<Unlocked>) -> Vec<String> {
+        project
+            .local_kits
+            .iter()
+            .map(|kit| kit.name().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_producers_are_built_first() {
+        let temp_dir = workspace(
+            r#"
+schema-version = 1
+
+[[member]]
+path = "external-kit"
+variants = ["*"]
+
+[[member]]
+path = "local-kit"
+kits = ["core-kit"]
+"#,
+        )
+        .await;
+        let workspace = Workspace::find_and_load(temp_dir.path().join("local-kit"))
+            .await
+            .unwrap();
+        let members = workspace.members();
+        assert_eq!(members.len(), 2);
+
+        let producer = &members[0];
+        assert!(producer.project().project_dir().ends_with("local-kit"));
+        assert_eq!(producer.kits(), ["core-kit"]);
+        assert_eq!(kit_names(producer.project()), ["extra-1-kit"]);
+
+        let consumer = &members[1];
+        assert!(consumer.project().project_dir().ends_with("external-kit"));
+        assert_eq!(consumer.kits(), ["extra-1-kit"]);
+        assert_eq!(consumer.variants(), ["*"]);
+        assert_eq!(kit_names(consumer.project()), ["core-kit"]);
+        let core_kit = consumer.project().direct_kit_deps().unwrap().remove(0);
+        assert!(consumer.project().local_kit_for(&core_kit).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_kits_must_be_unique() {
+        // Both projects have an `extra-1-kit` at version 1.0.0.
+        let temp_dir = workspace(
+            r#"
+schema-version = 1
+
+[[member]]
+path = "local-kit"
+
+[[member]]
+path = "external-kit"
+"#,
+        )
+        .await;
+        let err = Workspace::load(temp_dir.path().join(TWOLITER_WORKSPACE))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("extra-1-kit"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_members_must_exist() {
+        let temp_dir = workspace("schema-version = 1\n[[member]]\npath = \"nope\"\n").await;
+        assert!(Workspace::load(temp_dir.path().join(TWOLITER_WORKSPACE))
+            .await
+            .is_err());
+    }
+}
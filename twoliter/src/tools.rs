@@ -1,12 +1,17 @@
-use crate::common::fs;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use filetime::{set_file_handle_times, set_file_mtime, FileTime};
 use flate2::read::ZlibDecoder;
-use std::path::Path;
-use tar::Archive;
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncWriteExt;
-use tokio::runtime::Handle;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+use tempfile::TempDir;
 use tracing::debug;
 
 const TAR_GZ_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/tools.tar.gz"));
@@ -18,122 +23,426 @@ const TESTSYS: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_TESTSYS"));
 const TUFTOOL: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_TUFTOOL"));
 const UNPLUG: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_UNPLUG"));
 
+/// The file in the tools directory that records the digest of each installed file.
+const MANIFEST: &str = ".twoliter-tools.json";
+
 /// Install tools into the given `tools_dir`. If you use a `TempDir` object, make sure to pass it by
 /// reference and hold on to it until you no longer need the tools to still be installed (it will
 /// auto delete when it goes out of scope).
+///
+/// Nothing is written if the directory already holds exactly the embedded tools. Otherwise the
+/// tools are staged next to `tools_dir`, reusing files whose content has not changed, and swapped
+/// into place, so that a command using the tools never sees a partially installed directory.
+/// Installation holds a lock on `.<name>.lock` next to `tools_dir` so that concurrent Twoliter
+/// commands in the same project take turns.
 pub(crate) async fn install_tools(tools_dir: impl AsRef<Path>) -> Result<()> {
-    let dir = tools_dir.as_ref();
-    debug!("Installing tools to '{}'", dir.display());
-    fs::remove_dir_all(dir)
+    let dir = tools_dir.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || install(&dir))
         .await
-        .context("Unable to remove tools directory before installing")?;
-    fs::create_dir_all(dir)
-        .await
-        .context("Unable to create directory for tools")?;
+        .context("Unable to run and join async task for installing tools")?
+}
 
-    // Write out the embedded tools and scripts.
-    unpack_tarball(dir)
-        .await
-        .context("Unable to install tools")?;
+fn install(dir: &Path) -> Result<()> {
+    let tools = embedded_tools()?;
+    let manifest = Manifest::new(&tools);
 
-    // Pick one of the embedded files for use as the canonical mtime.
-    let metadata = fs::metadata(dir.join("build.Dockerfile"))
-        .await
-        .context("Unable to get Dockerfile metadata")?;
-    let mtime = FileTime::from_last_modification_time(&metadata);
+    let parent = parent_dir(dir);
+    std::fs::create_dir_all(parent)
+        .context(format!("Unable to create directory '{}'", parent.display()))?;
+    let _lock = lock(dir)?;
 
-    write_bin("buildsys", BUILDSYS, &dir, mtime).await?;
-    write_bin("pipesys", PIPESYS, &dir, mtime).await?;
-    write_bin("pubsys", PUBSYS, &dir, mtime).await?;
-    write_bin("pubsys-setup", PUBSYS_SETUP, &dir, mtime).await?;
-    write_bin("testsys", TESTSYS, &dir, mtime).await?;
-    write_bin("tuftool", TUFTOOL, &dir, mtime).await?;
-    write_bin("unplug", UNPLUG, &dir, mtime).await?;
+    let installed = Manifest::read(dir);
+    if installed.as_ref() == Some(&manifest) && manifest.is_present(dir) {
+        debug!("Tools in '{}' are up to date", dir.display());
+        return Ok(());
+    }
+
+    debug!("Installing tools to '{}'", dir.display());
+    let staging = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name(dir)?))
+        .tempdir_in(parent)
+        .context(format!(
+            "Unable to create staging directory in '{}'",
+            parent.display()
+        ))?;
+    std::fs::set_permissions(staging.path(), Permissions::from_mode(0o755)).context(format!(
+        "Unable to set permissions for '{}'",
+        staging.path().display()
+    ))?;
+
+    let mut reused = 0;
+    for tool in &tools {
+        let existing = installed
+            .as_ref()
+            .filter(|installed| installed.files.get(&tool.path) == Some(&tool.digest))
+            .map(|_| dir.join(&tool.path))
+            .filter(|existing| existing.is_file());
+        if tool.install(staging.path(), existing.as_deref())? {
+            reused += 1;
+        }
+    }
+    manifest.write(staging.path())?;
 
     // Apply the mtime to the directory now that the writes are done.
-    set_file_mtime(dir, mtime).context(format!("Unable to set mtime for '{}'", dir.display()))?;
+    let mtime = canonical_mtime(&tools)?;
+    set_file_mtime(staging.path(), mtime).context(format!(
+        "Unable to set mtime for '{}'",
+        staging.path().display()
+    ))?;
 
+    swap(staging, dir)?;
+    debug!(
+        "Installed tools to '{}', {reused} of {} files unchanged",
+        dir.display(),
+        tools.len()
+    );
     Ok(())
 }
 
-async fn write_bin(name: &str, data: &[u8], dir: impl AsRef<Path>, mtime: FileTime) -> Result<()> {
-    let path = dir.as_ref().join(name);
-    let mut f = OpenOptions::new()
+/// A file that Twoliter installs into the tools directory.
+struct Tool {
+    /// The path relative to the tools directory.
+    path: PathBuf,
+    data: Cow<'static, [u8]>,
+    mode: u32,
+    mtime: FileTime,
+    /// The sha256 of `data`.
+    digest: String,
+}
+
+impl Tool {
+    fn new(path: PathBuf, data: Cow<'static, [u8]>, mode: u32, mtime: FileTime) -> Self {
+        let digest = format!("{:x}", Sha256::digest(&data));
+        Self {
+            path,
+            data,
+            mode,
+            mtime,
+            digest,
+        }
+    }
+
+    /// Installs the file into `dir`, hard linking it from `existing` when given. Returns whether
+    /// the existing file was reused.
+    fn install(&self, dir: &Path, existing: Option<&Path>) -> Result<bool> {
+        let path = dir.join(&self.path);
+        let parent = parent_dir(&path);
+        std::fs::create_dir_all(parent)
+            .context(format!("Unable to create directory '{}'", parent.display()))?;
+
+        if let Some(existing) = existing {
+            match std::fs::hard_link(existing, &path) {
+                Ok(()) => return Ok(true),
+                Err(e) => debug!(
+                    "Unable to link '{}', writing it instead: {e}",
+                    existing.display()
+                ),
+            }
+        }
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(self.mode)
+            .open(&path)
+            .context(format!("Unable to create file '{}'", path.display()))?;
+        f.write_all(&self.data)
+            .context(format!("Unable to write to '{}'", path.display()))?;
+        f.flush()
+            .context(format!("Unable to finalize '{}'", path.display()))?;
+        set_file_handle_times(&f, None, Some(self.mtime))
+            .context(format!("Unable to set mtime for '{}'", path.display()))?;
+        Ok(false)
+    }
+}
+
+/// The embedded scripts and binaries, in the order they are installed.
+fn embedded_tools() -> Result<Vec<Tool>> {
+    let mut tools = Vec::new();
+    let mut archive = Archive::new(ZlibDecoder::new(TAR_GZ_DATA));
+    let entries = archive
+        .entries()
+        .context("Unable to read the embedded tools tarball")?;
+    for entry in entries {
+        let mut entry = entry.context("Unable to read an entry of the embedded tools tarball")?;
+        match entry.header().entry_type() {
+            EntryType::Directory => continue,
+            EntryType::Regular => {}
+            other => bail!("Unexpected entry of type '{other:?}' in the embedded tools tarball"),
+        }
+        let path = entry
+            .path()
+            .context("Unable to read a path from the embedded tools tarball")?
+            .into_owned();
+        let mode = entry
+            .header()
+            .mode()
+            .context(format!("Unable to read the mode of '{}'", path.display()))?;
+        let mtime = entry
+            .header()
+            .mtime()
+            .context(format!("Unable to read the mtime of '{}'", path.display()))?;
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .context(format!("Unable to unpack '{}'", path.display()))?;
+        let mtime = FileTime::from_unix_time(mtime as i64, 0);
+        tools.push(Tool::new(path, Cow::Owned(data), mode, mtime));
+    }
+
+    let mtime = canonical_mtime(&tools)?;
+    for (name, data) in [
+        ("buildsys", BUILDSYS),
+        ("pipesys", PIPESYS),
+        ("pubsys", PUBSYS),
+        ("pubsys-setup", PUBSYS_SETUP),
+        ("testsys", TESTSYS),
+        ("tuftool", TUFTOOL),
+        ("unplug", UNPLUG),
+    ] {
+        tools.push(Tool::new(name.into(), Cow::Borrowed(data), 0o755, mtime));
+    }
+    Ok(tools)
+}
+
+/// Pick one of the embedded files for use as the canonical mtime.
+fn canonical_mtime(tools: &[Tool]) -> Result<FileTime> {
+    tools
+        .iter()
+        .find(|tool| tool.path == Path::new("build.Dockerfile"))
+        .map(|tool| tool.mtime)
+        .context("The embedded tools tarball does not contain 'build.Dockerfile'")
+}
+
+/// The digest of each file installed in a tools directory.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Manifest {
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl Manifest {
+    fn new(tools: &[Tool]) -> Self {
+        Self {
+            files: tools
+                .iter()
+                .map(|tool| (tool.path.clone(), tool.digest.clone()))
+                .collect(),
+        }
+    }
+
+    /// Reads the manifest of a tools directory. A missing or unreadable manifest means that
+    /// nothing installed in the directory can be trusted, so this returns `None` for both.
+    fn read(dir: &Path) -> Option<Self> {
+        let data = std::fs::read(dir.join(MANIFEST)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST);
+        let data = serde_json::to_vec_pretty(self).context("Unable to serialize tools manifest")?;
+        std::fs::write(&path, data).context(format!("Unable to write '{}'", path.display()))
+    }
+
+    /// Whether every file in the manifest exists in `dir`.
+    fn is_present(&self, dir: &Path) -> bool {
+        self.files.keys().all(|path| dir.join(path).is_file())
+    }
+}
+
+/// Takes an exclusive lock for installing into `dir`, waiting for any other Twoliter process that
+/// holds it. The lock is released when the returned value is dropped.
+fn lock(dir: &Path) -> Result<Flock<File>> {
+    let path = parent_dir(dir).join(format!(".{}.lock", file_name(dir)?));
+    let file = OpenOptions::new()
         .create(true)
-        .truncate(true)
-        .read(false)
+        .truncate(false)
         .write(true)
-        .mode(0o755)
         .open(&path)
-        .await
-        .context(format!("Unable to create file '{}'", path.display()))?;
-    f.write_all(data)
-        .await
-        .context(format!("Unable to write to '{}'", path.display()))?;
-    f.flush()
-        .await
-        .context(format!("Unable to finalize '{}'", path.display()))?;
-
-    let f = f.into_std().await;
-    let rt = Handle::current();
-    rt.spawn_blocking(move || {
-        set_file_handle_times(&f, None, Some(mtime))
-            .context(format!("Unable to set mtime for '{}'", path.display()))
-    })
-    .await
-    .context("Unable to run and join async task for reading handle time".to_string())?
+        .context(format!("Unable to open lock file '{}'", path.display()))?;
+    debug!("Waiting for lock '{}'", path.display());
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| errno)
+        .context(format!("Unable to lock '{}'", path.display()))
 }
 
-async fn unpack_tarball(tools_dir: impl AsRef<Path>) -> Result<()> {
-    let tools_dir = tools_dir.as_ref();
-    let tar = ZlibDecoder::new(TAR_GZ_DATA);
-    let mut archive = Archive::new(tar);
-    archive.unpack(tools_dir).context(format!(
-        "Unable to unpack tarball into directory '{}'",
-        tools_dir.display()
+/// Moves the staged tools to `dir`. When `dir` already exists the two are exchanged atomically
+/// where the platform supports it, and the previous tools are removed with the staging directory.
+/// Elsewhere the previous tools are moved aside just before the staged tools are moved in.
+fn swap(staging: TempDir, dir: &Path) -> Result<()> {
+    if dir.symlink_metadata().is_err() {
+        std::fs::rename(staging.path(), dir).context(format!(
+            "Unable to move '{}' to '{}'",
+            staging.path().display(),
+            dir.display()
+        ))?;
+        // The staging directory no longer exists, so there is nothing left to clean up.
+        let _ = staging.into_path();
+        return Ok(());
+    }
+
+    if exchange(staging.path(), dir)? {
+        return Ok(());
+    }
+
+    debug!(
+        "Unable to exchange '{}' and '{}' atomically, replacing it instead",
+        staging.path().display(),
+        dir.display()
+    );
+    let previous = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name(dir)?))
+        .tempdir_in(parent_dir(dir))
+        .context("Unable to create directory for the previous tools")?;
+    std::fs::rename(dir, previous.path())
+        .context(format!("Unable to move '{}' out of the way", dir.display()))?;
+    std::fs::rename(staging.path(), dir).context(format!(
+        "Unable to move '{}' to '{}'",
+        staging.path().display(),
+        dir.display()
     ))?;
-    debug!("Installed tools to '{}'", tools_dir.display());
+    let _ = staging.into_path();
     Ok(())
 }
 
-#[tokio::test]
-async fn test_install_tools() {
-    let tempdir = tempfile::TempDir::new().unwrap();
-    let toolsdir = tempdir.path().join("tools");
-    install_tools(&toolsdir).await.unwrap();
-
-    // Assert that the expected files exist in the tools directory.
-
-    // Check that non-binary files were copied.
-    assert!(toolsdir.join("Makefile.toml").is_file());
-    assert!(toolsdir.join("build.Dockerfile").is_file());
-    assert!(toolsdir.join("build.Dockerfile.dockerignore").is_file());
-    assert!(toolsdir.join("docker-go").is_file());
-    assert!(toolsdir.join("img2img").is_file());
-    assert!(toolsdir.join("imghelper").is_file());
-    assert!(toolsdir.join("metadata.spec").is_file());
-    assert!(toolsdir.join("partyplanner").is_file());
-    assert!(toolsdir.join("rpm2img").is_file());
-    assert!(toolsdir.join("rpm2kit").is_file());
-    assert!(toolsdir.join("rpm2kmodkit").is_file());
-    assert!(toolsdir.join("rpm2migrations").is_file());
-
-    // Check that binaries were copied.
-    assert!(toolsdir.join("buildsys").is_file());
-    assert!(toolsdir.join("pipesys").is_file());
-    assert!(toolsdir.join("pubsys").is_file());
-    assert!(toolsdir.join("pubsys-setup").is_file());
-    assert!(toolsdir.join("testsys").is_file());
-    assert!(toolsdir.join("tuftool").is_file());
-    assert!(toolsdir.join("unplug").is_file());
-
-    // Check that the mtimes match.
-    let dockerfile_metadata = fs::metadata(toolsdir.join("build.Dockerfile"))
-        .await
-        .unwrap();
-    let buildsys_metadata = fs::metadata(toolsdir.join("buildsys")).await.unwrap();
-    let dockerfile_mtime = FileTime::from_last_modification_time(&dockerfile_metadata);
-    let buildsys_mtime = FileTime::from_last_modification_time(&buildsys_metadata);
+/// Atomically exchanges two paths. Returns `false` if the platform or filesystem cannot do this.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn exchange(a: &Path, b: &Path) -> Result<bool> {
+    use nix::errno::Errno;
+    use nix::fcntl::{renameat2, RenameFlags};
+
+    match renameat2(None, a, None, b, RenameFlags::RENAME_EXCHANGE) {
+        Ok(()) => Ok(true),
+        Err(Errno::EINVAL | Errno::ENOSYS) => Ok(false),
+        Err(e) => Err(e).context(format!(
+            "Unable to exchange '{}' and '{}'",
+            a.display(),
+            b.display()
+        )),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn exchange(_: &Path, _: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new("."))
+}
+
+fn file_name(dir: &Path) -> Result<String> {
+    Ok(dir
+        .file_name()
+        .context(format!("Tools directory '{}' has no name", dir.display()))?
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn test_install_tools() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let toolsdir = tempdir.path().join("tools");
+        install_tools(&toolsdir).await.unwrap();
+
+        // Assert that the expected files exist in the tools directory.
+
+        // Check that non-binary files were copied.
+        assert!(toolsdir.join("Makefile.toml").is_file());
+        assert!(toolsdir.join("build.Dockerfile").is_file());
+        assert!(toolsdir.join("build.Dockerfile.dockerignore").is_file());
+        assert!(toolsdir.join("docker-go").is_file());
+        assert!(toolsdir.join("img2img").is_file());
+        assert!(toolsdir.join("imghelper").is_file());
+        assert!(toolsdir.join("metadata.spec").is_file());
+        assert!(toolsdir.join("partyplanner").is_file());
+        assert!(toolsdir.join("rpm2img").is_file());
+        assert!(toolsdir.join("rpm2kit").is_file());
+        assert!(toolsdir.join("rpm2kmodkit").is_file());
+        assert!(toolsdir.join("rpm2migrations").is_file());
+
+        // Check that binaries were copied.
+        assert!(toolsdir.join("buildsys").is_file());
+        assert!(toolsdir.join("pipesys").is_file());
+        assert!(toolsdir.join("pubsys").is_file());
+        assert!(toolsdir.join("pubsys-setup").is_file());
+        assert!(toolsdir.join("testsys").is_file());
+        assert!(toolsdir.join("tuftool").is_file());
+        assert!(toolsdir.join("unplug").is_file());
+
+        // Check that the mtimes match.
+        let dockerfile_metadata = fs::metadata(toolsdir.join("build.Dockerfile"))
+            .await
+            .unwrap();
+        let buildsys_metadata = fs::metadata(toolsdir.join("buildsys")).await.unwrap();
+        let dockerfile_mtime = FileTime::from_last_modification_time(&dockerfile_metadata);
+        let buildsys_mtime = FileTime::from_last_modification_time(&buildsys_metadata);
+
+        assert_eq!(dockerfile_mtime, buildsys_mtime);
+    }
+
+    #[tokio::test]
+    async fn test_install_tools_skips_unchanged() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let toolsdir = tempdir.path().join("tools");
+        install_tools(&toolsdir).await.unwrap();
+        let ino = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+        let dir_ino = ino(toolsdir.clone());
+        let buildsys_ino = ino(toolsdir.join("buildsys"));
+        let makefile_ino = ino(toolsdir.join("Makefile.toml"));
+
+        // Nothing is replaced when the tools are up to date.
+        install_tools(&toolsdir).await.unwrap();
+        assert_eq!(ino(toolsdir.clone()), dir_ino);
+
+        // A changed file is rewritten, and the unchanged files are carried over.
+        let makefile = toolsdir.join("Makefile.toml");
+        let expected = std::fs::read(&makefile).unwrap();
+        let mut manifest = Manifest::read(&toolsdir).unwrap();
+        manifest
+            .files
+            .insert("Makefile.toml".into(), "stale".to_string());
+        manifest.write(&toolsdir).unwrap();
+        std::fs::remove_file(&makefile).unwrap();
+        std::fs::write(&makefile, "stale").unwrap();
+
+        install_tools(&toolsdir).await.unwrap();
+        assert_ne!(ino(toolsdir.clone()), dir_ino);
+        assert_eq!(ino(toolsdir.join("buildsys")), buildsys_ino);
+        assert_ne!(ino(toolsdir.join("Makefile.toml")), makefile_ino);
+        assert_eq!(std::fs::read(&makefile).unwrap(), expected);
+
+        // A missing file is reinstalled.
+        std::fs::remove_file(toolsdir.join("unplug")).unwrap();
+        install_tools(&toolsdir).await.unwrap();
+        assert!(toolsdir.join("unplug").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_install_tools_concurrently() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let toolsdir = tempdir.path().join("tools");
+        let installs = (0..4).map(|_| install_tools(&toolsdir));
+        for result in futures::future::join_all(installs).await {
+            result.unwrap();
+        }
+        assert_eq!(
+            Manifest::read(&toolsdir),
+            Some(Manifest::new(&embedded_tools().unwrap()))
+        );
 
-    assert_eq!(dockerfile_mtime, buildsys_mtime);
+        // Only the tools and the lock file are left behind.
+        let mut names = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".tools.lock", "tools"]);
+    }
 }